@@ -1,12 +1,10 @@
 use anyhow::anyhow;
-use log::{trace, warn};
+use log::trace;
 use std::{borrow::Cow, path::Path};
 
 use addr2line::{fallible_iterator::FallibleIterator, gimli, Loader, Location};
 
-use crate::utils;
-
-use super::Annotater;
+use super::images::AddressAnnotater;
 
 pub struct Addr2LineAnnotator {
     ctx: addr2line::Loader,
@@ -130,21 +128,18 @@ impl Addr2LineAnnotator {
     }
 }
 
-impl Annotater for Addr2LineAnnotator {
+impl AddressAnnotater for Addr2LineAnnotator {
     fn accepts_keys(&self) -> Vec<String> {
         vec!["a2l".to_string()]
     }
 
-    fn annotate(&mut self, placeholder: &super::Placeholder) -> anyhow::Result<Option<String>> {
-        let Ok(addr) = utils::string_to_u64(&placeholder.value) else {
-            warn!(
-                "addr2line: invalid number '{}' - skipping.",
-                placeholder.value
-            );
-            return Ok(None);
-        };
-
+    fn annotate_addr(
+        &mut self,
+        _kind: &str,
+        addr: u64,
+        trace_addr: u64,
+    ) -> anyhow::Result<Option<String>> {
         let frames_str = self.frames_str(addr);
-        Ok(Some(format!("{frames_str} (0x{addr:08x})")))
+        Ok(Some(format!("{frames_str} (0x{trace_addr:08x})")))
     }
 }
@@ -1,141 +1,288 @@
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+use anyhow::anyhow;
 use instruction_decoder::Decoder;
-use log::warn;
+use log::{debug, warn};
 
 use crate::utils;
 
-use super::Annotater;
+use super::{
+    march::{riscv_instr_len, March},
+    riscv::{self, FormatOptions},
+    Annotater,
+};
 
 pub struct Disassembler {
     decoder: Decoder,
-    instr_len: usize,
+    /// Instruction length in bits, or `None` to detect the length of each
+    /// instruction from its RISC-V length encoding.
+    instr_len: Option<usize>,
+    /// XLEN of RISC-V disassemblers, whose output can be formatted with
+    /// [`riscv::format_instr`].
+    riscv_xlen: Option<u32>,
 }
 
 impl Disassembler {
     pub fn new(decoder: Decoder, instr_len: usize) -> Self {
-        Self { decoder, instr_len }
+        Self {
+            decoder,
+            instr_len: Some(instr_len),
+            riscv_xlen: None,
+        }
+    }
+
+    /// RISC-V disassembler with exactly the extensions of a `-march` string
+    /// (e.g. `rv32imac_zicsr_zifencei`).
+    pub fn from_march(march: &str) -> anyhow::Result<Self> {
+        let parsed = March::parse(march)?;
+        let tables = parsed.tables();
+        let decoder = Decoder::new(&tables).map_err(|errs| {
+            anyhow!(
+                "Failed to build decoder for '{march}' - {}",
+                errs.join("; ")
+            )
+        })?;
+        Ok(Self {
+            decoder,
+            instr_len: None,
+            riscv_xlen: Some(parsed.xlen),
+        })
+    }
+}
+
+/// Additional ISA, given as `name[:bits]=file.toml[,file.toml..]`: The
+/// instruction-decoder TOML files describing it, and its instruction length in
+/// bits (default 32). Instructions are disassembled from `$da-<name>`
+/// placeholders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsaSpec {
+    pub name: String,
+    pub instr_len: usize,
+    pub files: Vec<PathBuf>,
+}
+
+impl FromStr for IsaSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, files)) = s.split_once('=') else {
+            return Err(format!(
+                "invalid ISA '{s}' - expected name[:bits]=file.toml[,file.toml..]"
+            ));
+        };
+        let (name, instr_len) = match name.split_once(':') {
+            Some((name, bits)) => {
+                let bits: usize = bits
+                    .parse()
+                    .map_err(|e| format!("invalid instruction length '{bits}' - {e}"))?;
+                if bits == 0 || bits > 128 {
+                    return Err(format!(
+                        "invalid instruction length {bits} - must be between 1 and 128 bits"
+                    ));
+                }
+                (name, bits)
+            }
+            None => (name, 32),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("invalid ISA name '{name}'"));
+        }
+        let files: Vec<PathBuf> = files
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        if files.is_empty() {
+            return Err(format!("no TOML files given for ISA '{name}'"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            instr_len,
+            files,
+        })
     }
 }
 
+impl IsaSpec {
+    pub fn build(&self) -> anyhow::Result<Disassembler> {
+        let tomls = self
+            .files
+            .iter()
+            .map(|path| {
+                fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read ISA file {} - {e}", path.display()))
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        let decoder = Decoder::new(&tomls).map_err(|errs| {
+            anyhow!(
+                "Failed to build decoder for ISA '{}' - {}",
+                self.name,
+                errs.join("; ")
+            )
+        })?;
+        Ok(Disassembler::new(decoder, self.instr_len))
+    }
+}
+
+/// Disassembles `$da-<name>:<instr>` placeholders. RISC-V instructions may
+/// also be given with their address, as `$da-<name>:<instr>@<pc>`, to resolve
+/// branch/jump targets.
 pub struct DisasmAnnotater {
-    rv32: Disassembler,
-    rv64: Disassembler,
+    /// Disassembler by placeholder kind.
+    disassemblers: HashMap<String, Disassembler>,
+    format: FormatOptions,
 }
 
 impl DisasmAnnotater {
     pub fn new() -> Self {
+        let mut disassemblers = HashMap::new();
+        disassemblers.insert("da-rv32".to_string(), new_rv32_translator());
+        disassemblers.insert("da-rv64".to_string(), new_rv64_translator());
         Self {
-            rv32: new_rv32_translator(),
-            rv64: new_rv64_translator(),
+            disassemblers,
+            format: FormatOptions::default(),
+        }
+    }
+
+    /// Formatting of RISC-V instructions.
+    pub fn set_format(&mut self, format: FormatOptions) {
+        self.format = format;
+    }
+
+    /// Disassemble `$da-<name>` placeholders with `disasm`, replacing any
+    /// previous ISA of that name.
+    pub fn add_isa(&mut self, name: &str, disasm: Disassembler) {
+        if self
+            .disassemblers
+            .insert(format!("da-{name}"), disasm)
+            .is_some()
+        {
+            debug!("disasm: ISA '{name}' replaces previous definition.");
         }
     }
 }
 
-#[rustfmt::skip]
+/// Extensions of the default RV32 disassembler.
+pub const DEFAULT_RV32_MARCH: &str = "rv32imafc_zicsr_zifencei_zba_zbb_zbc_zbs_zbkb_zbkc_zbkx\
+    _zknd_zkne_zknh_zksed_zksh_zfa_zfh_zcb_zcd_zacas_zawrs_zicond_zicbom_zimop_zihintntl";
+
+/// Extensions of the default RV64 disassembler.
+pub const DEFAULT_RV64_MARCH: &str = "rv64imadcv_zifencei_zba_zbb_zbc_zbs_zbkb_zbkc_zbkx\
+    _zknd_zkne_zknh_zksed_zksh_zfa_zfh_zcb_zacas_zawrs_zicond_zicbom_zimop_zihintntl\
+    _zvbb_zvbc_zvkg_zvkned_zvknha_zvknhb_zvksed_zvksh";
+
 pub fn new_rv32_translator() -> Disassembler {
-    let decoder = Decoder::new(&[
-        include_str!("../../../third_party/instruction-decoder/toml/RV32I.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32M.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32A.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32F.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zbb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zbkb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zbs.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zknd.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zkne.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zfa.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zicsr.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32C-lower.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zcb-lower.toml") .to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zcf-lower.toml") .to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV32_Zacas.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zcd-lower.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zfh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zba.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbc.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbkc.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbkx.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zfh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zknh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zksed.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zksh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zawrs.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zicond.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zifencei.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zicbo.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zimop.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zihintntl.toml").to_string(),
-    ])
-    .expect("Can't build RV32 decoder");
-
-    Disassembler::new(decoder, 32)
+    Disassembler::from_march(DEFAULT_RV32_MARCH).expect("Can't build RV32 decoder")
 }
 
-#[rustfmt::skip]
 pub fn new_rv64_translator() -> Disassembler {
-    let decoder = Decoder::new(&[
-        include_str!("../../../third_party/instruction-decoder/toml/RV64I.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64M.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64A.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64D.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zbb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zbkb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zbs.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zknd.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zkne.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zacas.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zfa.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64C-lower.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zcb-lower.toml") .to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV64_Zcd-lower.toml") .to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RVV.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvbb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvbc.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvkg.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvkned.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvknha.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvknhb.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvksed.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zvksh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zcd-lower.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zfh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zba.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbc.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbkc.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zbkx.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zknh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zksed.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zksh.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zawrs.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zicond.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zifencei.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zicbo.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zimop.toml").to_string(),
-        include_str!("../../../third_party/instruction-decoder/toml/RV_Zihintntl.toml").to_string(),
-    ])
-    .expect("Can't build RV64 decoder");
-    Disassembler::new(decoder, 32)
+    Disassembler::from_march(DEFAULT_RV64_MARCH).expect("Can't build RV64 decoder")
 }
 
 impl Annotater for DisasmAnnotater {
     fn accepts_keys(&self) -> Vec<String> {
-        vec!["da-rv32".into(), "da-rv64".into()]
+        self.disassemblers.keys().cloned().collect()
     }
 
     fn annotate(&mut self, placeholder: &super::Placeholder) -> anyhow::Result<Option<String>> {
-        let disasm = match placeholder.kind.as_str() {
-            "da-rv32" => &self.rv32,
-            "da-rv64" => &self.rv64,
-            _ => unreachable!(),
+        let Some(disasm) = self.disassemblers.get(&placeholder.kind) else {
+            return Ok(None);
         };
 
-        let Ok(val) = utils::string_to_u128(&placeholder.value) else {
+        let (val, pc) = match placeholder.value.split_once('@') {
+            Some((val, pc)) => (val, Some(pc)),
+            None => (placeholder.value.as_str(), None),
+        };
+        let Ok(val) = utils::string_to_u128(val) else {
             warn!("disasm: invalid number '{}' - skipping.", placeholder.value);
             return Ok(None);
         };
+        let pc = match pc.map(utils::string_to_u64) {
+            Some(Ok(pc)) => Some(pc),
+            Some(Err(_)) => {
+                warn!("disasm: invalid pc in '{}' - ignoring.", placeholder.value);
+                None
+            }
+            None => None,
+        };
+
+        let instr_len = match disasm.instr_len {
+            Some(len) => len,
+            None => {
+                let len = riscv_instr_len(val);
+                if len < 128 && val >> len != 0 {
+                    warn!(
+                        "disasm: '{}' is longer than a {len}-bit instruction - truncating.",
+                        placeholder.value
+                    );
+                }
+                len
+            }
+        };
+        let val = if instr_len < 128 {
+            val & ((1 << instr_len) - 1)
+        } else {
+            val
+        };
+
+        let width = instr_len.div_ceil(4);
+        match disasm.decoder.decode(val, instr_len) {
+            Ok(instr) => {
+                let instr = match disasm.riscv_xlen {
+                    Some(xlen) => riscv::format_instr(&instr, pc, xlen, &self.format),
+                    None => instr,
+                };
+                Ok(Some(format!("{instr} (0x{val:0width$x})")))
+            }
+            Err(_err) => Ok(Some(format!("? (0x{val:0width$x})"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::annotate::Placeholder;
+
+    #[test]
+    fn test_parse_isa_spec() {
+        assert_eq!(
+            "acc:16=a.toml,b.toml".parse(),
+            Ok(IsaSpec {
+                name: "acc".into(),
+                instr_len: 16,
+                files: vec!["a.toml".into(), "b.toml".into()],
+            })
+        );
+        assert_eq!("toy=isa.toml".parse::<IsaSpec>().unwrap().instr_len, 32);
+        assert!("toy".parse::<IsaSpec>().is_err());
+        assert!("toy:0=isa.toml".parse::<IsaSpec>().is_err());
+        assert!("toy=".parse::<IsaSpec>().is_err());
+    }
+
+    #[test]
+    fn test_isa_build() {
+        // A 16-bit ISA, from the compressed RISC-V instructions:
+        let tomls = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../third_party/instruction-decoder/toml/RV32C-lower.toml");
+        let isa: IsaSpec = format!("rvc:16={}", tomls.display()).parse().unwrap();
+        let mut disasm = DisasmAnnotater::new();
+        disasm.add_isa(&isa.name, isa.build().unwrap());
+        assert!(disasm.accepts_keys().contains(&"da-rvc".to_string()));
 
-        match disasm.decoder.decode(val, disasm.instr_len) {
-            Ok(instr) => Ok(Some(format!("{instr} (0x{val:08x})"))),
-            Err(_err) => Ok(Some(format!("? (0x{val:08x})"))),
+        // c.li a0, 0 - with bits above the instruction length ignored:
+        for value in ["0x4501", "0x14501"] {
+            let text = disasm
+                .annotate(&Placeholder {
+                    kind: "da-rvc".into(),
+                    value: value.into(),
+                })
+                .unwrap()
+                .unwrap();
+            assert!(text.starts_with("c.li "), "{text}");
+            assert!(text.ends_with(" (0x4501)"), "{text}");
         }
     }
 }
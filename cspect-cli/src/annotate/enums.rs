@@ -0,0 +1,400 @@
+//! Decoding of raw numbers (FSM states, opcodes, error codes, ..) to names
+//! from mapping tables, so simulations can emit numbers instead of formatting
+//! strings.
+//!
+//! A table maps values (`3`, `0x10`) or inclusive ranges (`0x10-0x1f`) to
+//! names. Two keys are reserved: `flags = true` decomposes values into bit
+//! flags (`READ|EXCL`), and `fallback` formats values without a name, with
+//! `{value}` and `{hex}` replaced by the value in decimal and hex. In TOML:
+//!
+//! ```toml
+//! [fsm]
+//! 0 = "IDLE"
+//! 1 = "BUSY"
+//! 0x10-0x1f = "RESERVED"
+//! fallback = "STATE_{value}"
+//!
+//! [access]
+//! flags = true
+//! 0x1 = "READ"
+//! 0x2 = "WRITE"
+//! 0x4 = "EXCL"
+//! ```
+//!
+//! JSON files have the same structure, CSV files have `table,key,name` rows.
+//! Files given as `name=file` hold a single table instead, without the table
+//! level (`key,name` rows in CSV).
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::anyhow;
+use log::warn;
+
+use crate::utils;
+
+use super::{Annotater, Placeholder};
+
+const DEFAULT_FALLBACK: &str = "{hex}";
+
+#[derive(Debug, Clone, Default)]
+pub struct EnumTable {
+    exact: HashMap<u64, String>,
+    /// Inclusive ranges.
+    ranges: Vec<(u64, u64, String)>,
+    /// Bit flags, masks with more bits first.
+    flags: Option<Vec<(u64, String)>>,
+    fallback: Option<String>,
+}
+
+impl EnumTable {
+    fn add(&mut self, key: &str, name: &str) -> anyhow::Result<()> {
+        match key {
+            "flags" => {
+                let flags = name
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("flags must be true or false, not '{name}'"))?;
+                self.flags = flags.then(Vec::new);
+            }
+            "fallback" => self.fallback = Some(name.to_string()),
+            _ => {
+                let parse = |value: &str| {
+                    utils::string_to_u64(value)
+                        .map_err(|e| anyhow!("invalid value '{value}' - {e}"))
+                };
+                match key.split_once('-') {
+                    Some((lo, hi)) => {
+                        let (lo, hi) = (parse(lo)?, parse(hi)?);
+                        if lo > hi {
+                            return Err(anyhow!("invalid range '{key}'"));
+                        }
+                        self.ranges.push((lo, hi, name.to_string()));
+                    }
+                    None => {
+                        self.exact.insert(parse(key)?, name.to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(flags) = &mut self.flags {
+            if !self.ranges.is_empty() {
+                return Err(anyhow!("flag tables can't have ranges"));
+            }
+            *flags = self
+                .exact
+                .iter()
+                .filter(|(mask, _)| **mask != 0)
+                .map(|(mask, name)| (*mask, name.clone()))
+                .collect();
+            flags.sort_by_key(|(mask, _)| (std::cmp::Reverse(mask.count_ones()), *mask));
+        }
+        // Narrowest ranges first:
+        self.ranges.sort_by_key(|(lo, hi, _)| (hi - lo, *lo));
+        Ok(())
+    }
+
+    pub fn decode(&self, value: u64) -> String {
+        if let Some(name) = self.exact.get(&value) {
+            return name.clone();
+        }
+
+        if let Some(flags) = &self.flags {
+            let mut names = vec![];
+            let mut rest = value;
+            for (mask, name) in flags {
+                if rest & mask == *mask {
+                    names.push(name.clone());
+                    rest &= !mask;
+                }
+            }
+            if names.is_empty() {
+                return self.fallback(value);
+            }
+            if rest != 0 {
+                names.push(self.fallback(rest));
+            }
+            return names.join("|");
+        }
+
+        match self
+            .ranges
+            .iter()
+            .find(|(lo, hi, _)| (*lo..=*hi).contains(&value))
+        {
+            Some((_, _, name)) => name.clone(),
+            None => self.fallback(value),
+        }
+    }
+
+    fn fallback(&self, value: u64) -> String {
+        self.fallback
+            .as_deref()
+            .unwrap_or(DEFAULT_FALLBACK)
+            .replace("{value}", &value.to_string())
+            .replace("{hex}", &format!("0x{value:x}"))
+    }
+}
+
+/// Tables by name.
+#[derive(Debug, Clone, Default)]
+pub struct EnumTables {
+    tables: HashMap<String, EnumTable>,
+}
+
+impl EnumTables {
+    /// Load the tables of a `[name=]file` (TOML, JSON or CSV, by extension).
+    /// Tables of earlier files with the same name are extended.
+    pub fn load(&mut self, spec: &str) -> anyhow::Result<()> {
+        let (name, path) = match spec.split_once('=') {
+            Some((name, path))
+                if !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+            {
+                (Some(name), path)
+            }
+            _ => (None, spec),
+        };
+        let path = Path::new(path);
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read enum table {} - {e}", path.display()))?;
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let entries = match extension.as_deref() {
+            Some("toml") => toml_entries(&text, name),
+            Some("json") => json_entries(&text, name),
+            Some("csv") => csv_entries(&text, name),
+            _ => Err(anyhow!("unknown format - expected .toml, .json or .csv")),
+        }
+        .map_err(|e| anyhow!("Failed to load enum table {} - {e}", path.display()))?;
+        self.add(entries)
+            .map_err(|e| anyhow!("Failed to load enum table {} - {e}", path.display()))
+    }
+
+    fn add(&mut self, entries: Vec<(String, String, String)>) -> anyhow::Result<()> {
+        let mut names = vec![];
+        for (table, key, name) in entries {
+            self.tables
+                .entry(table.clone())
+                .or_default()
+                .add(&key, &name)
+                .map_err(|e| anyhow!("table '{table}': {e}"))?;
+            if !names.contains(&table) {
+                names.push(table);
+            }
+        }
+        for table in names {
+            if let Some(t) = self.tables.get_mut(&table) {
+                t.finish().map_err(|e| anyhow!("table '{table}': {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, table: &str) -> Option<&EnumTable> {
+        self.tables.get(table)
+    }
+}
+
+/// `(table, key, name)` entries of a TOML file.
+fn toml_entries(text: &str, name: Option<&str>) -> anyhow::Result<Vec<(String, String, String)>> {
+    let root: toml::Table = text.parse()?;
+    let table_entries = |table: &str, entries: &toml::Table| {
+        entries
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Boolean(b) => b.to_string(),
+                    _ => return Err(anyhow!("table '{table}': invalid entry '{key}'")),
+                };
+                Ok((table.to_string(), key.clone(), value))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    match name {
+        Some(name) => table_entries(name, &root),
+        None => {
+            let mut result = vec![];
+            for (table, entries) in &root {
+                let toml::Value::Table(entries) = entries else {
+                    return Err(anyhow!("'{table}' is not a table"));
+                };
+                result.extend(table_entries(table, entries)?);
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// `(table, key, name)` entries of a JSON file.
+fn json_entries(text: &str, name: Option<&str>) -> anyhow::Result<Vec<(String, String, String)>> {
+    use serde_json::Value;
+
+    let root: Value = serde_json::from_str(text)?;
+    let table_entries = |table: &str, entries: &Value| {
+        let Value::Object(entries) = entries else {
+            return Err(anyhow!("'{table}' is not an object"));
+        };
+        entries
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return Err(anyhow!("table '{table}': invalid entry '{key}'")),
+                };
+                Ok((table.to_string(), key.clone(), value))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    match name {
+        Some(name) => table_entries(name, &root),
+        None => {
+            let Value::Object(tables) = &root else {
+                return Err(anyhow!("expected an object of tables"));
+            };
+            let mut result = vec![];
+            for (table, entries) in tables {
+                result.extend(table_entries(table, entries)?);
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// `(table, key, name)` entries of a CSV file with `table,key,name` rows, or
+/// `key,name` rows for a single table.
+fn csv_entries(text: &str, name: Option<&str>) -> anyhow::Result<Vec<(String, String, String)>> {
+    let mut result = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = split_csv_line(line);
+        let entry = match (name, fields.as_slice()) {
+            (Some(name), [key, value]) => (name.to_string(), key.clone(), value.clone()),
+            (None, [table, key, value]) => (table.clone(), key.clone(), value.clone()),
+            _ => {
+                let expected = if name.is_some() { 2 } else { 3 };
+                return Err(anyhow!(
+                    "line {}: expected {expected} fields, found {}",
+                    idx + 1,
+                    fields.len()
+                ));
+            }
+        };
+        // Optional header:
+        if result.is_empty() && matches!(entry.1.to_lowercase().as_str(), "key" | "value") {
+            continue;
+        }
+        result.push(entry);
+    }
+    Ok(result)
+}
+
+/// Split a CSV line, allowing double-quoted fields (with `""` escapes).
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            ch => field.push(ch),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Resolves `$enum:<table>:<value>` placeholders.
+pub struct EnumAnnotator {
+    tables: EnumTables,
+}
+
+impl EnumAnnotator {
+    pub fn new(tables: EnumTables) -> Self {
+        Self { tables }
+    }
+}
+
+impl Annotater for EnumAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        vec!["enum".to_string()]
+    }
+
+    fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+        let Some((table, value)) = placeholder.value.split_once(':') else {
+            warn!(
+                "enum: expected table:value, not '{}' - skipping.",
+                placeholder.value
+            );
+            return Ok(None);
+        };
+        let Some(table_ref) = self.tables.get(table) else {
+            warn!("enum: unknown table '{table}' - skipping.");
+            return Ok(None);
+        };
+        let Ok(value) = utils::string_to_u64(value) else {
+            warn!("enum: invalid number '{value}' - skipping.");
+            return Ok(None);
+        };
+        Ok(Some(table_ref.decode(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enum_tables() {
+        let toml = r#"
+            [fsm]
+            0 = "IDLE"
+            1 = "BUSY"
+            0x10-0x1f = "RESERVED"
+            0x18-0x19 = "DEBUG"
+            fallback = "STATE_{value}"
+
+            [access]
+            flags = true
+            0x1 = "READ"
+            0x2 = "WRITE"
+            0x3 = "RW"
+            0x4 = "EXCL"
+        "#;
+        let mut tables = EnumTables::default();
+        tables.add(toml_entries(toml, None).unwrap()).unwrap();
+        tables
+            .add(csv_entries("key,name\n2,\"ERR, fatal\"\n", Some("err")).unwrap())
+            .unwrap();
+
+        let fsm = tables.get("fsm").unwrap();
+        assert_eq!(fsm.decode(1), "BUSY");
+        assert_eq!(fsm.decode(0x12), "RESERVED");
+        assert_eq!(fsm.decode(0x18), "DEBUG");
+        assert_eq!(fsm.decode(7), "STATE_7");
+
+        let access = tables.get("access").unwrap();
+        assert_eq!(access.decode(0x5), "READ|EXCL");
+        assert_eq!(access.decode(0x7), "RW|EXCL");
+        assert_eq!(access.decode(0x41), "READ|0x40");
+        assert_eq!(access.decode(0), "0x0");
+
+        assert_eq!(tables.get("err").unwrap().decode(2), "ERR, fatal");
+    }
+}
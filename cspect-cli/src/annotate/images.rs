@@ -0,0 +1,319 @@
+//! Symbolization of traces covering several program images (e.g. bootloader,
+//! OS and per-core firmware), each from an ELF or linker map file.
+
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use log::warn;
+
+use crate::utils;
+
+use super::{
+    addr2line::Addr2LineAnnotator,
+    linkmap::{LinkMap, LinkMapAnnotator},
+    symbols::{Symbol, SymbolAnnotator, SymbolTable},
+    variables::VariableTable,
+    Annotater, Placeholder,
+};
+
+/// Image file, given as `path[,tag=NAME][,offset=ADDR][,range=START-END]`.
+///
+/// The image is loaded `offset` bytes above its link address, and only
+/// resolves trace addresses in `range` (end exclusive). A tagged image can be
+/// selected explicitly with placeholders like `$a2l@<tag>:<addr>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSpec {
+    pub path: PathBuf,
+    pub tag: Option<String>,
+    /// Load offset, wrapping for images loaded below their link address.
+    pub offset: u64,
+    pub range: Option<(u64, u64)>,
+}
+
+impl FromStr for ImageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let path = parts.next().unwrap_or_default();
+        if path.is_empty() {
+            return Err(format!("invalid image '{s}' - no path given"));
+        }
+        let mut spec = ImageSpec {
+            path: PathBuf::from(path),
+            tag: None,
+            offset: 0,
+            range: None,
+        };
+
+        let parse_addr = |addr: &str| {
+            utils::string_to_u64(addr).map_err(|e| format!("invalid address '{addr}' - {e}"))
+        };
+        for option in parts {
+            match option.split_once('=') {
+                Some(("tag", tag)) => {
+                    if tag.is_empty()
+                        || tag.contains(|c: char| c.is_whitespace() || matches!(c, ':' | '@' | '$'))
+                    {
+                        return Err(format!("invalid image tag '{tag}'"));
+                    }
+                    spec.tag = Some(tag.to_string());
+                }
+                Some(("offset", offset)) => {
+                    spec.offset = match offset.strip_prefix('-') {
+                        Some(offset) => parse_addr(offset)?.wrapping_neg(),
+                        None => parse_addr(offset)?,
+                    };
+                }
+                Some(("range", range)) => {
+                    let Some((start, end)) = range.split_once('-') else {
+                        return Err(format!("invalid range '{range}' - expected START-END"));
+                    };
+                    let (start, end) = (parse_addr(start)?, parse_addr(end)?);
+                    if start >= end {
+                        return Err(format!("invalid range '{range}' - empty"));
+                    }
+                    spec.range = Some((start, end));
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid image option '{option}' - expected tag=, offset= or range="
+                    ))
+                }
+            }
+        }
+        Ok(spec)
+    }
+}
+
+pub struct Image {
+    spec: ImageSpec,
+    /// Symbols at their link addresses.
+    symbols: Arc<SymbolTable>,
+    /// Annotators of the image with their keys.
+    annotators: Vec<(Vec<String>, Box<dyn AddressAnnotater>)>,
+}
+
+/// Resolves placeholders of addresses within an image.
+pub trait AddressAnnotater {
+    fn accepts_keys(&self) -> Vec<String>;
+
+    /// Resolve the placeholder of `kind` at link address `addr`, showing the
+    /// address as it appears in the trace (`trace_addr`, which differs for
+    /// images loaded at an offset).
+    fn annotate_addr(
+        &mut self,
+        kind: &str,
+        addr: u64,
+        trace_addr: u64,
+    ) -> anyhow::Result<Option<String>>;
+}
+
+impl Image {
+    fn new(
+        spec: ImageSpec,
+        symbols: Arc<SymbolTable>,
+        annotators: Vec<Box<dyn AddressAnnotater>>,
+    ) -> Self {
+        let annotators = annotators
+            .into_iter()
+            .map(|annotator| (annotator.accepts_keys(), annotator))
+            .collect();
+        Self {
+            spec,
+            symbols,
+            annotators,
+        }
+    }
+
+    /// Image from an ELF file, resolving `$a2l`, `$sym` and `$var`.
+    pub fn elf(spec: ImageSpec) -> anyhow::Result<Self> {
+        let symbols = Arc::new(SymbolTable::load(&spec.path)?);
+        let annotators: Vec<Box<dyn AddressAnnotater>> = vec![
+            Box::new(Addr2LineAnnotator::new(&spec.path)?),
+            Box::new(SymbolAnnotator::new(
+                symbols.clone(),
+                VariableTable::load(&spec.path)?,
+            )),
+        ];
+        Ok(Self::new(spec, symbols, annotators))
+    }
+
+    /// Image from a linker map file, resolving `$a2l` and `$sym`.
+    pub fn map(spec: ImageSpec) -> anyhow::Result<Self> {
+        let map = LinkMap::load(&spec.path)?;
+        let symbols = Arc::new(map.symbols().clone());
+        Ok(Self::new(
+            spec,
+            symbols,
+            vec![Box::new(LinkMapAnnotator::new(map))],
+        ))
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        match self.spec.range {
+            Some((start, end)) => addr >= start && addr < end,
+            None => true,
+        }
+    }
+}
+
+/// Dispatches `$a2l`, `$sym` and `$var` placeholders to images: Plain ones to
+/// the first image supporting them whose range contains the address, tagged
+/// ones (`$a2l@<tag>:<addr>`) to the image with that tag. Images resolve (and show)
+/// addresses relative to their link address.
+pub struct ImageAnnotator {
+    images: Vec<Image>,
+}
+
+impl ImageAnnotator {
+    pub fn new(images: Vec<Image>) -> Self {
+        Self { images }
+    }
+
+    /// Symbols of all images at their load addresses, in trace address
+    /// ranges they resolve.
+    pub fn symbols(&self) -> SymbolTable {
+        let mut symbols = vec![];
+        for image in &self.images {
+            for sym in image.symbols.symbols() {
+                let address = sym.address.wrapping_add(image.spec.offset);
+                if image.contains(address) {
+                    symbols.push(Symbol {
+                        address,
+                        ..sym.clone()
+                    });
+                }
+            }
+        }
+        SymbolTable::from_symbols(symbols)
+    }
+}
+
+impl Annotater for ImageAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = vec![];
+        for image in &self.images {
+            for key in image.annotators.iter().flat_map(|(keys, _)| keys) {
+                let tagged = image.spec.tag.as_ref().map(|tag| format!("{key}@{tag}"));
+                for key in std::iter::once(key.clone()).chain(tagged) {
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+        let (key, tag) = match placeholder.kind.split_once('@') {
+            Some((key, tag)) => (key, Some(tag)),
+            None => (placeholder.kind.as_str(), None),
+        };
+        let Ok(addr) = utils::string_to_u64(&placeholder.value) else {
+            warn!("{key}: invalid number '{}' - skipping.", placeholder.value);
+            return Ok(None);
+        };
+
+        let image = self.images.iter_mut().find(|image| {
+            let matches = match tag {
+                Some(tag) => image.spec.tag.as_deref() == Some(tag),
+                None => image.contains(addr),
+            };
+            matches
+                && image
+                    .annotators
+                    .iter()
+                    .any(|(keys, _)| keys.iter().any(|k| k == key))
+        });
+        let Some(image) = image else {
+            return Ok(Some(format!("? (0x{addr:08x})")));
+        };
+        let offset = image.spec.offset;
+        let Some((_, annotator)) = image
+            .annotators
+            .iter_mut()
+            .find(|(keys, _)| keys.iter().any(|k| k == key))
+        else {
+            return Ok(None);
+        };
+
+        annotator.annotate_addr(key, addr.wrapping_sub(offset), addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_images() {
+        assert_eq!(
+            "fw.elf,tag=core1,offset=-0x100,range=0x1000-0x2000".parse(),
+            Ok(ImageSpec {
+                path: "fw.elf".into(),
+                tag: Some("core1".into()),
+                offset: 0x100u64.wrapping_neg(),
+                range: Some((0x1000, 0x2000)),
+            })
+        );
+        assert!("fw.elf,tag=a:b".parse::<ImageSpec>().is_err());
+        assert!("fw.elf,range=0x2000-0x1000".parse::<ImageSpec>().is_err());
+        assert!("fw.elf,base=0".parse::<ImageSpec>().is_err());
+
+        let map = |spec: &str, text: &str| {
+            let map = LinkMap::parse(text).unwrap();
+            Image::new(
+                spec.parse().unwrap(),
+                Arc::new(map.symbols().clone()),
+                vec![Box::new(LinkMapAnnotator::new(map))],
+            )
+        };
+        let boot = map(
+            "boot.map,range=0x0-0x1000",
+            "             VMA     Size Align Out     In      Symbol\n\
+             \x20            0       20     4           boot.o:(.text)\n\
+             \x20            0        0     1                   reset\n",
+        );
+        let core1 = map(
+            "core1.map,tag=core1,offset=0x10000",
+            "             VMA     Size Align Out     In      Symbol\n\
+             \x20            0       20     4           main.o:(.text)\n\
+             \x20            0        0     1                   main\n",
+        );
+        let mut images = ImageAnnotator::new(vec![boot, core1]);
+        assert_eq!(
+            images.accepts_keys(),
+            vec!["a2l", "sym", "a2l@core1", "sym@core1"]
+        );
+
+        let mut annotate = |kind: &str, value: &str| {
+            images
+                .annotate(&Placeholder {
+                    kind: kind.into(),
+                    value: value.into(),
+                })
+                .unwrap()
+        };
+        assert_eq!(
+            annotate("sym", "0x4").as_deref(),
+            Some("reset+0x4 (0x00000004)")
+        );
+        assert_eq!(
+            annotate("sym@core1", "0x10008").as_deref(),
+            Some("main+0x8 (0x00010008)")
+        );
+        assert_eq!(
+            annotate("sym", "0x10008").as_deref(),
+            Some("main+0x8 (0x00010008)")
+        );
+        assert_eq!(
+            annotate("sym@core2", "0x10008").as_deref(),
+            Some("? (0x00010008)")
+        );
+        assert_eq!(
+            images.symbols().symbolize(0x10004).as_deref(),
+            Some("main+0x4")
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Symbolization from linker map files (GNU ld and LLVM lld), for builds
+//! that only provide a map file and a raw binary.
+
+use std::{borrow::Cow, fs, path::Path};
+
+use anyhow::anyhow;
+
+use super::{
+    images::AddressAnnotater,
+    symbols::{Symbol, SymbolKind, SymbolTable},
+};
+
+/// Start of the input section list in GNU ld maps. Discarded input sections
+/// are listed before it.
+const GNU_MAP_START: &str = "Linker script and memory map";
+
+/// Input section of the link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// Object file the section was taken from.
+    pub file: Option<String>,
+}
+
+impl Section {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.address && addr - self.address < self.size
+    }
+}
+
+/// Input sections and symbols of a linker map.
+#[derive(Debug, Clone, Default)]
+pub struct LinkMap {
+    /// Sorted by address.
+    sections: Vec<Section>,
+    symbols: SymbolTable,
+}
+
+impl LinkMap {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read map file {} - {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| anyhow!("Failed to parse {} - {e}", path.display()))
+    }
+
+    /// Parse a GNU ld (`-Map`) or LLVM lld (`--Map`) map file.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut builder = MapBuilder::default();
+        let first = text.lines().find(|line| !line.trim().is_empty());
+        match first {
+            Some(header) if is_lld_header(header) => parse_lld(text, header, &mut builder),
+            _ => parse_gnu(text, &mut builder),
+        }
+        if builder.sections.is_empty() {
+            return Err(anyhow!("no sections found"));
+        }
+        Ok(builder.finish())
+    }
+
+    pub fn section(&self, addr: u64) -> Option<&Section> {
+        let end = self.sections.partition_point(|sec| sec.address <= addr);
+        self.sections[..end].last().filter(|sec| sec.contains(addr))
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// `addr` as `symbol+offset`, or `section+offset` if no symbol contains it.
+    pub fn symbolize(&self, addr: u64) -> Option<String> {
+        self.symbols.symbolize(addr).or_else(|| {
+            let section = self.section(addr)?;
+            Some(match addr - section.address {
+                0 => section.name.clone(),
+                offset => format!("{}+0x{offset:x}", section.name),
+            })
+        })
+    }
+
+    /// Best-effort source location of `addr`: `file.o:symbol+offset`.
+    pub fn location(&self, addr: u64) -> Option<String> {
+        let name = self.symbolize(addr)?;
+        let file = self.section(addr).and_then(|sec| sec.file.as_deref());
+        Some(match file {
+            Some(file) => {
+                // Trim to the file name, like addr2line does:
+                let file = Path::new(file)
+                    .file_name()
+                    .map(|f| f.to_string_lossy())
+                    .unwrap_or(Cow::Borrowed(file));
+                format!("{file}:{name}")
+            }
+            None => name,
+        })
+    }
+}
+
+#[derive(Default)]
+struct MapBuilder {
+    sections: Vec<Section>,
+    /// Symbols with the index of their section.
+    symbols: Vec<(String, u64, usize)>,
+}
+
+impl MapBuilder {
+    /// Add an input section, returning its index if it is loaded.
+    fn section(&mut self, name: &str, address: u64, size: u64, file: &str) -> Option<usize> {
+        if !is_loaded(name) {
+            return None;
+        }
+        self.sections.push(Section {
+            name: name.to_string(),
+            address,
+            size,
+            file: (!file.is_empty()).then(|| file.to_string()),
+        });
+        Some(self.sections.len() - 1)
+    }
+
+    fn symbol(&mut self, name: &str, address: u64, section: usize) {
+        self.symbols.push((name.to_string(), address, section));
+    }
+
+    fn finish(mut self) -> LinkMap {
+        // Maps don't give symbol sizes, so each symbol extends to the next
+        // one in its section, or the end of the section:
+        self.symbols
+            .sort_by(|(_, a_addr, a_sec), (_, b_addr, b_sec)| {
+                (a_sec, a_addr).cmp(&(b_sec, b_addr))
+            });
+        let mut symbols = Vec::with_capacity(self.symbols.len());
+        for (idx, (name, address, sec_idx)) in self.symbols.iter().enumerate() {
+            let section = &self.sections[*sec_idx];
+            let end = self.symbols[idx + 1..]
+                .iter()
+                .take_while(|(_, _, next_sec)| next_sec == sec_idx)
+                .map(|(_, next_addr, _)| *next_addr)
+                .find(|next_addr| next_addr > address)
+                .unwrap_or(section.address.saturating_add(section.size));
+            let kind = if section.name.starts_with(".text") || section.name.contains(".text.") {
+                SymbolKind::Function
+            } else {
+                SymbolKind::Object
+            };
+            symbols.push(Symbol {
+                name: addr2line::demangle_auto(Cow::from(name.as_str()), None).into_owned(),
+                address: *address,
+                size: end.saturating_sub(*address),
+                kind,
+            });
+        }
+
+        self.sections.sort_by_key(|sec| sec.address);
+        LinkMap {
+            sections: self.sections,
+            symbols: SymbolTable::from_symbols(symbols),
+        }
+    }
+}
+
+/// Whether a section is loaded to memory (as opposed to debug info and
+/// other metadata).
+fn is_loaded(name: &str) -> bool {
+    const NOT_LOADED: &[&str] = &[
+        ".debug",
+        ".comment",
+        ".note",
+        ".stab",
+        ".riscv.attributes",
+        ".ARM.attributes",
+        ".gnu.attributes",
+        ".symtab",
+        ".strtab",
+        ".shstrtab",
+    ];
+    !NOT_LOADED.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Parse a GNU ld map:
+///
+/// ```text
+///  .text.main     0x0000000080000100       0x58 build/main.o
+///                 0x0000000080000100                main
+///  .text.a_rather_long_name
+///                 0x0000000080000158       0x10 build/main.o
+/// ```
+fn parse_gnu(text: &str, builder: &mut MapBuilder) {
+    let start = text.find(GNU_MAP_START).unwrap_or(0);
+    let mut pending_name: Option<&str> = None;
+    let mut section: Option<usize> = None;
+
+    for line in text[start..].lines() {
+        let trimmed = line.trim_start();
+        let tokens: Vec<&str> = trimmed.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        // Address, size and file of an input section with a long name:
+        if let Some(name) = pending_name.take() {
+            section = match tokens.as_slice() {
+                [addr, size, file @ ..] => match (parse_hex(addr), parse_hex(size)) {
+                    (Some(addr), Some(size)) => builder.section(name, addr, size, &file.join(" ")),
+                    _ => None,
+                },
+                _ => None,
+            };
+            continue;
+        }
+
+        match line.len() - trimmed.len() {
+            // Output sections and linker script statements:
+            0 => section = None,
+            // Input sections:
+            1 => {
+                let name = tokens[0];
+                section = None;
+                // Skip fill and input section patterns (`*(.text*)`):
+                if name.starts_with('*') {
+                    continue;
+                }
+                match &tokens[1..] {
+                    [] if name.starts_with('.') || name == "COMMON" => pending_name = Some(name),
+                    [addr, size, file @ ..] => {
+                        if let (Some(addr), Some(size)) = (parse_hex(addr), parse_hex(size)) {
+                            section = builder.section(name, addr, size, &file.join(" "));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Symbols, skipping assignments (`__stack_top = .`):
+            _ => {
+                let (Some(sec_idx), Some(addr)) = (section, parse_hex(tokens[0])) else {
+                    continue;
+                };
+                let name = trimmed[tokens[0].len()..].trim();
+                if !name.is_empty() && !name.contains('=') && !name.starts_with("PROVIDE") {
+                    builder.symbol(name, addr, sec_idx);
+                }
+            }
+        }
+    }
+}
+
+fn is_lld_header(line: &str) -> bool {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    matches!(tokens.first(), Some(&"VMA") | Some(&"Address"))
+        && tokens.contains(&"Size")
+        && tokens.contains(&"Symbol")
+}
+
+/// Parse an LLVM lld map:
+///
+/// ```text
+///              VMA              LMA     Size Align Out     In      Symbol
+///         80000000         80000000      1a4     4 .text
+///         80000100         80000100       58     4         build/main.o:(.text.main)
+///         80000100         80000100        0     1                 main
+/// ```
+///
+/// Older versions of lld don't have the `LMA` column.
+fn parse_lld(text: &str, header: &str, builder: &mut MapBuilder) {
+    let columns = if header.contains("LMA") { 4 } else { 3 };
+    let size_column = columns - 2;
+    let column_of = |name: &str| {
+        header
+            .find(&format!(" {name} "))
+            .or_else(|| header.find(&format!(" {name}")))
+            .map(|pos| pos + 1)
+            .unwrap_or(usize::MAX)
+    };
+    let (in_col, symbol_col) = (column_of("In"), column_of("Symbol"));
+
+    let mut section: Option<usize> = None;
+    for line in text.lines().skip_while(|line| *line != header).skip(1) {
+        // Numeric columns, followed by the indented name:
+        let mut values = vec![];
+        let mut rest = line;
+        for _ in 0..columns {
+            let trimmed = rest.trim_start();
+            let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+            match parse_hex(&trimmed[..end]) {
+                Some(value) => values.push(value),
+                None => break,
+            }
+            rest = &trimmed[end..];
+        }
+        let name = rest.trim();
+        if values.len() < columns || name.is_empty() {
+            continue;
+        }
+        let name_col = line.len() - rest.trim_start().len();
+
+        if name_col >= symbol_col {
+            if let Some(sec_idx) = section {
+                if !name.contains(" = ") && !name.starts_with("PROVIDE") {
+                    builder.symbol(name, values[0], sec_idx);
+                }
+            }
+        } else if name_col >= in_col {
+            let (file, sec_name) = match name.rsplit_once(":(") {
+                Some((file, sec_name)) => (file, sec_name.trim_end_matches(')')),
+                None => ("", name),
+            };
+            section = builder.section(sec_name, values[0], values[size_column], file);
+        } else {
+            section = None;
+        }
+    }
+}
+
+/// Resolves `$a2l:<addr>` (as `file.o:symbol+offset`) and `$sym:<addr>` from a
+/// linker map.
+pub struct LinkMapAnnotator {
+    map: LinkMap,
+}
+
+impl LinkMapAnnotator {
+    pub fn new(map: LinkMap) -> Self {
+        Self { map }
+    }
+}
+
+impl AddressAnnotater for LinkMapAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        vec!["a2l".to_string(), "sym".to_string()]
+    }
+
+    fn annotate_addr(
+        &mut self,
+        kind: &str,
+        addr: u64,
+        trace_addr: u64,
+    ) -> anyhow::Result<Option<String>> {
+        let name = match kind {
+            "a2l" => self.map.location(addr),
+            _ => self.map.symbolize(addr),
+        };
+        let name = name.unwrap_or_else(|| "?".to_string());
+        Ok(Some(format!("{name} (0x{trace_addr:08x})")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU_MAP: &str = "\
+Discarded input sections
+
+ .text.unused   0x0000000000000000       0x10 build/main.o
+
+Linker script and memory map
+
+LOAD build/main.o
+
+.text           0x0000000080000000      0x168
+ *(.text.init)
+ .text.init     0x0000000080000000       0x10 build/crt0.o
+                0x0000000080000000                _start
+ .text.main     0x0000000080000010       0x58 build/main.o
+                0x0000000080000010                main
+                0x0000000080000040                helper
+ .text.a_rather_long_function_name
+                0x0000000080000068      0x100 build/lib/util.o
+                0x0000000080000068                a_rather_long_function_name
+.bss            0x0000000080001000       0x40
+                0x0000000080001000                __bss_start = .
+ .bss           0x0000000080001000       0x40 build/main.o
+                0x0000000080001000                uart_buf
+.debug_info     0x0000000000000000      0x1e4
+ .debug_info    0x0000000000000000      0x1e4 build/main.o
+";
+
+    // The header is indented, so the string must not start with a line continuation:
+    const LLD_MAP: &str = "
+             VMA              LMA     Size Align Out     In      Symbol
+        80000000         80000000       68     4 .text
+        80000000         80000000       10     4         build/crt0.o:(.text.init)
+        80000000         80000000        0     1                 _start
+        80000010         80000010       58     4         build/main.o:(.text.main)
+        80000010         80000010        0     1                 main
+        80001000         80001000       40     8 .bss
+        80001000         80001000       40     8         build/main.o:(.bss)
+        80001000         80001000        0     1                 uart_buf
+";
+
+    #[test]
+    fn test_parse() {
+        for text in [GNU_MAP, LLD_MAP] {
+            let map = LinkMap::parse(text).unwrap();
+            assert_eq!(map.symbolize(0x8000_0004).as_deref(), Some("_start+0x4"));
+            assert_eq!(
+                map.location(0x8000_0014).as_deref(),
+                Some("main.o:main+0x4")
+            );
+            assert_eq!(map.symbolize(0x8000_1010).as_deref(), Some("uart_buf+0x10"));
+            assert_eq!(map.symbolize(0x10), None);
+        }
+
+        let map = LinkMap::parse(GNU_MAP).unwrap();
+        assert_eq!(map.symbolize(0x8000_0044).as_deref(), Some("helper+0x4"));
+        assert_eq!(
+            map.location(0x8000_0070).as_deref(),
+            Some("util.o:a_rather_long_function_name+0x8")
+        );
+
+        // Sections extending past the end of the address space:
+        let map = LinkMap::parse(
+            "             VMA     Size Align Out     In      Symbol\n\
+             \x20ffffffffffffff00      200     4           top.o:(.text)\n\
+             \x20ffffffffffffff00        0     1                   top\n",
+        )
+        .unwrap();
+        assert_eq!(
+            map.symbolize(0xffff_ffff_ffff_ff10).as_deref(),
+            Some("top+0x10")
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Selection of RISC-V instruction tables from `-march` style ISA strings
+//! (e.g. `rv32imac_zicsr_zifencei`).
+
+use anyhow::anyhow;
+use log::warn;
+
+macro_rules! table {
+    ($name:literal) => {
+        include_str!(concat!(
+            "../../../third_party/instruction-decoder/toml/",
+            $name,
+            ".toml"
+        ))
+    };
+}
+
+/// Instruction tables of each extension: `(extension, RV32 tables, RV64 tables)`.
+///
+/// Extensions without tables for one XLEN are accepted, but can't be decoded.
+#[rustfmt::skip]
+const EXTENSIONS: &[(&str, &[&str], &[&str])] = &[
+    ("i", &[table!("RV32I")], &[table!("RV64I")]),
+    ("m", &[table!("RV32M")], &[table!("RV64M")]),
+    ("a", &[table!("RV32A")], &[table!("RV64A")]),
+    // There is no separate RV64F table:
+    ("f", &[table!("RV32F")], &[table!("RV64D")]),
+    ("d", &[], &[table!("RV64D")]),
+    ("c", &[table!("RV32C-lower")], &[table!("RV64C-lower")]),
+    ("v", &[table!("RVV")], &[table!("RVV")]),
+    ("zicsr", &[table!("RV32_Zicsr")], &[]),
+    ("zifencei", &[table!("RV_Zifencei")], &[table!("RV_Zifencei")]),
+    ("zicbom", &[table!("RV_Zicbo")], &[table!("RV_Zicbo")]),
+    ("zicbop", &[table!("RV_Zicbo")], &[table!("RV_Zicbo")]),
+    ("zicboz", &[table!("RV_Zicbo")], &[table!("RV_Zicbo")]),
+    ("zicond", &[table!("RV_Zicond")], &[table!("RV_Zicond")]),
+    ("zihintntl", &[table!("RV_Zihintntl")], &[table!("RV_Zihintntl")]),
+    ("zimop", &[table!("RV_Zimop")], &[table!("RV_Zimop")]),
+    ("zawrs", &[table!("RV_Zawrs")], &[table!("RV_Zawrs")]),
+    ("zacas", &[table!("RV32_Zacas")], &[table!("RV64_Zacas")]),
+    ("zfa", &[table!("RV32_Zfa")], &[table!("RV64_Zfa")]),
+    ("zfh", &[table!("RV_Zfh")], &[table!("RV_Zfh")]),
+    ("zba", &[table!("RV_Zba")], &[table!("RV_Zba")]),
+    ("zbb", &[table!("RV32_Zbb")], &[table!("RV64_Zbb")]),
+    ("zbc", &[table!("RV_Zbc")], &[table!("RV_Zbc")]),
+    ("zbs", &[table!("RV32_Zbs")], &[table!("RV64_Zbs")]),
+    ("zbkb", &[table!("RV32_Zbkb")], &[table!("RV64_Zbkb")]),
+    ("zbkc", &[table!("RV_Zbkc")], &[table!("RV_Zbkc")]),
+    ("zbkx", &[table!("RV_Zbkx")], &[table!("RV_Zbkx")]),
+    ("zknd", &[table!("RV32_Zknd")], &[table!("RV64_Zknd")]),
+    ("zkne", &[table!("RV32_Zkne")], &[table!("RV64_Zkne")]),
+    ("zknh", &[table!("RV_Zknh")], &[table!("RV_Zknh")]),
+    ("zksed", &[table!("RV_Zksed")], &[table!("RV_Zksed")]),
+    ("zksh", &[table!("RV_Zksh")], &[table!("RV_Zksh")]),
+    ("zcb", &[table!("RV32_Zcb-lower")], &[table!("RV64_Zcb-lower")]),
+    ("zcf", &[table!("RV32_Zcf-lower")], &[]),
+    ("zcd", &[table!("RV_Zcd-lower")], &[table!("RV64_Zcd-lower"), table!("RV_Zcd-lower")]),
+    ("zvbb", &[table!("RV_Zvbb")], &[table!("RV_Zvbb")]),
+    ("zvbc", &[table!("RV_Zvbc")], &[table!("RV_Zvbc")]),
+    ("zvkg", &[table!("RV_Zvkg")], &[table!("RV_Zvkg")]),
+    ("zvkned", &[table!("RV_Zvkned")], &[table!("RV_Zvkned")]),
+    ("zvknha", &[table!("RV_Zvknha")], &[table!("RV_Zvknha")]),
+    ("zvknhb", &[table!("RV_Zvknhb")], &[table!("RV_Zvknhb")]),
+    ("zvksed", &[table!("RV_Zvksed")], &[table!("RV_Zvksed")]),
+    ("zvksh", &[table!("RV_Zvksh")], &[table!("RV_Zvksh")]),
+];
+
+/// Extensions decoded with the tables of others: `(extension, extensions)`.
+///
+/// Subsets (e.g. `zmmul` of `m`) use the tables of the full extension, as
+/// the missing instructions can't appear in a valid program anyway.
+const ALIASES: &[(&str, &[&str])] = &[
+    ("b", &["zba", "zbb", "zbs"]),
+    ("zca", &["c"]),
+    ("zmmul", &["m"]),
+    ("zaamo", &["a"]),
+    ("zalrsc", &["a"]),
+];
+
+/// A parsed `-march` string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct March {
+    pub xlen: u32,
+    /// Extensions in order, without duplicates.
+    pub extensions: Vec<String>,
+}
+
+impl March {
+    pub fn parse(march: &str) -> anyhow::Result<Self> {
+        let lower = march.trim().to_ascii_lowercase();
+        let (xlen, rest) = if let Some(rest) = lower.strip_prefix("rv32") {
+            (32, rest)
+        } else if let Some(rest) = lower.strip_prefix("rv64") {
+            (64, rest)
+        } else {
+            return Err(anyhow!(
+                "invalid march '{march}' - must start with rv32 or rv64"
+            ));
+        };
+
+        let mut extensions: Vec<String> = vec![];
+        let push = |extensions: &mut Vec<String>, ext: &str| {
+            if !extensions.iter().any(|e| e == ext) {
+                extensions.push(ext.to_string());
+            }
+        };
+
+        let mut parts = rest.split('_').filter(|p| !p.is_empty());
+        // Single-letter extensions, possibly followed by the first
+        // multi-letter one:
+        let mut multi = vec![];
+        if let Some(single) = parts.next() {
+            let mut prev_digit = false;
+            for (idx, ch) in single.char_indices() {
+                let digit = std::mem::replace(&mut prev_digit, ch.is_ascii_digit());
+                match ch {
+                    'z' | 's' | 'x' => {
+                        multi.push(&single[idx..]);
+                        break;
+                    }
+                    // Version numbers (e.g. `i2p1`):
+                    '0'..='9' => continue,
+                    'p' if digit => continue,
+                    'g' => {
+                        for ext in ["i", "m", "a", "f", "d", "zicsr", "zifencei"] {
+                            push(&mut extensions, ext);
+                        }
+                    }
+                    'e' => push(&mut extensions, "i"),
+                    ch if ch.is_ascii_lowercase() => push(&mut extensions, &ch.to_string()),
+                    ch => return Err(anyhow!("invalid march '{march}' - unexpected '{ch}'")),
+                }
+            }
+        }
+        multi.extend(parts);
+
+        for ext in multi {
+            push(&mut extensions, strip_version(ext));
+        }
+
+        // Compressed floating point loads/stores are implied by C:
+        if extensions.iter().any(|e| e == "c") {
+            if extensions.iter().any(|e| e == "f") && xlen == 32 {
+                push(&mut extensions, "zcf");
+            }
+            if extensions.iter().any(|e| e == "d") {
+                push(&mut extensions, "zcd");
+            }
+        }
+
+        Ok(Self { xlen, extensions })
+    }
+
+    /// Instruction tables of all extensions, without duplicates.
+    ///
+    /// Extensions without instruction tables are skipped with a warning.
+    pub fn tables(&self) -> Vec<String> {
+        let mut tables: Vec<&str> = vec![];
+        for ext in &self.extensions {
+            let ext_slice = [ext.as_str()];
+            let names = ALIASES
+                .iter()
+                .find(|(alias, _)| alias == ext)
+                .map_or(&ext_slice[..], |(_, names)| names);
+            for name in names {
+                let Some((_, rv32, rv64)) = EXTENSIONS.iter().find(|(n, _, _)| n == name) else {
+                    warn!("disasm: no instruction tables for extension '{ext}' - skipping.");
+                    continue;
+                };
+                let ext_tables = if self.xlen == 32 { rv32 } else { rv64 };
+                if ext_tables.is_empty() {
+                    warn!(
+                        "disasm: no instruction tables for extension '{ext}' on rv{} - skipping.",
+                        self.xlen
+                    );
+                }
+                for table in ext_tables.iter() {
+                    if !tables.contains(table) {
+                        tables.push(table);
+                    }
+                }
+            }
+        }
+        tables.into_iter().map(str::to_string).collect()
+    }
+}
+
+/// Strip a version number (e.g. `zicsr2p0` or `zicsr2`) from an extension.
+fn strip_version(ext: &str) -> &str {
+    let is_digit = |c: char| c.is_ascii_digit();
+    let base = ext.trim_end_matches(is_digit);
+    if base.len() < ext.len() {
+        if let Some(major) = base.strip_suffix('p') {
+            let stripped = major.trim_end_matches(is_digit);
+            if stripped.len() < major.len() {
+                return stripped;
+            }
+        }
+    }
+    base
+}
+
+/// Length in bits of the RISC-V instruction starting with the given bits,
+/// following the base ISA length encoding.
+pub fn riscv_instr_len(bits: u128) -> usize {
+    if bits & 0b11 != 0b11 {
+        16
+    } else if bits & 0b11100 != 0b11100 {
+        32
+    } else if bits & 0b111111 == 0b011111 {
+        48
+    } else if bits & 0b1111111 == 0b0111111 {
+        64
+    } else {
+        // Longer encodings are reserved:
+        32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_march() {
+        let march = March::parse("rv32imac_zicsr_zifencei").unwrap();
+        assert_eq!(march.xlen, 32);
+        assert_eq!(
+            march.extensions,
+            vec!["i", "m", "a", "c", "zicsr", "zifencei"]
+        );
+
+        let march = March::parse("RV64GCzba_zbb").unwrap();
+        assert_eq!(
+            march.extensions,
+            vec!["i", "m", "a", "f", "d", "zicsr", "zifencei", "c", "zba", "zbb", "zcd"]
+        );
+
+        let march = March::parse("rv64i2p1_zicbop_zicsr2p0").unwrap();
+        assert_eq!(march.extensions, vec!["i", "zicbop", "zicsr"]);
+
+        assert!(March::parse("x86_64").is_err());
+        // Unknown extensions are skipped:
+        let march = March::parse("rv32i_zfoo_sstc").unwrap();
+        assert_eq!(march.tables(), March::parse("rv32i").unwrap().tables());
+    }
+
+    #[test]
+    fn test_aliases() {
+        let march =
+            March::parse("rv32i2p1_m2p0_a2p1_c2p0_zicsr2p0_zmmul1p0_zaamo1p0_zalrsc1p0_zca1p0")
+                .unwrap();
+        assert_eq!(
+            march.tables(),
+            March::parse("rv32imac_zicsr").unwrap().tables()
+        );
+
+        assert_eq!(
+            March::parse("rv64ib").unwrap().tables(),
+            March::parse("rv64i_zba_zbb_zbs").unwrap().tables()
+        );
+    }
+
+    #[test]
+    fn test_riscv_instr_len() {
+        assert_eq!(riscv_instr_len(0x4501), 16); // c.li a0, 0
+        assert_eq!(riscv_instr_len(0x00000513), 32); // li a0, 0
+        assert_eq!(riscv_instr_len(0x1f), 48);
+        assert_eq!(riscv_instr_len(0x3f), 64);
+    }
+}
@@ -1,21 +1,41 @@
 pub mod addr2line;
 pub mod disasm;
+pub mod enums;
+pub mod images;
+pub mod linkmap;
+pub mod march;
+pub mod plugin;
+pub mod regmap;
+pub mod riscv;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod symbols;
+pub mod variables;
 
-use anyhow::anyhow;
 use log::{debug, trace, warn};
-use synthetto::{decode_length_delimiter, Message, TracePacket};
+use synthetto::{
+    debug_annotation, debug_arg, trace_packet::Data, track_descriptor, track_event,
+    DebugAnnotation, RawPacket, TracePacket, TraceReader, TraceWriter, TrackDescriptor, TrackEvent,
+};
 use tempfile::NamedTempFile;
 
 use std::{
     cell::RefCell,
     collections::HashMap,
     fs::{self, File},
-    io::{BufReader, BufWriter, Read, Seek, Write},
+    io::{self, Read, Write},
     path::Path,
     rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Placeholder {
     pub kind: String,
     pub value: String,
@@ -30,14 +50,95 @@ pub enum ParsedElement {
 pub trait Annotater {
     fn accepts_keys(&self) -> Vec<String>;
     fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>>;
+
+    /// Resolve several placeholders at once, returning their replacements in
+    /// order. Annotators with a per-call overhead (such as external
+    /// processes) can override this to resolve all placeholders of a packet
+    /// together.
+    fn annotate_batch(
+        &mut self,
+        placeholders: &[Placeholder],
+    ) -> anyhow::Result<Vec<Option<String>>> {
+        placeholders
+            .iter()
+            .map(|placeholder| self.annotate(placeholder))
+            .collect()
+    }
+
+    /// Whether the replacements of this annotator can be cached across
+    /// packets, i.e. it always resolves a placeholder the same way.
+    /// Annotators that may keep state between calls (such as plugins and
+    /// scripts) return false, and are asked again for every packet.
+    fn cacheable(&self) -> bool {
+        true
+    }
 }
 
 type AnnotatorRef = Rc<RefCell<Box<dyn Annotater>>>;
 
+/// Replacements of placeholders, `None` for placeholders an annotator left
+/// unresolved.
+type Resolved = HashMap<Placeholder, Option<String>>;
+
+/// Name of the dictionary argument holding resolved placeholders, see
+/// [`AnnotateOptions::structured_args`].
+pub const ANNOTATIONS_ARG: &str = "annotations";
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnotateOptions {
+    /// Instead of rewriting the strings of events, keep the placeholders and
+    /// add their resolved values as entries (named `kind:value`) of a
+    /// dictionary argument named [`ANNOTATIONS_ARG`]. Strings of track
+    /// descriptors are always rewritten.
+    pub structured_args: bool,
+    /// Number of resolved placeholders to remember, so that annotators are
+    /// only asked once for each (kind, value). Once the cache is full, new
+    /// placeholders are no longer added, keeping the (typically hot) ones
+    /// seen first. Placeholders of annotators that aren't
+    /// [`Annotater::cacheable`] aren't cached. With 0, placeholders are only
+    /// deduplicated within a packet.
+    pub cache_size: usize,
+    /// Show the progress of annotation on stderr.
+    pub progress: bool,
+}
+
+/// Number of packets buffered between the stages of [`annotate`].
+const PIPELINE_DEPTH: usize = 1024;
+
+/// Interval between updates of the progress indicator.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Packet to write: Annotated, or unmodified as read.
+enum Output {
+    Packet(Box<TracePacket>),
+    Raw(RawPacket),
+}
+
+/// Reader counting the bytes read from it, for progress of (possibly
+/// compressed) files.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Annotate the placeholders of a trace, writing it to `output` or replacing
+/// `input`.
+///
+/// Reading and writing run on separate threads, but annotation itself is
+/// single-threaded, as annotators (e.g. plugins) are called in trace order.
 pub fn annotate(
     input: &Path,
     output: Option<&Path>,
     annotators_list: Vec<Box<dyn Annotater>>,
+    options: &AnnotateOptions,
 ) -> anyhow::Result<()> {
     // Build map of annotators:
     let mut annotators: HashMap<String, AnnotatorRef> = HashMap::new();
@@ -65,79 +166,53 @@ pub fn annotate(
         }
     };
 
+    // Read/decode, annotate and encode/write packets in a pipeline of
+    // threads. Annotators stay on this thread:
     {
-        let input_file = File::open(input)?;
-        let mut reader = BufReader::new(input_file);
-        let mut decode_buffer: Vec<u8> = Vec::with_capacity(128);
-
-        let output_file = File::create(&output_path)?;
-        let mut writer = BufWriter::new(output_file);
-        let mut encode_buffer: Vec<u8> = Vec::with_capacity(128);
-
-        loop {
-            trace!(
-                "Reading package @ {}",
-                reader.stream_position().unwrap_or(0)
-            );
-            // Read tag:
-            let mut tag: [u8; 1] = [0];
-            let tag_bytes_cnt = reader.read(&mut tag)?;
-            if tag_bytes_cnt == 0 {
-                break; // No more to read.
-            }
-            if tag[0] != 0x0A {
-                return Err(anyhow!(
-                    "invalid trace: tag is 0x{:x}, expected 0x0A",
-                    tag[0]
-                ));
-            }
-
-            trace!("  Tag OK.");
-
-            // Read length varint:
-            let mut len_num_bytes: usize = 0;
-            let mut len_field: [u8; 19] = [0; 19];
-            loop {
-                let mut byte: [u8; 1] = [0];
-                reader.read_exact(&mut byte)?;
-                len_field[len_num_bytes] = byte[0];
-                len_num_bytes += 1;
-                if (byte[0] & 0x80) == 0 {
-                    break;
-                }
-                if len_num_bytes == 19 {
-                    return Err(anyhow!("invalid trace: len is un-terminated varint."));
+        let file = File::open(input)?;
+        let size = file.metadata()?.len();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let mut writer = TraceWriter::create(&output_path)?;
+
+        let (decoded_tx, decoded_rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (output_tx, output_rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+
+        let reader_count = bytes_read.clone();
+        let reader_thread = thread::spawn(move || {
+            read_packets(
+                CountingReader {
+                    inner: file,
+                    count: reader_count,
+                },
+                decoded_tx,
+            )
+        });
+        let writer_thread = thread::spawn(move || -> anyhow::Result<()> {
+            for output in output_rx {
+                match output {
+                    Output::Packet(pkt) => writer.write_packet(&pkt)?,
+                    Output::Raw(raw) => writer.write_raw(&raw.data)?,
                 }
             }
-            let len: usize = decode_length_delimiter(&len_field[0..len_num_bytes])?;
-
-            if len == 0 {
-                return Err(anyhow!("invalid trace: zero-len package."));
-            }
-
-            trace!("  read {} bytes for len field. len: {}", len_num_bytes, len);
-
-            // Read field:
-            decode_buffer.resize(len, 0);
-            reader.read_exact(&mut decode_buffer)?;
-
-            // Decode TracePacket:
-            let packet = TracePacket::decode(&*decode_buffer)?;
-
-            // Apply annotations:
-            let transformed_packet = annotate_packet(packet, &annotators)?;
-
-            // Write-back to output:
-            writer.write_all(&tag)?;
-            if let Some(transformed_packet) = transformed_packet {
-                encode_buffer.clear();
-                transformed_packet.encode_length_delimited(&mut encode_buffer)?;
-                writer.write_all(&encode_buffer)?;
-            } else {
-                writer.write_all(&len_field[0..len_num_bytes])?;
-                writer.write_all(&decode_buffer)?;
-            }
-        }
+            writer.flush()?;
+            Ok(())
+        });
+
+        let progress = options
+            .progress
+            .then(|| Progress::new(size, bytes_read.clone()));
+        let result = annotate_packets(decoded_rx, output_tx, &annotators, options, progress);
+
+        // Errors of the reader are passed on through the pipeline, so that
+        // ones of the writer (which end annotation) take precedence:
+        let written = writer_thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("Failed to write trace - writer panicked"))?;
+        reader_thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("Failed to read trace - reader panicked"))?;
+        written?;
+        result?;
     }
 
     // If we used a temp file, move it to overwrite the input
@@ -152,42 +227,171 @@ pub fn annotate(
     Ok(())
 }
 
-fn annotate_packet(
-    mut pkt: TracePacket,
+/// Read and decode the packets of a trace, until the receiver hangs up.
+fn read_packets(
+    input: impl Read + 'static,
+    decoded: SyncSender<anyhow::Result<(RawPacket, TracePacket)>>,
+) {
+    let mut reader = match TraceReader::detect(input) {
+        Ok(reader) => reader,
+        Err(e) => {
+            let _ = decoded.send(Err(e.into()));
+            return;
+        }
+    };
+    while let Some(raw) = reader.next_raw() {
+        let packet = raw.map_err(anyhow::Error::from).and_then(|raw| {
+            trace!("Read packet @ {} ({} bytes)", raw.offset, raw.data.len());
+            let pkt = raw.decode()?;
+            Ok((raw, pkt))
+        });
+        let failed = packet.is_err();
+        if decoded.send(packet).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Annotate decoded packets, passing them on to be written in order.
+fn annotate_packets(
+    decoded: Receiver<anyhow::Result<(RawPacket, TracePacket)>>,
+    output: SyncSender<Output>,
     annotators: &HashMap<String, AnnotatorRef>,
-) -> anyhow::Result<Option<TracePacket>> {
-    let mut did_modify = false;
+    options: &AnnotateOptions,
+    mut progress: Option<Progress>,
+) -> anyhow::Result<()> {
+    let mut cache = Resolved::new();
+    for packet in decoded {
+        let (raw, pkt) = packet?;
+
+        let output_packet = match annotate_packet(pkt, annotators, options, &mut cache)? {
+            Some(pkt) => Output::Packet(Box::new(pkt)),
+            None => Output::Raw(raw),
+        };
+        // The writer only hangs up on errors, which are reported by it:
+        if output.send(output_packet).is_err() {
+            break;
+        }
 
-    if let Some(synthetto::trace_packet::Data::TrackEvent(evt)) = &mut pkt.data {
-        if let Some(synthetto::track_event::NameField::Name(name)) = &mut evt.name_field {
-            did_modify |= annotate_string(name, annotators)?;
+        if let Some(progress) = &mut progress {
+            progress.packet();
         }
     }
+    if let Some(progress) = &mut progress {
+        progress.finish();
+    }
+    Ok(())
+}
 
-    if let Some(synthetto::trace_packet::Data::TrackDescriptor(evt)) = &mut pkt.data {
-        if let Some(description) = &mut evt.description {
-            did_modify |= annotate_string(description, annotators)?;
+/// Progress indicator on stderr.
+struct Progress {
+    /// Size of the input file.
+    size: u64,
+    bytes_read: Arc<AtomicU64>,
+    packets: u64,
+    start: Instant,
+    last_update: Instant,
+}
+
+impl Progress {
+    fn new(size: u64, bytes_read: Arc<AtomicU64>) -> Self {
+        let now = Instant::now();
+        Self {
+            size,
+            bytes_read,
+            packets: 0,
+            start: now,
+            last_update: now,
         }
+    }
 
-        if let Some(synthetto::track_descriptor::StaticOrDynamicName::Name(name)) =
-            &mut evt.static_or_dynamic_name
-        {
-            did_modify |= annotate_string(name, annotators)?;
+    fn packet(&mut self) {
+        self.packets += 1;
+        if self.last_update.elapsed() >= PROGRESS_INTERVAL {
+            self.last_update = Instant::now();
+            self.print();
         }
+    }
 
-        if let Some(proc) = &mut evt.process {
-            if let Some(name) = &mut proc.process_name {
-                did_modify |= annotate_string(name, annotators)?;
-            }
-            for cmdline_part in &mut proc.cmdline {
-                did_modify |= annotate_string(cmdline_part, annotators)?;
+    fn finish(&mut self) {
+        self.print();
+        eprintln!();
+    }
+
+    fn print(&self) {
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        let percent = match self.size {
+            0 => 100.0,
+            size => bytes_read.min(size) as f64 * 100.0 / size as f64,
+        };
+        eprint!(
+            "\rAnnotating: {percent:5.1}% ({} packets, {:.1}s)",
+            self.packets,
+            self.start.elapsed().as_secs_f64()
+        );
+        let _ = io::stderr().flush();
+    }
+}
+
+fn annotate_packet(
+    mut pkt: TracePacket,
+    annotators: &HashMap<String, AnnotatorRef>,
+    options: &AnnotateOptions,
+    resolved: &mut Resolved,
+) -> anyhow::Result<Option<TracePacket>> {
+    let mut did_modify = false;
+
+    let new = resolve_packet(&mut pkt, annotators, resolved)?;
+
+    match &mut pkt.data {
+        Some(Data::TrackEvent(evt)) if options.structured_args => {
+            let mut entries: Vec<(String, String)> = vec![];
+            visit_event_strings(evt, &mut |s| {
+                for (placeholder, replacement) in resolve_string(s, annotators, resolved)?.1 {
+                    let key = format!("{}:{}", placeholder.kind, placeholder.value);
+                    if !entries.iter().any(|(k, _)| *k == key) {
+                        entries.push((key, replacement));
+                    }
+                }
+                Ok(())
+            })?;
+            if !entries.is_empty() {
+                let entries = entries
+                    .into_iter()
+                    .map(|(key, value)| debug_arg(key, debug_annotation::Value::StringValue(value)))
+                    .collect();
+                evt.debug_annotations.push(DebugAnnotation {
+                    name_field: Some(debug_annotation::NameField::Name(
+                        ANNOTATIONS_ARG.to_string(),
+                    )),
+                    dict_entries: entries,
+                    ..DebugAnnotation::default()
+                });
+                did_modify = true;
             }
         }
+        Some(Data::TrackEvent(evt)) => {
+            visit_event_strings(evt, &mut |s| {
+                did_modify |= annotate_string(s, annotators, resolved)?;
+                Ok(())
+            })?;
+        }
+        // Descriptors can't carry arguments, so their strings are always
+        // rewritten:
+        Some(Data::TrackDescriptor(desc)) => {
+            visit_descriptor_strings(desc, &mut |s| {
+                did_modify |= annotate_string(s, annotators, resolved)?;
+                Ok(())
+            })?;
+        }
+        None => (),
+    }
 
-        if let Some(thread) = &mut evt.thread {
-            if let Some(name) = &mut thread.thread_name {
-                did_modify |= annotate_string(name, annotators)?;
-            }
+    // Placeholders resolved for this packet only stay cached while there is
+    // room:
+    for (placeholder, cacheable) in new {
+        if !cacheable || resolved.len() > options.cache_size {
+            resolved.remove(&placeholder);
         }
     }
 
@@ -198,11 +402,160 @@ fn annotate_packet(
     }
 }
 
+/// Resolve all placeholders of a packet with one batch per annotator, calling
+/// annotators in the order of their first placeholder. Returns the newly
+/// resolved placeholders, and whether they can be cached beyond this packet.
+fn resolve_packet(
+    pkt: &mut TracePacket,
+    annotators: &HashMap<String, AnnotatorRef>,
+    resolved: &mut Resolved,
+) -> anyhow::Result<Vec<(Placeholder, bool)>> {
+    let mut batches: Vec<(String, Vec<Placeholder>)> = vec![];
+    let mut collect = |s: &mut String| {
+        for part in parse_string(s) {
+            if let ParsedElement::Placeholder(placeholder) = part {
+                if !annotators.contains_key(&placeholder.kind)
+                    || resolved.contains_key(&placeholder)
+                {
+                    continue;
+                }
+                let batch = match batches
+                    .iter()
+                    .position(|(kind, _)| *kind == placeholder.kind)
+                {
+                    Some(idx) => &mut batches[idx].1,
+                    None => {
+                        batches.push((placeholder.kind.clone(), vec![]));
+                        &mut batches.last_mut().unwrap().1
+                    }
+                };
+                if !batch.contains(&placeholder) {
+                    batch.push(placeholder);
+                }
+            }
+        }
+        Ok(())
+    };
+    match &mut pkt.data {
+        Some(Data::TrackEvent(evt)) => visit_event_strings(evt, &mut collect)?,
+        Some(Data::TrackDescriptor(desc)) => visit_descriptor_strings(desc, &mut collect)?,
+        None => (),
+    }
+
+    let mut new = vec![];
+    for (kind, placeholders) in batches {
+        let mut annotator = annotators[&kind].borrow_mut();
+        let replacements = annotator.annotate_batch(&placeholders)?;
+        let cacheable = annotator.cacheable();
+        new.extend(placeholders.iter().map(|p| (p.clone(), cacheable)));
+        resolved.extend(placeholders.into_iter().zip(replacements));
+    }
+    Ok(new)
+}
+
+type StringVisitor<'a> = dyn FnMut(&mut String) -> anyhow::Result<()> + 'a;
+
+/// Call `f` with every string of an event, including all (nested) debug
+/// annotation names and values.
+fn visit_event_strings(evt: &mut TrackEvent, f: &mut StringVisitor) -> anyhow::Result<()> {
+    for category in &mut evt.categories {
+        f(category)?;
+    }
+    if let Some(track_event::NameField::Name(name)) = &mut evt.name_field {
+        f(name)?;
+    }
+    if let Some(track_event::CorrelationIdField::CorrelationIdStr(id)) =
+        &mut evt.correlation_id_field
+    {
+        f(id)?;
+    }
+    for arg in &mut evt.debug_annotations {
+        visit_debug_annotation_strings(arg, f)?;
+    }
+    Ok(())
+}
+
+fn visit_debug_annotation_strings(
+    arg: &mut DebugAnnotation,
+    f: &mut StringVisitor,
+) -> anyhow::Result<()> {
+    if let Some(debug_annotation::NameField::Name(name)) = &mut arg.name_field {
+        f(name)?;
+    }
+    if let Some(debug_annotation::Value::StringValue(value)) = &mut arg.value {
+        f(value)?;
+    }
+    for entry in arg.dict_entries.iter_mut().chain(&mut arg.array_values) {
+        visit_debug_annotation_strings(entry, f)?;
+    }
+    Ok(())
+}
+
+/// Call `f` with every string of a track descriptor.
+fn visit_descriptor_strings(
+    desc: &mut TrackDescriptor,
+    f: &mut StringVisitor,
+) -> anyhow::Result<()> {
+    if let Some(track_descriptor::StaticOrDynamicName::Name(name)) =
+        &mut desc.static_or_dynamic_name
+    {
+        f(name)?;
+    }
+    if let Some(description) = &mut desc.description {
+        f(description)?;
+    }
+    if let Some(key) = &mut desc.sibling_merge_key {
+        f(key)?;
+    }
+    if let Some(proc) = &mut desc.process {
+        if let Some(name) = &mut proc.process_name {
+            f(name)?;
+        }
+        for cmdline_part in &mut proc.cmdline {
+            f(cmdline_part)?;
+        }
+    }
+    if let Some(thread) = &mut desc.thread {
+        if let Some(name) = &mut thread.thread_name {
+            f(name)?;
+        }
+    }
+    if let Some(counter) = &mut desc.counter {
+        for category in &mut counter.categories {
+            f(category)?;
+        }
+        if let Some(unit_name) = &mut counter.unit_name {
+            f(unit_name)?;
+        }
+        if let Some(key) = &mut counter.y_axis_share_key {
+            f(key)?;
+        }
+    }
+    Ok(())
+}
+
 fn annotate_string(
     s: &mut String,
     annotators: &HashMap<String, AnnotatorRef>,
+    resolved: &mut Resolved,
 ) -> anyhow::Result<bool> {
-    let mut did_modify = false;
+    let (annotated, replaced) = resolve_string(s, annotators, resolved)?;
+    if replaced.is_empty() {
+        return Ok(false);
+    }
+    *s = annotated;
+    Ok(true)
+}
+
+/// Resolve all placeholders in `s`, returning the annotated string and every
+/// placeholder that was resolved, with its replacement. Placeholders already
+/// in `resolved` are not passed to their annotator again.
+fn resolve_string(
+    s: &str,
+    annotators: &HashMap<String, AnnotatorRef>,
+    resolved: &mut Resolved,
+) -> anyhow::Result<(String, Vec<(Placeholder, String)>)> {
+    let mut replaced = vec![];
 
     let parts_input = parse_string(s);
     let mut parts_processed: Vec<String> = Vec::with_capacity(parts_input.len());
@@ -212,9 +565,17 @@ fn annotate_string(
             ParsedElement::Text(s) => parts_processed.push(s),
             ParsedElement::Placeholder(placeholder) => {
                 if let Some(annotator) = annotators.get(&placeholder.kind) {
-                    if let Some(replacement) = annotator.borrow_mut().annotate(&placeholder)? {
-                        did_modify = true;
-                        parts_processed.push(replacement);
+                    let replacement = match resolved.get(&placeholder) {
+                        Some(replacement) => replacement.clone(),
+                        None => {
+                            let replacement = annotator.borrow_mut().annotate(&placeholder)?;
+                            resolved.insert(placeholder.clone(), replacement.clone());
+                            replacement
+                        }
+                    };
+                    if let Some(replacement) = replacement {
+                        parts_processed.push(replacement.clone());
+                        replaced.push((placeholder, replacement));
                     } else {
                         parts_processed.push(placeholder.value);
                     }
@@ -229,11 +590,7 @@ fn annotate_string(
         }
     }
 
-    if did_modify {
-        *s = parts_processed.join("");
-    }
-
-    Ok(did_modify)
+    Ok((parts_processed.join(""), replaced))
 }
 
 pub fn parse_string(input: &str) -> Vec<ParsedElement> {
@@ -374,7 +731,7 @@ mod tests {
         annotators.insert("cap".to_string(), annotator);
 
         let mut test_string = "Hello, $cap:world !".to_string();
-        let result = annotate_string(&mut test_string, &annotators).unwrap();
+        let result = annotate_string(&mut test_string, &annotators, &mut Resolved::new()).unwrap();
 
         assert!(result);
         assert_eq!(test_string, "Hello, WORLD !");
@@ -385,7 +742,7 @@ mod tests {
         let annotators: HashMap<String, AnnotatorRef> = HashMap::new();
 
         let mut test_string = "Hello $unknown:world".to_string();
-        let result = annotate_string(&mut test_string, &annotators).unwrap();
+        let result = annotate_string(&mut test_string, &annotators, &mut Resolved::new()).unwrap();
 
         assert!(!result);
         assert_eq!(test_string, "Hello $unknown:world");
@@ -400,9 +757,172 @@ mod tests {
         annotators.insert("cap".to_string(), annotator);
 
         let mut test_string = "Process $cap:main with $other:value and $cap:thread".to_string();
-        let result = annotate_string(&mut test_string, &annotators).unwrap();
+        let result = annotate_string(&mut test_string, &annotators, &mut Resolved::new()).unwrap();
 
         assert!(result);
         assert_eq!(test_string, "Process MAIN with $other:value and THREAD");
     }
+
+    #[test]
+    fn test_annotate_packet_nested_args() {
+        let mut annotators: HashMap<String, AnnotatorRef> = HashMap::new();
+        let annotator = Rc::new(RefCell::new(
+            Box::new(CapitalizeAnnotator) as Box<dyn Annotater>
+        ));
+        annotators.insert("cap".to_string(), annotator);
+
+        let nested = DebugAnnotation {
+            name_field: Some(debug_annotation::NameField::Name("dict".into())),
+            dict_entries: vec![debug_arg(
+                "insn".into(),
+                debug_annotation::Value::StringValue("$cap:add".into()),
+            )],
+            ..DebugAnnotation::default()
+        };
+        let pkt = TracePacket {
+            data: Some(Data::TrackEvent(TrackEvent {
+                categories: vec!["$cap:cat".into()],
+                debug_annotations: vec![nested],
+                ..TrackEvent::default()
+            })),
+            ..TracePacket::default()
+        };
+
+        let options = AnnotateOptions::default();
+        let annotated = annotate_packet(pkt.clone(), &annotators, &options, &mut Resolved::new())
+            .unwrap()
+            .unwrap();
+        let Some(Data::TrackEvent(evt)) = annotated.data else {
+            panic!("expected track event");
+        };
+        assert_eq!(evt.categories, vec!["CAT"]);
+        assert_eq!(
+            evt.debug_annotations[0].dict_entries[0].value,
+            Some(debug_annotation::Value::StringValue("ADD".into()))
+        );
+
+        let options = AnnotateOptions {
+            structured_args: true,
+            ..AnnotateOptions::default()
+        };
+        let annotated = annotate_packet(pkt, &annotators, &options, &mut Resolved::new())
+            .unwrap()
+            .unwrap();
+        let Some(Data::TrackEvent(evt)) = annotated.data else {
+            panic!("expected track event");
+        };
+        assert_eq!(evt.categories, vec!["$cap:cat"]);
+        let resolved = &evt.debug_annotations[1];
+        assert_eq!(
+            resolved.name_field,
+            Some(debug_annotation::NameField::Name(ANNOTATIONS_ARG.into()))
+        );
+        assert_eq!(resolved.dict_entries.len(), 2);
+    }
+
+    /// Records the placeholders it is asked to resolve.
+    struct RecordingAnnotator {
+        calls: Rc<RefCell<Vec<String>>>,
+        cacheable: bool,
+    }
+
+    impl Annotater for RecordingAnnotator {
+        fn accepts_keys(&self) -> Vec<String> {
+            vec!["cap".to_string(), "up".to_string()]
+        }
+
+        fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+            let call = format!("{}:{}", placeholder.kind, placeholder.value);
+            self.calls.borrow_mut().push(call);
+            Ok(Some(placeholder.value.to_uppercase()))
+        }
+
+        fn cacheable(&self) -> bool {
+            self.cacheable
+        }
+    }
+
+    #[test]
+    fn test_annotate_trace_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (
+            dir.path().join("in.pftrace"),
+            dir.path().join("out.pftrace"),
+        );
+
+        let names = ["$cap:a", "plain", "$cap:b", "$cap:a $cap:b"];
+        let mut writer = TraceWriter::create(&input).unwrap();
+        for name in names {
+            writer
+                .write_packet(&TracePacket {
+                    data: Some(Data::TrackEvent(TrackEvent {
+                        name_field: Some(track_event::NameField::Name(name.into())),
+                        ..TrackEvent::default()
+                    })),
+                    ..TracePacket::default()
+                })
+                .unwrap();
+        }
+        writer.flush().unwrap();
+
+        let calls = |cacheable, cache_size| {
+            let calls = Rc::new(RefCell::new(vec![]));
+            let annotator = RecordingAnnotator {
+                calls: calls.clone(),
+                cacheable,
+            };
+            let options = AnnotateOptions {
+                cache_size,
+                ..AnnotateOptions::default()
+            };
+            annotate(&input, Some(&output), vec![Box::new(annotator)], &options).unwrap();
+            let calls = calls.borrow().len();
+            calls
+        };
+        // Uncacheable placeholders are only deduplicated within a packet:
+        assert_eq!(calls(false, 16), 4);
+        assert_eq!(calls(true, 16), 2);
+        // Once full, the cache keeps the placeholders seen first:
+        assert_eq!(calls(true, 1), 3);
+
+        let annotated: Vec<_> = TraceReader::open(&output)
+            .unwrap()
+            .map(|packet| match packet.unwrap().1.data {
+                Some(Data::TrackEvent(TrackEvent {
+                    name_field: Some(track_event::NameField::Name(name)),
+                    ..
+                })) => name,
+                _ => panic!("expected named track event"),
+            })
+            .collect();
+        assert_eq!(annotated, vec!["A", "plain", "B", "A B"]);
+    }
+
+    #[test]
+    fn test_resolve_packet_order() {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let annotator: Box<dyn Annotater> = Box::new(RecordingAnnotator {
+            calls: calls.clone(),
+            cacheable: true,
+        });
+        let annotator = Rc::new(RefCell::new(annotator));
+        let annotators: HashMap<String, AnnotatorRef> = ["up", "cap"]
+            .into_iter()
+            .map(|kind| (kind.to_string(), annotator.clone()))
+            .collect();
+
+        let mut pkt = TracePacket {
+            data: Some(Data::TrackEvent(TrackEvent {
+                name_field: Some(track_event::NameField::Name(
+                    "$up:a $cap:b $up:c $cap:b".into(),
+                )),
+                ..TrackEvent::default()
+            })),
+            ..TracePacket::default()
+        };
+        let new = resolve_packet(&mut pkt, &annotators, &mut Resolved::new()).unwrap();
+        // Annotators are called in the order of their first placeholder:
+        assert_eq!(*calls.borrow(), vec!["up:a", "up:c", "cap:b"]);
+        assert_eq!(new.len(), 3);
+    }
 }
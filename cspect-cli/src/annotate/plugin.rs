@@ -0,0 +1,339 @@
+//! Annotators implemented by external processes ("plugins"), for decoders
+//! that cannot be built into cspect.
+//!
+//! A plugin is started once and kept running. For every placeholder of its
+//! kind, it is sent one line of JSON on stdin, numbered by `id`:
+//!
+//! ```json
+//! {"id":7,"kind":"pkt","value":"0x1234"}
+//! ```
+//!
+//! and has to answer with one line on stdout, in the order of requests:
+//!
+//! ```json
+//! {"id":7,"text":"READ id=4"}
+//! ```
+//!
+//! where a missing or `null` text leaves the placeholder unresolved, and
+//! `{"error":"..."}` reports a failure (the placeholder is kept as well). All
+//! placeholders of a packet are sent at once, so plugins should not wait for
+//! more requests before answering. The plugin's stderr is passed through.
+//!
+//! If a plugin doesn't answer within the timeout, the placeholder is left
+//! unresolved. Responses echoing the `id` of their request are matched to it,
+//! discarding late ones. Plugins that don't echo it can't be resynchronized,
+//! and are stopped on the first timeout, leaving all further placeholders of
+//! their kind unresolved.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+use super::{Annotater, Placeholder};
+
+/// Plugin, given as `kind=command`. The command is run by the shell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginSpec {
+    pub kind: String,
+    pub command: String,
+}
+
+impl FromStr for PluginSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, command)) = s.split_once('=') else {
+            return Err(format!("invalid plugin '{s}' - expected kind=command"));
+        };
+        if kind.is_empty() || kind.contains(|c: char| c.is_whitespace() || matches!(c, ':' | '$')) {
+            return Err(format!("invalid plugin kind '{kind}'"));
+        }
+        if command.trim().is_empty() {
+            return Err(format!("invalid plugin '{s}' - no command given"));
+        }
+        Ok(PluginSpec {
+            kind: kind.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+/// Resolves placeholders of one kind using a plugin process.
+pub struct PluginAnnotator {
+    kind: String,
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines of the plugin's stdout, read by a separate thread so that
+    /// responses can be awaited with a timeout.
+    responses: Receiver<std::io::Result<String>>,
+    timeout: Duration,
+    /// ID of the next request.
+    next_id: u64,
+    /// Whether the plugin echoes request IDs in its responses.
+    echoes_ids: bool,
+    /// Set once the plugin has been stopped after a timeout.
+    stopped: bool,
+}
+
+impl PluginAnnotator {
+    /// Start the plugin, waiting at most `timeout` for each response.
+    pub fn spawn(spec: &PluginSpec, timeout: Duration) -> anyhow::Result<Self> {
+        debug!("starting plugin for ${}: {}", spec.kind, spec.command);
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        };
+        let mut child = command
+            .arg(&spec.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start plugin '{}' - {e}", spec.command))?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            kind: spec.kind.clone(),
+            child,
+            stdin,
+            responses,
+            timeout,
+            next_id: 0,
+            echoes_ids: false,
+            stopped: false,
+        })
+    }
+
+    /// Receive the response to request `id`, or `None` if it timed out.
+    fn receive(&mut self, id: u64) -> anyhow::Result<Option<String>> {
+        let (line, response) = loop {
+            let line = match self.responses.recv_timeout(self.timeout) {
+                Ok(line) => {
+                    line.map_err(|e| anyhow!("Failed to read from plugin ${} - {e}", self.kind))?
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timed_out(id);
+                    return Ok(None);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Plugin ${} exited unexpectedly", self.kind))
+                }
+            };
+
+            let response: Value = serde_json::from_str(&line).map_err(|e| {
+                anyhow!("Invalid response of plugin ${}: '{line}' - {e}", self.kind)
+            })?;
+            match response.get("id").map(Value::as_u64) {
+                None => break (line, response),
+                Some(Some(response_id)) if response_id == id => {
+                    self.echoes_ids = true;
+                    break (line, response);
+                }
+                Some(Some(response_id)) if response_id < id => {
+                    debug!(
+                        "plugin ${}: discarding late response to request {response_id}.",
+                        self.kind
+                    );
+                }
+                Some(_) => {
+                    return Err(anyhow!(
+                        "Invalid response of plugin ${}: '{line}' - expected id {id}",
+                        self.kind
+                    ))
+                }
+            }
+        };
+
+        if let Some(error) = response.get("error") {
+            warn!("plugin ${}: {error} - skipping.", self.kind);
+            return Ok(None);
+        }
+        match response.get("text") {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(text)) => Ok(Some(text.clone())),
+            Some(_) => Err(anyhow!(
+                "Invalid response of plugin ${}: '{line}' - text is not a string",
+                self.kind
+            )),
+        }
+    }
+
+    /// Handle a timeout of request `id`, stopping the plugin if its responses
+    /// can't be matched to requests any more.
+    fn timed_out(&mut self, id: u64) {
+        if self.echoes_ids {
+            warn!(
+                "plugin ${}: no response to request {id} within {:?} - skipping.",
+                self.kind, self.timeout
+            );
+        } else {
+            warn!(
+                "plugin ${}: no response within {:?} - stopping plugin, skipping all further placeholders.",
+                self.kind, self.timeout
+            );
+            let _ = self.child.kill();
+            self.stopped = true;
+        }
+    }
+}
+
+impl Annotater for PluginAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        vec![self.kind.clone()]
+    }
+
+    fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .annotate_batch(std::slice::from_ref(placeholder))?
+            .remove(0))
+    }
+
+    fn annotate_batch(
+        &mut self,
+        placeholders: &[Placeholder],
+    ) -> anyhow::Result<Vec<Option<String>>> {
+        if self.stopped {
+            return Ok(vec![None; placeholders.len()]);
+        }
+        let first_id = self.next_id;
+        self.next_id += placeholders.len() as u64;
+
+        let mut requests = String::new();
+        for (id, placeholder) in (first_id..).zip(placeholders) {
+            let request = json!({"id": id, "kind": placeholder.kind, "value": placeholder.value});
+            requests.push_str(&request.to_string());
+            requests.push('\n');
+        }
+        self.stdin
+            .write_all(requests.as_bytes())
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| anyhow!("Failed to write to plugin ${} - {e}", self.kind))?;
+
+        (first_id..self.next_id)
+            .map(|id| match self.stopped {
+                true => Ok(None),
+                false => self.receive(id),
+            })
+            .collect()
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for PluginAnnotator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_spec() {
+        assert_eq!(
+            "pkt=./decode --verbose".parse(),
+            Ok(PluginSpec {
+                kind: "pkt".into(),
+                command: "./decode --verbose".into(),
+            })
+        );
+        assert!("pkt".parse::<PluginSpec>().is_err());
+        assert!("a:b=decode".parse::<PluginSpec>().is_err());
+        assert!("pkt= ".parse::<PluginSpec>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plugin() {
+        // Answers with the length of each request line, and nothing for
+        // "skip" values:
+        let spec = PluginSpec {
+            kind: "len".into(),
+            command: r#"while read -r line; do
+                case "$line" in
+                    *skip*) echo '{"text":null}' ;;
+                    *) echo "{\"text\":\"${#line}\"}" ;;
+                esac
+            done"#
+                .into(),
+        };
+        let mut plugin = PluginAnnotator::spawn(&spec, Duration::from_secs(10)).unwrap();
+        let placeholder = |value: &str| Placeholder {
+            kind: "len".into(),
+            value: value.into(),
+        };
+        assert_eq!(
+            plugin
+                .annotate_batch(&[placeholder("a"), placeholder("skip"), placeholder("abc")])
+                .unwrap(),
+            vec![Some("33".into()), None, Some("35".into())]
+        );
+
+        // Plugins not echoing request IDs are stopped on a timeout:
+        let spec = PluginSpec {
+            kind: "slow".into(),
+            command: "sleep 10".into(),
+        };
+        let mut plugin = PluginAnnotator::spawn(&spec, Duration::from_millis(100)).unwrap();
+        assert_eq!(plugin.annotate(&placeholder("a")).unwrap(), None);
+        assert!(plugin.stopped);
+        assert_eq!(
+            plugin
+                .annotate_batch(&[placeholder("b"), placeholder("c")])
+                .unwrap(),
+            vec![None, None]
+        );
+
+        // Late responses of plugins echoing request IDs are discarded:
+        let spec = PluginSpec {
+            kind: "id".into(),
+            command: r#"while read -r line; do
+                id=${line#*\"id\":}; id=${id%%,*}
+                case "$line" in *slow*) sleep 1 ;; esac
+                echo "{\"id\":$id,\"text\":\"ok$id\"}"
+            done"#
+                .into(),
+        };
+        let mut plugin = PluginAnnotator::spawn(&spec, Duration::from_millis(700)).unwrap();
+        assert_eq!(
+            plugin.annotate(&placeholder("a")).unwrap().as_deref(),
+            Some("ok0")
+        );
+        assert_eq!(
+            plugin
+                .annotate_batch(&[placeholder("slow"), placeholder("b")])
+                .unwrap(),
+            vec![None, Some("ok2".into())]
+        );
+        assert!(!plugin.stopped);
+    }
+}
@@ -0,0 +1,477 @@
+//! Decoding of bus addresses to register names (`$reg:<addr>`) and of
+//! register values to fields (`$regval:<addr>=<value>`), from register
+//! descriptions.
+//!
+//! Descriptions are TOML or JSON files with a list of blocks:
+//!
+//! ```toml
+//! [[blocks]]
+//! name = "UART0"
+//! base = 0x4000_1000
+//!
+//! [[blocks.registers]]
+//! name = "CTRL"
+//! offset = 0x8
+//! size = 4 # bytes, default 4
+//! fields = [
+//!     { name = "EN", bits = "0" },
+//!     { name = "BAUD", bits = "3:1", values = { 0 = "9600", 1 = "115200" } },
+//! ]
+//! ```
+//!
+//! Fields are given as `bits = "msb:lsb"` (or a single bit), or with `lsb`
+//! and `msb` or `width`. Numbers may also be strings, in decimal or hex.
+//!
+//! JSON files exported from SystemRDL (a tree of `addrmap`, `regfile`, `reg`
+//! and `field` nodes with `inst_name`, `addr_offset`, `children` and
+//! `lsb`/`msb`, as produced by the systemrdl-compiler JSON exporter example)
+//! are supported as well.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::anyhow;
+use log::warn;
+use serde_json::Value;
+
+use crate::utils;
+
+use super::{Annotater, Placeholder};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    lsb: u32,
+    width: u32,
+    /// Names of field values.
+    values: HashMap<u64, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Register {
+    /// Hierarchical name, such as `UART0.CTRL`.
+    path: String,
+    address: u64,
+    /// Size in bytes.
+    size: u64,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Block {
+    path: String,
+    address: u64,
+    /// Size in bytes, 0 to span all its registers.
+    size: u64,
+}
+
+/// Registers and blocks, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct RegisterMap {
+    registers: Vec<Register>,
+    blocks: Vec<Block>,
+}
+
+impl RegisterMap {
+    /// Add the registers of a TOML or JSON description.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read register map {} - {e}", path.display()))?;
+        let root: Value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => text
+                .parse::<toml::Table>()
+                .map_err(anyhow::Error::from)
+                .and_then(|table| serde_json::to_value(table).map_err(anyhow::Error::from)),
+            _ => serde_json::from_str(&text).map_err(anyhow::Error::from),
+        }
+        .map_err(|e| anyhow!("Failed to parse register map {} - {e}", path.display()))?;
+
+        self.add(&root)
+            .map_err(|e| anyhow!("Invalid register map {} - {e}", path.display()))
+    }
+
+    fn add(&mut self, root: &Value) -> anyhow::Result<()> {
+        if root.get("type").is_some() {
+            self.add_rdl_node(root, 0, None)?;
+        } else {
+            let blocks = root
+                .get("blocks")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("no blocks given"))?;
+            for block in blocks {
+                self.add_block(block)?;
+            }
+        }
+        self.finish();
+        Ok(())
+    }
+
+    fn add_block(&mut self, block: &Value) -> anyhow::Result<()> {
+        let name = string(block, "name")?;
+        let base = number(block, "base")?;
+        let size = optional_number(block, "size")?.unwrap_or(0);
+        let registers = block
+            .get("registers")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for reg in registers {
+            let reg_name = string(reg, "name")?;
+            let fields = reg
+                .get("fields")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .map(field)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map_err(|e| anyhow!("{name}.{reg_name}: {e}"))?;
+            self.registers.push(Register {
+                path: format!("{name}.{reg_name}"),
+                address: base.wrapping_add(number(reg, "offset")?),
+                size: optional_number(reg, "size")?.unwrap_or(4),
+                fields,
+            });
+        }
+        self.blocks.push(Block {
+            path: name.to_string(),
+            address: base,
+            size,
+        });
+        Ok(())
+    }
+
+    /// Add a node of a SystemRDL JSON export. The name of the root address
+    /// map is omitted from paths.
+    fn add_rdl_node(
+        &mut self,
+        node: &Value,
+        base: u64,
+        prefix: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let name = string(node, "inst_name")?;
+        let address = base.wrapping_add(optional_number(node, "addr_offset")?.unwrap_or(0));
+        let path = match prefix {
+            Some("") => name.to_string(),
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => String::new(),
+        };
+        let children = node
+            .get("children")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        match string(node, "type")? {
+            "reg" => {
+                let mut fields = vec![];
+                for child in children {
+                    if child.get("type").and_then(Value::as_str) != Some("field") {
+                        continue;
+                    }
+                    let lsb = number(child, "lsb")?;
+                    let msb = number(child, "msb")?;
+                    fields.push(Field {
+                        name: string(child, "inst_name")?.to_string(),
+                        lsb: lsb as u32,
+                        width: (msb.saturating_sub(lsb) + 1) as u32,
+                        values: HashMap::new(),
+                    });
+                }
+                let size = match optional_number(node, "size")? {
+                    Some(size) => size,
+                    None => optional_number(node, "regwidth")?.map_or(4, |bits| bits.div_ceil(8)),
+                };
+                self.registers.push(Register {
+                    path,
+                    address,
+                    size,
+                    fields,
+                });
+            }
+            _ => {
+                for child in children {
+                    self.add_rdl_node(child, address, Some(&path))?;
+                }
+                if prefix.is_some() {
+                    self.blocks.push(Block {
+                        path,
+                        address,
+                        size: 0,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.registers.sort_by_key(|reg| reg.address);
+        self.blocks.sort_by_key(|block| block.address);
+        // Blocks without a size span their registers:
+        for block in self.blocks.iter_mut().filter(|block| block.size == 0) {
+            let prefix = format!("{}.", block.path);
+            block.size = self
+                .registers
+                .iter()
+                .filter(|reg| reg.path.starts_with(&prefix))
+                .map(|reg| {
+                    reg.address
+                        .saturating_add(reg.size)
+                        .saturating_sub(block.address)
+                })
+                .max()
+                .unwrap_or(0);
+        }
+    }
+
+    fn register(&self, addr: u64) -> Option<&Register> {
+        let end = self.registers.partition_point(|reg| reg.address <= addr);
+        self.registers[..end]
+            .iter()
+            .rev()
+            .find(|reg| addr - reg.address < reg.size)
+    }
+
+    /// `addr` as `BLOCK.REG`, `BLOCK.REG+offset` or `BLOCK+offset`. Of nested
+    /// blocks, the innermost one is used.
+    pub fn name(&self, addr: u64) -> Option<String> {
+        let (path, offset) = match self.register(addr) {
+            Some(reg) => (&reg.path, addr - reg.address),
+            None => {
+                let end = self.blocks.partition_point(|block| block.address <= addr);
+                let block = self.blocks[..end]
+                    .iter()
+                    .filter(|block| addr - block.address < block.size)
+                    .min_by_key(|block| block.size)?;
+                (&block.path, addr - block.address)
+            }
+        };
+        Some(match offset {
+            0 => path.clone(),
+            offset => format!("{path}+0x{offset:x}"),
+        })
+    }
+
+    /// Value of the register at `addr` decoded to fields:
+    /// `BLOCK.REG{FIELD=1,MODE=TX}`.
+    pub fn decode(&self, addr: u64, value: u64) -> Option<String> {
+        let reg = self.register(addr)?;
+        if reg.fields.is_empty() {
+            return Some(format!("{}={}", reg.path, format_value(value)));
+        }
+        let fields: Vec<String> = reg
+            .fields
+            .iter()
+            .map(|field| {
+                let mask = if field.width >= 64 {
+                    u64::MAX
+                } else {
+                    (1 << field.width) - 1
+                };
+                let field_value = value.checked_shr(field.lsb).unwrap_or(0) & mask;
+                match field.values.get(&field_value) {
+                    Some(name) => format!("{}={name}", field.name),
+                    None => format!("{}={}", field.name, format_value(field_value)),
+                }
+            })
+            .collect();
+        Some(format!("{}{{{}}}", reg.path, fields.join(",")))
+    }
+}
+
+fn format_value(value: u64) -> String {
+    if value < 10 {
+        value.to_string()
+    } else {
+        format!("0x{value:x}")
+    }
+}
+
+/// Parse a number, allowing `_` separators (`0x4000_1008`).
+fn parse_number(s: &str) -> anyhow::Result<u64> {
+    utils::string_to_u64(&s.replace('_', "")).map_err(|e| anyhow!("invalid number '{s}' - {e}"))
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> anyhow::Result<&'a str> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("missing string '{key}'"))
+}
+
+fn optional_number(value: &Value, key: &str) -> anyhow::Result<Option<u64>> {
+    match value.get(key) {
+        None => Ok(None),
+        Some(Value::Number(n)) => n
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| anyhow!("invalid number '{key}' = {n}")),
+        Some(Value::String(s)) => parse_number(s).map(Some),
+        Some(other) => Err(anyhow!("invalid number '{key}' = {other}")),
+    }
+}
+
+fn number(value: &Value, key: &str) -> anyhow::Result<u64> {
+    optional_number(value, key)?.ok_or_else(|| anyhow!("missing number '{key}'"))
+}
+
+fn field(value: &Value) -> anyhow::Result<Field> {
+    let name = string(value, "name")?;
+    let (lsb, width) = match value.get("bits") {
+        Some(Value::String(bits)) => match bits.split_once(':') {
+            Some((msb, lsb)) => {
+                let (msb, lsb) = (parse_number(msb)?, parse_number(lsb)?);
+                if msb < lsb {
+                    return Err(anyhow!("field {name}: invalid bits '{bits}'"));
+                }
+                (lsb, msb - lsb + 1)
+            }
+            None => (parse_number(bits)?, 1),
+        },
+        Some(_) => (number(value, "bits")?, 1),
+        None => {
+            let lsb = number(value, "lsb")?;
+            let width = match optional_number(value, "width")? {
+                Some(width) => width,
+                None => number(value, "msb")?.saturating_sub(lsb) + 1,
+            };
+            (lsb, width)
+        }
+    };
+    if lsb >= 64 || width == 0 || width > 64 {
+        return Err(anyhow!("field {name}: unsupported bits"));
+    }
+
+    let mut values = HashMap::new();
+    if let Some(Value::Object(names)) = value.get("values") {
+        for (field_value, field_name) in names {
+            let Some(field_name) = field_name.as_str() else {
+                return Err(anyhow!("field {name}: invalid name of value {field_value}"));
+            };
+            values.insert(parse_number(field_value)?, field_name.to_string());
+        }
+    }
+
+    Ok(Field {
+        name: name.to_string(),
+        lsb: lsb as u32,
+        width: width as u32,
+        values,
+    })
+}
+
+/// Resolves `$reg:<addr>` and `$regval:<addr>=<value>` placeholders.
+pub struct RegisterAnnotator {
+    map: RegisterMap,
+}
+
+impl RegisterAnnotator {
+    pub fn new(map: RegisterMap) -> Self {
+        Self { map }
+    }
+}
+
+impl Annotater for RegisterAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        vec!["reg".to_string(), "regval".to_string()]
+    }
+
+    fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+        let (addr, value) = match placeholder.kind.as_str() {
+            "regval" => match placeholder.value.split_once('=') {
+                Some((addr, value)) => (addr, Some(value)),
+                None => {
+                    warn!(
+                        "regval: expected addr=value, not '{}' - skipping.",
+                        placeholder.value
+                    );
+                    return Ok(None);
+                }
+            },
+            _ => (placeholder.value.as_str(), None),
+        };
+        let (Ok(addr), Ok(value)) = (parse_number(addr), value.map(parse_number).transpose())
+        else {
+            warn!(
+                "{}: invalid number in '{}' - skipping.",
+                placeholder.kind, placeholder.value
+            );
+            return Ok(None);
+        };
+
+        Ok(Some(match value {
+            Some(value) => self
+                .map
+                .decode(addr, value)
+                .unwrap_or_else(|| format!("0x{addr:08x}=0x{value:x}")),
+            None => {
+                let name = self.map.name(addr).unwrap_or_else(|| "?".to_string());
+                format!("{name} (0x{addr:08x})")
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_map() {
+        let simple = r#"
+            [[blocks]]
+            name = "UART0"
+            base = 0x4000_1000
+            size = 0x100
+
+            [[blocks.registers]]
+            name = "CTRL"
+            offset = 0x8
+            fields = [
+                { name = "EN", bits = "0" },
+                { name = "BAUD", bits = "3:1" },
+                { name = "MODE", lsb = 4, width = 2, values = { "1" = "TX", "2" = "RX" } },
+            ]
+        "#;
+        let rdl = r#"{
+            "type": "addrmap", "inst_name": "soc", "addr_offset": 0, "children": [
+                {"type": "addrmap", "inst_name": "GPIO", "addr_offset": 1073750016, "children": [
+                    {"type": "reg", "inst_name": "DIR", "addr_offset": 4, "children": [
+                        {"type": "field", "inst_name": "PIN0", "lsb": 0, "msb": 0}
+                    ]}
+                ]}
+            ]
+        }"#;
+        let mut map = RegisterMap::default();
+        map.add(&serde_json::to_value(simple.parse::<toml::Table>().unwrap()).unwrap())
+            .unwrap();
+        map.add(&serde_json::from_str(rdl).unwrap()).unwrap();
+
+        assert_eq!(map.name(0x4000_1008).as_deref(), Some("UART0.CTRL"));
+        assert_eq!(map.name(0x4000_100A).as_deref(), Some("UART0.CTRL+0x2"));
+        assert_eq!(map.name(0x4000_1030).as_deref(), Some("UART0+0x30"));
+        assert_eq!(map.name(0x4000_2004).as_deref(), Some("GPIO.DIR"));
+        assert_eq!(map.name(0x5000_0000), None);
+
+        assert_eq!(
+            map.decode(0x4000_1008, 0x17).as_deref(),
+            Some("UART0.CTRL{EN=1,BAUD=3,MODE=TX}")
+        );
+        assert_eq!(
+            map.decode(0x4000_2004, 0x0).as_deref(),
+            Some("GPIO.DIR{PIN0=0}")
+        );
+
+        // Registers at the end of the address space:
+        let top = r#"{
+            "type": "addrmap", "inst_name": "soc", "addr_offset": 0, "children": [
+                {"type": "addrmap", "inst_name": "TOP", "addr_offset": 18446744073709551600, "children": [
+                    {"type": "reg", "inst_name": "LAST", "addr_offset": 12, "children": []}
+                ]}
+            ]
+        }"#;
+        let mut map = RegisterMap::default();
+        map.add(&serde_json::from_str(top).unwrap()).unwrap();
+        assert_eq!(map.name(0xffff_ffff_ffff_fffc).as_deref(), Some("TOP.LAST"));
+    }
+}
@@ -0,0 +1,346 @@
+//! RISC-V specific formatting of disassembled instructions: Absolute
+//! branch/jump targets, register names and pseudo-instructions.
+
+use std::{str::FromStr, sync::Arc};
+
+use crate::utils;
+
+use super::symbols::SymbolTable;
+
+#[rustfmt::skip]
+const INT_ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+#[rustfmt::skip]
+const FLOAT_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Register naming of disassembled instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegNames {
+    /// ABI names (`zero`, `sp`, `a0`, `fa0`, ..).
+    Abi,
+    /// Architectural names (`x0`, `x2`, `x10`, `f10`, ..).
+    Numeric,
+}
+
+impl FromStr for RegNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abi" => Ok(RegNames::Abi),
+            "numeric" => Ok(RegNames::Numeric),
+            _ => Err(format!(
+                "invalid register names '{s}' - expected abi or numeric"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Register naming, or `None` to keep the names of the decoder.
+    pub reg_names: Option<RegNames>,
+    /// Show pseudo-instructions (`nop`, `ret`, `j`, `li`, `mv`) instead of
+    /// the instructions implementing them.
+    pub pseudo: bool,
+    /// Symbols to show absolute branch/jump targets as `symbol+offset`.
+    pub symbols: Option<Arc<SymbolTable>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reg {
+    Int(usize),
+    Float(usize),
+}
+
+impl Reg {
+    fn parse(s: &str) -> Option<Self> {
+        if s == "fp" {
+            return Some(Reg::Int(8));
+        }
+        let numeric = |prefix: &str| {
+            let num = s.strip_prefix(prefix)?;
+            // No leading zeros/signs:
+            if num.is_empty() || (num.len() > 1 && num.starts_with('0')) {
+                return None;
+            }
+            num.parse().ok().filter(|n: &usize| *n < 32)
+        };
+        if let Some(n) = numeric("x") {
+            Some(Reg::Int(n))
+        } else if let Some(n) = numeric("f") {
+            Some(Reg::Float(n))
+        } else if let Some(n) = INT_ABI_NAMES.iter().position(|name| *name == s) {
+            Some(Reg::Int(n))
+        } else {
+            FLOAT_ABI_NAMES
+                .iter()
+                .position(|name| *name == s)
+                .map(Reg::Float)
+        }
+    }
+
+    fn name(self, names: RegNames) -> String {
+        match (self, names) {
+            (Reg::Int(n), RegNames::Abi) => INT_ABI_NAMES[n].to_string(),
+            (Reg::Float(n), RegNames::Abi) => FLOAT_ABI_NAMES[n].to_string(),
+            (Reg::Int(n), RegNames::Numeric) => format!("x{n}"),
+            (Reg::Float(n), RegNames::Numeric) => format!("f{n}"),
+        }
+    }
+}
+
+/// Parse a (possibly negative) immediate, in decimal or hex.
+fn parse_imm(s: &str) -> Option<i128> {
+    let (neg, abs) = match s.strip_prefix('-') {
+        Some(abs) => (true, abs),
+        None => (false, s),
+    };
+    let abs = utils::string_to_u128(abs).ok()?;
+    let abs = i128::try_from(abs).ok()?;
+    Some(if neg { -abs } else { abs })
+}
+
+struct Operand {
+    text: String,
+    /// Resolved branch/jump target, whose text must not be touched anymore.
+    is_target: bool,
+}
+
+impl Operand {
+    fn int_reg(&self) -> Option<usize> {
+        match Reg::parse(&self.text) {
+            Some(Reg::Int(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn imm(&self) -> Option<i128> {
+        parse_imm(&self.text)
+    }
+
+    /// Offset and base register of a memory operand (`offset(reg)`).
+    fn mem(&self) -> Option<(i128, usize)> {
+        let (offset, base) = self.text.strip_suffix(')')?.split_once('(')?;
+        let offset = if offset.is_empty() {
+            0
+        } else {
+            parse_imm(offset)?
+        };
+        match Reg::parse(base)? {
+            Reg::Int(n) => Some((offset, n)),
+            Reg::Float(_) => None,
+        }
+    }
+}
+
+/// Whether the last operand of `mnemonic` is a PC-relative offset.
+fn is_pc_relative(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "jal"
+            | "j"
+            | "beq"
+            | "bne"
+            | "blt"
+            | "bge"
+            | "bltu"
+            | "bgeu"
+            | "bgt"
+            | "ble"
+            | "bgtu"
+            | "bleu"
+            | "beqz"
+            | "bnez"
+            | "blez"
+            | "bgez"
+            | "bltz"
+            | "bgtz"
+            | "c.j"
+            | "c.jal"
+            | "c.beqz"
+            | "c.bnez"
+    )
+}
+
+/// Pseudo-instruction implemented by an instruction, and the indices of the
+/// operands it keeps.
+fn pseudo_instr(mnemonic: &str, ops: &[Operand]) -> Option<(&'static str, Vec<usize>)> {
+    const RA: usize = 1;
+    let pseudo = match (mnemonic, ops) {
+        ("addi", [rd, rs, imm]) => match (rd.int_reg()?, rs.int_reg()?, imm.imm()?) {
+            (0, 0, 0) => ("nop", vec![]),
+            // Other writes to x0 are HINTs:
+            (0, _, _) => return None,
+            (_, 0, _) => ("li", vec![0, 2]),
+            (_, _, 0) => ("mv", vec![0, 1]),
+            _ => return None,
+        },
+        ("c.nop", []) => ("nop", vec![]),
+        ("c.li", [rd, _]) if rd.int_reg()? != 0 => ("li", vec![0, 1]),
+        ("c.mv", [rd, _]) if rd.int_reg()? != 0 => ("mv", vec![0, 1]),
+        // jalr rd, offset(rs1) / jalr rd, rs1, offset / jalr rd, rs1:
+        ("jalr", [rd, rs]) => match (
+            rd.int_reg()?,
+            rs.mem().or_else(|| Some((0, rs.int_reg()?)))?,
+        ) {
+            (0, (0, RA)) => ("ret", vec![]),
+            _ => return None,
+        },
+        ("jalr", [rd, rs, imm]) => match (rd.int_reg()?, rs.int_reg()?, imm.imm()?) {
+            (0, RA, 0) => ("ret", vec![]),
+            _ => return None,
+        },
+        ("c.jr", [rs]) if rs.int_reg() == Some(RA) => ("ret", vec![]),
+        ("jal", [rd, _]) => match rd.int_reg()? {
+            0 => ("j", vec![1]),
+            RA => ("jal", vec![1]),
+            _ => return None,
+        },
+        ("c.j", [_]) => ("j", vec![0]),
+        _ => return None,
+    };
+    Some(pseudo)
+}
+
+/// Replace all register names in an operand.
+fn rename_regs(operand: &str, names: RegNames) -> String {
+    let mut result = String::with_capacity(operand.len());
+    let mut rest = operand;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let (token, tail) = rest.split_at(end);
+        match Reg::parse(token) {
+            Some(reg) => result.push_str(&reg.name(names)),
+            None => result.push_str(token),
+        }
+        let sep_len = tail.chars().next().map_or(0, char::len_utf8);
+        result.push_str(&tail[..sep_len]);
+        rest = &tail[sep_len..];
+    }
+    result
+}
+
+/// Format a disassembled instruction (`mnemonic op, op, ..`) of an `xlen`-bit
+/// RISC-V core. If the `pc` of the instruction is known, PC-relative
+/// branch/jump offsets are shown as absolute targets.
+pub fn format_instr(instr: &str, pc: Option<u64>, xlen: u32, options: &FormatOptions) -> String {
+    if pc.is_none() && options.reg_names.is_none() && !options.pseudo {
+        return instr.to_string();
+    }
+
+    let instr = instr.trim();
+    let (mnemonic, operands) = instr.split_once(char::is_whitespace).unwrap_or((instr, ""));
+    let mut mnemonic = mnemonic;
+    let mut operands: Vec<Operand> = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|op| !op.is_empty())
+        .map(|op| Operand {
+            text: op.to_string(),
+            is_target: false,
+        })
+        .collect();
+
+    if let (Some(pc), true) = (pc, is_pc_relative(mnemonic)) {
+        if let Some(op) = operands.last_mut() {
+            if let Some(offset) = op.imm() {
+                let mask = if xlen >= 64 {
+                    u64::MAX
+                } else {
+                    (1 << xlen) - 1
+                };
+                let target = pc.wrapping_add(offset as u64) & mask;
+                op.text = format!("0x{target:x}");
+                if let Some(sym) = options.symbols.as_ref().and_then(|s| s.symbolize(target)) {
+                    op.text.push_str(&format!(" <{sym}>"));
+                }
+                op.is_target = true;
+            }
+        }
+    }
+
+    if options.pseudo {
+        if let Some((pseudo, keep)) = pseudo_instr(mnemonic, &operands) {
+            mnemonic = pseudo;
+            let mut old: Vec<Option<Operand>> = operands.into_iter().map(Some).collect();
+            operands = keep.into_iter().filter_map(|idx| old[idx].take()).collect();
+        }
+    }
+
+    if let Some(names) = options.reg_names {
+        for op in operands.iter_mut().filter(|op| !op.is_target) {
+            op.text = rename_regs(&op.text, names);
+        }
+    }
+
+    let operands: Vec<&str> = operands.iter().map(|op| op.text.as_str()).collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{mnemonic} {}", operands.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::symbols::{Symbol, SymbolKind};
+    use super::*;
+
+    #[test]
+    fn test_format_instr() {
+        let symbols = SymbolTable::from_symbols(vec![Symbol {
+            name: "main".into(),
+            address: 0x8000_0100,
+            size: 0x40,
+            kind: SymbolKind::Function,
+        }]);
+        let options = FormatOptions {
+            reg_names: Some(RegNames::Abi),
+            pseudo: true,
+            symbols: Some(Arc::new(symbols)),
+        };
+        let fmt = |instr, pc| format_instr(instr, pc, 32, &options);
+
+        assert_eq!(fmt("addi x0, x0, 0", None), "nop");
+        assert_eq!(fmt("addi x10, x0, -1", None), "li a0, -1");
+        assert_eq!(fmt("addi x8, x2, 0", None), "mv s0, sp");
+        // HINTs:
+        assert_eq!(fmt("addi x0, x0, 5", None), "addi zero, zero, 5");
+        assert_eq!(fmt("addi x0, x8, 0", None), "addi zero, s0, 0");
+        assert_eq!(fmt("c.li x0, 1", None), "c.li zero, 1");
+        assert_eq!(fmt("addi x2, x2, 16", None), "addi sp, sp, 16");
+        assert_eq!(fmt("jalr x0, 0(x1)", None), "ret");
+        assert_eq!(fmt("sw x1, 12(x2)", None), "sw ra, 12(sp)");
+        assert_eq!(
+            fmt("jal x0, -0x8", Some(0x8000_0110)),
+            "j 0x80000108 <main+0x8>"
+        );
+        assert_eq!(
+            fmt("jal ra, 0x100", Some(0x8000_0000)),
+            "jal 0x80000100 <main>"
+        );
+        assert_eq!(fmt("bne a0, a1, 8", Some(0xFFFF_FFFC)), "bne a0, a1, 0x4");
+
+        let numeric = FormatOptions {
+            reg_names: Some(RegNames::Numeric),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_instr("fadd.s fa0, ft0, fs1", None, 64, &numeric),
+            "fadd.s f10, f0, f9"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Annotators written as [Rhai](https://rhai.rs) scripts, for quick one-off
+//! decoders.
+//!
+//! Every (non-`private`) function of a script taking a single parameter
+//! resolves the placeholders of its name, and is called with their value as
+//! a string:
+//!
+//! ```rhai
+//! fn pkt(value) {
+//!     let x = num(value);
+//!     `id=${bits(x, 7, 4)} len=${bits(x, 3, 0)} raw=${hex(x, 4)}`
+//! }
+//! ```
+//!
+//! Returning `()` leaves a placeholder unresolved, other values are
+//! converted to strings. Besides Rhai's standard library, scripts can use:
+//!
+//! - `num(s)`: parse a decimal or `0x`-prefixed hex number
+//! - `bits(x, msb, lsb)`: bits `msb` down to `lsb` (inclusive) of `x`
+//! - `hex(x)`, `hex(x, digits)`: format `x` as `0x`-prefixed hex, optionally
+//!   zero-padded to `digits` digits
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FnAccess, Scope, AST, INT};
+
+use crate::utils;
+
+use super::{Annotater, Placeholder};
+
+fn num(s: &str) -> Result<INT, Box<EvalAltResult>> {
+    utils::string_to_u64(s.trim())
+        .map(|x| x as INT)
+        .map_err(|e| format!("invalid number '{s}' - {e}").into())
+}
+
+fn bits(x: INT, msb: INT, lsb: INT) -> Result<INT, Box<EvalAltResult>> {
+    if lsb < 0 || msb < lsb || msb > 63 {
+        return Err(format!("invalid bit range {msb}:{lsb}").into());
+    }
+    let width = msb - lsb + 1;
+    let mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+    Ok(((x as u64 >> lsb) & mask) as INT)
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_fn("num", num)
+        .register_fn("bits", bits)
+        .register_fn("hex", |x: INT| format!("0x{:x}", x as u64))
+        .register_fn("hex", |x: INT, digits: INT| {
+            format!("0x{:0digits$x}", x as u64, digits = digits.max(0) as usize)
+        });
+    engine
+}
+
+/// Resolves placeholders using the functions of a Rhai script.
+pub struct ScriptAnnotator {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    kinds: Vec<String>,
+}
+
+impl ScriptAnnotator {
+    /// Compile a script and run its top-level statements.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = engine();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow!("Failed to load script {} - {e}", path.display()))?;
+        Self::new(engine, ast).map_err(|e| anyhow!("Failed to run script {} - {e}", path.display()))
+    }
+
+    fn new(engine: Engine, ast: AST) -> Result<Self, Box<EvalAltResult>> {
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+
+        let mut kinds: Vec<String> = ast
+            .iter_functions()
+            .filter(|f| f.access != FnAccess::Private && f.params.len() == 1)
+            .map(|f| f.name.to_string())
+            .collect();
+        kinds.sort();
+        debug!("script resolves placeholders {kinds:?}");
+
+        Ok(Self {
+            engine,
+            ast,
+            scope,
+            kinds,
+        })
+    }
+}
+
+impl Annotater for ScriptAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        self.kinds.clone()
+    }
+
+    fn annotate(&mut self, placeholder: &Placeholder) -> anyhow::Result<Option<String>> {
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut self.scope,
+            &self.ast,
+            &placeholder.kind,
+            (placeholder.value.clone(),),
+        );
+        match result {
+            Ok(value) if value.is_unit() => Ok(None),
+            Ok(value) => Ok(Some(value.to_string())),
+            Err(e) => {
+                warn!(
+                    "script: failed to resolve ${}:{} - {e} - skipping.",
+                    placeholder.kind, placeholder.value
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let engine = engine();
+        let ast = engine
+            .compile(
+                r#"
+                fn pkt(value) {
+                    let x = num(value);
+                    if x == 0 { return; }
+                    `id=${bits(x, 7, 4)} raw=${hex(x, 4)}`
+                }
+                fn width(value) { value.len() }
+                private fn helper(value) { value }
+                fn two(a, b) { a + b }
+                "#,
+            )
+            .unwrap();
+        let mut script = ScriptAnnotator::new(engine, ast).unwrap();
+        assert_eq!(script.accepts_keys(), vec!["pkt", "width"]);
+
+        let mut annotate = |kind: &str, value: &str| {
+            script
+                .annotate(&Placeholder {
+                    kind: kind.into(),
+                    value: value.into(),
+                })
+                .unwrap()
+        };
+        assert_eq!(annotate("pkt", "0x3a").as_deref(), Some("id=3 raw=0x003a"));
+        assert_eq!(annotate("pkt", "0"), None);
+        assert_eq!(annotate("pkt", "zz"), None);
+        assert_eq!(annotate("width", "abc").as_deref(), Some("3"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Symbol tables of ELF files, to show addresses as `symbol+offset`.
+
+use std::{borrow::Cow, fs, path::Path, sync::Arc};
+
+use anyhow::anyhow;
+use object::{Object, ObjectSymbol};
+
+use super::{images::AddressAnnotater, variables::VariableTable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    /// Data symbol, such as a global variable.
+    Object,
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Demangled name.
+    pub name: String,
+    pub address: u64,
+    /// Size in bytes, 0 if unknown.
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.address && (self.size == 0 || addr - self.address < self.size)
+    }
+}
+
+/// Function and object symbols, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    max_size: u64,
+}
+
+impl SymbolTable {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path)
+            .map_err(|e| anyhow!("Failed to read symbols of {} - {e}", path.display()))?;
+        let file = object::File::parse(data.as_slice())
+            .map_err(|e| anyhow!("Failed to parse {} - {e}", path.display()))?;
+
+        let symbols = file
+            .symbols()
+            .filter(|sym| sym.is_definition())
+            .filter_map(|sym| {
+                let kind = match sym.kind() {
+                    object::SymbolKind::Text => SymbolKind::Function,
+                    object::SymbolKind::Data => SymbolKind::Object,
+                    _ => return None,
+                };
+                let name = sym.name().ok()?;
+                // Skip mapping symbols ($x, $d) and local labels:
+                if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                    return None;
+                }
+                Some(Symbol {
+                    name: addr2line::demangle_auto(Cow::from(name), None).into_owned(),
+                    address: sym.address(),
+                    size: sym.size(),
+                    kind,
+                })
+            })
+            .collect();
+        Ok(Self::from_symbols(symbols))
+    }
+
+    pub fn from_symbols(mut symbols: Vec<Symbol>) -> Self {
+        // Of symbols at the same address, prefer functions, then sized ones:
+        symbols.sort_by(|a, b| {
+            (a.address, a.kind, a.size != 0, &a.name).cmp(&(
+                b.address,
+                b.kind,
+                b.size != 0,
+                &b.name,
+            ))
+        });
+        let max_size = symbols.iter().map(|sym| sym.size).max().unwrap_or(0);
+        Self { symbols, max_size }
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// The symbol containing `addr` (or the closest symbol of unknown size
+    /// before it), and the offset of `addr` into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let end = self.symbols.partition_point(|sym| sym.address <= addr);
+        let preferred = self.symbols[..end].last()?;
+        // Symbols may nest, so an earlier, larger symbol may still contain
+        // `addr`:
+        let sym = if preferred.contains(addr) {
+            preferred
+        } else {
+            self.symbols[..end]
+                .iter()
+                .rev()
+                .take_while(|sym| addr - sym.address < self.max_size)
+                .find(|sym| sym.contains(addr))?
+        };
+        Some((sym, addr - sym.address))
+    }
+
+    /// `addr` as `symbol` or `symbol+0x<offset>`.
+    pub fn symbolize(&self, addr: u64) -> Option<String> {
+        self.lookup(addr).map(|(sym, offset)| match offset {
+            0 => sym.name.clone(),
+            offset => format!("{}+0x{offset:x}", sym.name),
+        })
+    }
+}
+
+/// Resolves `$sym:<addr>` to the function or object containing it, and
+/// `$var:<addr>` to the (member of the) variable containing it, falling back
+/// to the symbol if there is no debug info for it.
+pub struct SymbolAnnotator {
+    symbols: Arc<SymbolTable>,
+    variables: VariableTable,
+}
+
+impl SymbolAnnotator {
+    pub fn new(symbols: Arc<SymbolTable>, variables: VariableTable) -> Self {
+        Self { symbols, variables }
+    }
+}
+
+impl AddressAnnotater for SymbolAnnotator {
+    fn accepts_keys(&self) -> Vec<String> {
+        vec!["sym".to_string(), "var".to_string()]
+    }
+
+    fn annotate_addr(
+        &mut self,
+        kind: &str,
+        addr: u64,
+        trace_addr: u64,
+    ) -> anyhow::Result<Option<String>> {
+        let name = match kind {
+            "var" => self
+                .variables
+                .lookup(addr)
+                .or_else(|| self.symbols.symbolize(addr)),
+            _ => self.symbols.symbolize(addr),
+        };
+        let name = name.unwrap_or_else(|| "?".to_string());
+        Ok(Some(format!("{name} (0x{trace_addr:08x})")))
+    }
+}
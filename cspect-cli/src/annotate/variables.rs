@@ -0,0 +1,403 @@
+//! Global variables from DWARF debug info, to show data addresses as
+//! `variable.field[index]`.
+
+use std::{borrow::Cow, collections::HashMap, fs, path::Path};
+
+use addr2line::gimli::{self, AttributeValue, EndianSlice, RunTimeEndian};
+use anyhow::anyhow;
+use object::{Object, ObjectSection};
+
+type DwarfReader<'a> = EndianSlice<'a, RunTimeEndian>;
+type Entry<'a, 'u> = gimli::DebuggingInformationEntry<'a, 'u, DwarfReader<'a>>;
+
+/// Offset of a type's entry in `.debug_info`.
+type TypeRef = usize;
+
+/// Maximum nesting of types, guarding against cycles in broken debug info.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    /// Base, pointer, enumeration and other types without named parts.
+    Scalar { size: u64 },
+    /// Structures, unions and classes.
+    Struct { size: u64, members: Vec<Member> },
+    /// Array with the given dimensions, 0 for unknown (flexible) ones.
+    Array {
+        elem: Option<TypeRef>,
+        dims: Vec<u64>,
+    },
+    /// Typedefs and qualified types.
+    Alias(Option<TypeRef>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    /// `None` for anonymous structs/unions, whose members are accessed
+    /// directly.
+    name: Option<String>,
+    offset: u64,
+    ty: Option<TypeRef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Variable {
+    name: String,
+    address: u64,
+    ty: Option<TypeRef>,
+}
+
+/// Variables with static storage and their types, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct VariableTable {
+    variables: Vec<Variable>,
+    types: HashMap<TypeRef, Type>,
+}
+
+impl VariableTable {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path)
+            .map_err(|e| anyhow!("Failed to read debug info of {} - {e}", path.display()))?;
+        let file = object::File::parse(data.as_slice())
+            .map_err(|e| anyhow!("Failed to parse {} - {e}", path.display()))?;
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+
+        let sections = gimli::DwarfSections::load(|id| -> Result<Cow<[u8]>, gimli::Error> {
+            Ok(match file.section_by_name(id.name()) {
+                Some(section) => section.uncompressed_data().unwrap_or(Cow::Borrowed(&[])),
+                None => Cow::Borrowed(&[]),
+            })
+        })?;
+        let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+        Self::from_dwarf(&dwarf)
+            .map_err(|e| anyhow!("Failed to read debug info of {} - {e}", path.display()))
+    }
+
+    fn from_dwarf(dwarf: &gimli::Dwarf<DwarfReader>) -> gimli::Result<Self> {
+        let mut table = Self::default();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut tree = unit.entries_tree(None)?;
+            table.add_entries(dwarf, &unit, tree.root()?)?;
+        }
+        table.variables.sort_by_key(|var| var.address);
+        Ok(table)
+    }
+
+    fn add_entries(
+        &mut self,
+        dwarf: &gimli::Dwarf<DwarfReader>,
+        unit: &gimli::Unit<DwarfReader>,
+        node: gimli::EntriesTreeNode<DwarfReader>,
+    ) -> gimli::Result<()> {
+        let entry = node.entry();
+        let offset = entry
+            .offset()
+            .to_debug_info_offset(&unit.header)
+            .map(|offset| offset.0);
+        let ty = type_ref(unit, entry)?;
+        let size = entry
+            .attr_value(gimli::DW_AT_byte_size)?
+            .and_then(|size| size.udata_value());
+
+        let parsed = match entry.tag() {
+            gimli::DW_TAG_variable => {
+                if let Some(var) = variable(dwarf, unit, entry)? {
+                    self.variables.push(var);
+                }
+                None
+            }
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type | gimli::DW_TAG_class_type => {
+                let mut members = vec![];
+                let mut children = node.children();
+                while let Some(child) = children.next()? {
+                    let entry = child.entry();
+                    // Static members are declared as members, but defined
+                    // as variables:
+                    let is_static = entry.attr_value(gimli::DW_AT_external)?.is_some()
+                        || entry.attr_value(gimli::DW_AT_declaration)?.is_some();
+                    if entry.tag() != gimli::DW_TAG_member || is_static {
+                        // Nested type definitions:
+                        self.add_entries(dwarf, unit, child)?;
+                        continue;
+                    }
+                    let member_offset = match entry.attr_value(gimli::DW_AT_data_member_location)? {
+                        Some(location) => location.udata_value(),
+                        None => entry
+                            .attr_value(gimli::DW_AT_data_bit_offset)?
+                            .and_then(|bits| bits.udata_value())
+                            .map(|bits| bits / 8)
+                            .or(Some(0)),
+                    };
+                    // Locations given as expressions are not supported:
+                    let Some(member_offset) = member_offset else {
+                        continue;
+                    };
+                    members.push(Member {
+                        name: name(dwarf, unit, entry)?,
+                        offset: member_offset,
+                        ty: type_ref(unit, entry)?,
+                    });
+                }
+                if let Some(offset) = offset {
+                    let size = size.unwrap_or(0);
+                    self.types.insert(offset, Type::Struct { size, members });
+                }
+                return Ok(());
+            }
+            gimli::DW_TAG_array_type => {
+                let mut dims = vec![];
+                let mut children = node.children();
+                while let Some(child) = children.next()? {
+                    let entry = child.entry();
+                    if entry.tag() != gimli::DW_TAG_subrange_type {
+                        continue;
+                    }
+                    let count = match entry.attr_value(gimli::DW_AT_count)? {
+                        Some(count) => count.udata_value(),
+                        None => entry
+                            .attr_value(gimli::DW_AT_upper_bound)?
+                            .and_then(|bound| bound.udata_value())
+                            .map(|bound| bound + 1),
+                    };
+                    dims.push(count.unwrap_or(0));
+                }
+                if dims.is_empty() {
+                    dims.push(0);
+                }
+                if let Some(offset) = offset {
+                    self.types.insert(offset, Type::Array { elem: ty, dims });
+                }
+                return Ok(());
+            }
+            gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type
+            | gimli::DW_TAG_atomic_type => Some(Type::Alias(ty)),
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_reference_type
+            | gimli::DW_TAG_rvalue_reference_type => Some(Type::Scalar {
+                size: size.unwrap_or(unit.encoding().address_size as u64),
+            }),
+            gimli::DW_TAG_base_type | gimli::DW_TAG_enumeration_type => Some(Type::Scalar {
+                size: size.unwrap_or(0),
+            }),
+            _ => None,
+        };
+        if let (Some(offset), Some(parsed)) = (offset, parsed) {
+            self.types.insert(offset, parsed);
+        }
+
+        let mut children = node.children();
+        while let Some(child) = children.next()? {
+            self.add_entries(dwarf, unit, child)?;
+        }
+        Ok(())
+    }
+
+    fn size_of(&self, ty: Option<TypeRef>, depth: usize) -> Option<u64> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match self.types.get(&ty?)? {
+            Type::Scalar { size } | Type::Struct { size, .. } => Some(*size),
+            Type::Array { elem, dims } => {
+                let elem_size = self.size_of(*elem, depth + 1)?;
+                Some(
+                    dims.iter()
+                        .fold(elem_size, |size, dim| size.saturating_mul(*dim)),
+                )
+            }
+            Type::Alias(target) => self.size_of(*target, depth + 1),
+        }
+    }
+
+    /// `addr` as path into the variable containing it, such as
+    /// `uart.regs[2].ctrl+0x1`.
+    pub fn lookup(&self, addr: u64) -> Option<String> {
+        let end = self.variables.partition_point(|var| var.address <= addr);
+        let var = self.variables[..end].last()?;
+        let offset = addr - var.address;
+        if offset >= self.size_of(var.ty, 0).unwrap_or(0).max(1) {
+            return None;
+        }
+        let mut path = var.name.clone();
+        self.describe(var.ty, offset, &mut path, 0);
+        Some(path)
+    }
+
+    fn describe(&self, ty: Option<TypeRef>, offset: u64, path: &mut String, depth: usize) {
+        let ty = ty.and_then(|ty| self.types.get(&ty));
+        match ty {
+            _ if depth > MAX_DEPTH => {}
+            Some(Type::Alias(target)) => return self.describe(*target, offset, path, depth + 1),
+            Some(Type::Struct { members, .. }) => {
+                let member = members.iter().find(|member| {
+                    offset >= member.offset
+                        && offset - member.offset < self.size_of(member.ty, 0).unwrap_or(0)
+                });
+                if let Some(member) = member {
+                    if let Some(name) = &member.name {
+                        path.push('.');
+                        path.push_str(name);
+                    }
+                    return self.describe(member.ty, offset - member.offset, path, depth + 1);
+                }
+            }
+            Some(Type::Array { elem, dims }) => {
+                let elem_size = self.size_of(*elem, 0).unwrap_or(0);
+                let strides: Vec<u64> = (0..dims.len())
+                    .map(|idx| {
+                        dims[idx + 1..]
+                            .iter()
+                            .fold(elem_size, |size, dim| size.saturating_mul(*dim))
+                    })
+                    .collect();
+                if strides.iter().all(|stride| *stride > 0) {
+                    let mut rest = offset;
+                    for stride in strides {
+                        path.push_str(&format!("[{}]", rest / stride));
+                        rest %= stride;
+                    }
+                    return self.describe(*elem, rest, path, depth + 1);
+                }
+            }
+            _ => {}
+        }
+        if offset != 0 {
+            path.push_str(&format!("+0x{offset:x}"));
+        }
+    }
+}
+
+fn type_ref(unit: &gimli::Unit<DwarfReader>, entry: &Entry) -> gimli::Result<Option<TypeRef>> {
+    Ok(match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => {
+            offset.to_debug_info_offset(&unit.header).map(|o| o.0)
+        }
+        Some(AttributeValue::DebugInfoRef(offset)) => Some(offset.0),
+        _ => None,
+    })
+}
+
+fn name(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    entry: &Entry,
+) -> gimli::Result<Option<String>> {
+    match entry.attr_value(gimli::DW_AT_name)? {
+        Some(name) => Ok(Some(
+            dwarf
+                .attr_string(unit, name)?
+                .to_string_lossy()
+                .into_owned(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Variable with a static address, such as globals and static locals.
+fn variable(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    entry: &Entry,
+) -> gimli::Result<Option<Variable>> {
+    let Some(location) = entry
+        .attr_value(gimli::DW_AT_location)?
+        .and_then(|location| location.exprloc_value())
+    else {
+        return Ok(None);
+    };
+    let mut ops = location.operations(unit.encoding());
+    let address = match (ops.next(), ops.next()) {
+        (Ok(Some(gimli::Operation::Address { address })), Ok(None)) => address,
+        (Ok(Some(gimli::Operation::AddressIndex { index })), Ok(None)) => {
+            dwarf.address(unit, index)?
+        }
+        _ => return Ok(None),
+    };
+
+    let mut var_name = name(dwarf, unit, entry)?;
+    let mut ty = type_ref(unit, entry)?;
+    // Definitions of declarations (e.g. static members) may only refer to
+    // them for name and type:
+    if let Some(AttributeValue::UnitRef(decl)) = entry.attr_value(gimli::DW_AT_specification)? {
+        let decl = unit.entry(decl)?;
+        if var_name.is_none() {
+            var_name = name(dwarf, unit, &decl)?;
+        }
+        if ty.is_none() {
+            ty = type_ref(unit, &decl)?;
+        }
+    }
+
+    Ok(var_name.map(|name| Variable { name, address, ty }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        // struct { u32 ctrl; u8 buf[2][4]; } uart[2] @ 0x1000
+        let types = HashMap::from([
+            (1, Type::Scalar { size: 4 }),
+            (2, Type::Scalar { size: 1 }),
+            (
+                3,
+                Type::Array {
+                    elem: Some(2),
+                    dims: vec![2, 4],
+                },
+            ),
+            (
+                4,
+                Type::Struct {
+                    size: 12,
+                    members: vec![
+                        Member {
+                            name: Some("ctrl".into()),
+                            offset: 0,
+                            ty: Some(1),
+                        },
+                        Member {
+                            name: Some("buf".into()),
+                            offset: 4,
+                            ty: Some(3),
+                        },
+                    ],
+                },
+            ),
+            (5, Type::Alias(Some(4))),
+            (
+                6,
+                Type::Array {
+                    elem: Some(5),
+                    dims: vec![2],
+                },
+            ),
+        ]);
+        let table = VariableTable {
+            variables: vec![Variable {
+                name: "uart".into(),
+                address: 0x1000,
+                ty: Some(6),
+            }],
+            types,
+        };
+
+        assert_eq!(table.lookup(0x1000).as_deref(), Some("uart[0].ctrl"));
+        assert_eq!(table.lookup(0x1002).as_deref(), Some("uart[0].ctrl+0x2"));
+        assert_eq!(table.lookup(0x1015).as_deref(), Some("uart[1].buf[1][1]"));
+        assert_eq!(table.lookup(0x1017).as_deref(), Some("uart[1].buf[1][3]"));
+        assert_eq!(table.lookup(0x1018).as_deref(), None);
+        assert_eq!(table.lookup(0xFFF).as_deref(), None);
+    }
+}
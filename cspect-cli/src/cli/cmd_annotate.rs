@@ -1,9 +1,26 @@
-use std::path::PathBuf;
+use std::{
+    io::{self, IsTerminal},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 
+#[cfg(feature = "script")]
+use crate::annotate::script::ScriptAnnotator;
 use crate::{
-    annotate::{addr2line::Addr2LineAnnotator, annotate, disasm::DisasmAnnotater, Annotater},
+    annotate::{
+        annotate,
+        disasm::{DisasmAnnotater, Disassembler, IsaSpec},
+        enums::{EnumAnnotator, EnumTables},
+        images::{Image, ImageAnnotator, ImageSpec},
+        march::March,
+        plugin::{PluginAnnotator, PluginSpec},
+        regmap::{RegisterAnnotator, RegisterMap},
+        riscv::{FormatOptions, RegNames},
+        AnnotateOptions, Annotater,
+    },
     open::{open_trace, serve_trace},
 };
 
@@ -22,9 +39,91 @@ pub struct Cmd {
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub disasm: bool,
 
-    /// Convert $a2l-annotated addresses to lines using given elf file.
-    #[arg(long)]
-    pub addr2line: Option<PathBuf>,
+    /// Disassemble $da-rv32/$da-rv64-annotated instructions with exactly the
+    /// extensions of this -march string (e.g. rv32imac_zicsr_zifencei)
+    /// instead of all known extensions. Use name=march to register the
+    /// disassembler as $da-<name> instead. May be repeated.
+    #[arg(long, value_name = "[NAME=]MARCH")]
+    pub march: Vec<String>,
+
+    /// Disassemble $da-<name>-annotated instructions of an additional ISA,
+    /// given as name[:bits]=file.toml[,file.toml..] (instruction-decoder
+    /// TOML files, instruction length in bits defaults to 32). May be
+    /// repeated.
+    #[arg(long, value_name = "NAME[:BITS]=FILES")]
+    pub isa: Vec<IsaSpec>,
+
+    /// Register names of disassembled RISC-V instructions (abi or numeric,
+    /// default: as decoded)
+    #[arg(long, value_name = "abi|numeric")]
+    pub reg_names: Option<RegNames>,
+
+    /// Show pseudo-instructions (nop, ret, j, li, mv) in disassembled
+    /// RISC-V instructions
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub pseudo: bool,
+
+    /// Convert $a2l-annotated addresses to lines using given elf file. Its
+    /// symbols also resolve $sym-annotated addresses to symbol+offset,
+    /// $var-annotated ones to variable members, and branch/jump targets of
+    /// $da-rv*:insn@pc placeholders. May be repeated for multi-image traces,
+    /// optionally with a tag (selected by $a2l@<tag>:...), a load offset
+    /// and the (end exclusive) range of trace addresses of the image.
+    #[arg(long, value_name = "ELF[,tag=NAME][,offset=ADDR][,range=START-END]")]
+    pub addr2line: Vec<ImageSpec>,
+
+    /// Resolve $a2l- and $sym-annotated addresses (best-effort) using a GNU
+    /// ld or LLVM lld map file, for builds without an elf file. Takes the
+    /// same options as --addr2line, elf files take precedence.
+    #[arg(long, value_name = "MAP[,tag=NAME][,offset=ADDR][,range=START-END]")]
+    pub map: Vec<ImageSpec>,
+
+    /// Decode $enum:<table>:<value>-annotated numbers using the mapping
+    /// tables of a TOML, JSON or CSV file. Use name=file for files holding a
+    /// single table. May be repeated.
+    #[arg(long, value_name = "[NAME=]FILE")]
+    pub enum_table: Vec<String>,
+
+    /// Resolve $reg:<addr>-annotated addresses to block/register names and
+    /// decode $regval:<addr>=<value>-annotated register values to fields,
+    /// using a TOML/JSON register description or a SystemRDL JSON export.
+    /// May be repeated.
+    #[arg(long, value_name = "FILE")]
+    pub reg_map: Vec<PathBuf>,
+
+    /// Resolve placeholders using the functions of a Rhai script: $<kind>
+    /// placeholders are passed to the function <kind>(value). May be
+    /// repeated.
+    #[cfg(feature = "script")]
+    #[arg(long, value_name = "FILE")]
+    pub script: Vec<PathBuf>,
+
+    /// Resolve $<kind>-annotated placeholders using an external process,
+    /// which is sent one {"id","kind","value"} JSON line per placeholder on
+    /// stdin and answers with one {"id","text"} line each on stdout. May be
+    /// repeated.
+    #[arg(long, value_name = "KIND=COMMAND")]
+    pub plugin: Vec<PluginSpec>,
+
+    /// Seconds to wait for each response of a plugin, before leaving the
+    /// placeholder unresolved (and stopping plugins that don't echo the id)
+    #[arg(long, value_name = "SECONDS", default_value_t = 10.0)]
+    pub plugin_timeout: f64,
+
+    /// Keep placeholders in event strings and add the resolved values as a
+    /// separate "annotations" argument instead
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub structured_args: bool,
+
+    /// Number of resolved placeholders to cache, so that repeated ones (such
+    /// as addresses of loops) are only resolved once. Placeholders of plugins
+    /// and scripts are never cached.
+    #[arg(long, value_name = "ENTRIES", default_value_t = 1 << 20)]
+    pub cache_size: usize,
+
+    /// Don't show the progress of annotation
+    #[arg(long, short, action = clap::ArgAction::SetTrue)]
+    pub quiet: bool,
 
     /// Open annotated trace in perfetto
     #[arg(long, action = clap::ArgAction::SetTrue)]
@@ -40,16 +139,74 @@ impl Cmd {
         // Construct Annotators:
         let mut annotators: Vec<Box<dyn Annotater>> = vec![];
 
-        if let Some(path) = &self.addr2line {
-            annotators.push(Box::new(Addr2LineAnnotator::new(path)?));
+        let mut images = vec![];
+        for spec in &self.addr2line {
+            images.push(Image::elf(spec.clone())?);
+        }
+        for spec in &self.map {
+            images.push(Image::map(spec.clone())?);
+        }
+        let mut symbols = None;
+        if !images.is_empty() {
+            let images = ImageAnnotator::new(images);
+            symbols = Some(Arc::new(images.symbols()));
+            annotators.push(Box::new(images));
+        }
+
+        if !self.enum_table.is_empty() {
+            let mut tables = EnumTables::default();
+            for spec in &self.enum_table {
+                tables.load(spec)?;
+            }
+            annotators.push(Box::new(EnumAnnotator::new(tables)));
+        }
+
+        if !self.reg_map.is_empty() {
+            let mut map = RegisterMap::default();
+            for path in &self.reg_map {
+                map.load(path)?;
+            }
+            annotators.push(Box::new(RegisterAnnotator::new(map)));
+        }
+
+        for spec in &self.plugin {
+            let timeout = Duration::try_from_secs_f64(self.plugin_timeout)
+                .map_err(|e| anyhow::anyhow!("Invalid plugin timeout - {e}"))?;
+            annotators.push(Box::new(PluginAnnotator::spawn(spec, timeout)?));
+        }
+
+        #[cfg(feature = "script")]
+        for path in &self.script {
+            annotators.push(Box::new(ScriptAnnotator::load(path)?));
         }
 
-        if self.disasm {
-            annotators.push(Box::new(DisasmAnnotater::new()));
+        if self.disasm || !self.isa.is_empty() || !self.march.is_empty() {
+            let mut disasm = DisasmAnnotater::new();
+            for march in &self.march {
+                let (name, march) = match march.split_once('=') {
+                    Some((name, march)) => (name.to_string(), march),
+                    None => (format!("rv{}", March::parse(march)?.xlen), march.as_str()),
+                };
+                disasm.add_isa(&name, Disassembler::from_march(march)?);
+            }
+            for isa in &self.isa {
+                disasm.add_isa(&isa.name, isa.build()?);
+            }
+            disasm.set_format(FormatOptions {
+                reg_names: self.reg_names,
+                pseudo: self.pseudo,
+                symbols: symbols.clone(),
+            });
+            annotators.push(Box::new(disasm));
         }
 
         // Perform annotation:
-        annotate(&self.input, self.output.as_deref(), annotators)?;
+        let options = AnnotateOptions {
+            structured_args: self.structured_args,
+            cache_size: self.cache_size,
+            progress: !self.quiet && io::stderr().is_terminal(),
+        };
+        annotate(&self.input, self.output.as_deref(), annotators, &options)?;
 
         // Serve/Open:
         let file_to_open = self.output.as_ref().unwrap_or(&self.input);
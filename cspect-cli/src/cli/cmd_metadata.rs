@@ -0,0 +1,55 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use clap::Parser;
+use synthetto::{
+    debug_annotation, trace_packet::Data, track_descriptor::StaticOrDynamicName, TraceReader,
+    METADATA_TRACK_NAME,
+};
+
+#[derive(Parser, Debug)]
+#[command(about = "Print metadata (cspect version, creation time, ..) of trace")]
+pub struct Cmd {
+    /// Perfetto trace file to inspect
+    #[arg()]
+    pub input: PathBuf,
+}
+
+impl Cmd {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let mut metadata_tracks = HashSet::new();
+        for packet in TraceReader::open(&self.input)? {
+            let (_, packet) = packet?;
+            match packet.data {
+                Some(Data::TrackDescriptor(desc)) => {
+                    if let Some(StaticOrDynamicName::Name(name)) = &desc.static_or_dynamic_name {
+                        if name == METADATA_TRACK_NAME {
+                            metadata_tracks.extend(desc.uuid);
+                        }
+                    }
+                }
+                Some(Data::TrackEvent(evt)) => {
+                    if !evt
+                        .track_uuid
+                        .is_some_and(|uuid| metadata_tracks.contains(&uuid))
+                    {
+                        continue;
+                    }
+                    for arg in evt.debug_annotations {
+                        let Some(debug_annotation::NameField::Name(key)) = arg.name_field else {
+                            continue;
+                        };
+                        match arg.value {
+                            Some(debug_annotation::Value::StringValue(value)) => {
+                                println!("{key}: {value}")
+                            }
+                            Some(value) => println!("{key}: {value:?}"),
+                            None => println!("{key}:"),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 mod cmd_annotate;
 mod cmd_completion;
+mod cmd_metadata;
 #[cfg(feature = "serve")]
 mod cmd_open;
 #[cfg(feature = "serve")]
@@ -20,8 +21,9 @@ pub struct Cli {
 
 #[derive(Parser, Debug)]
 pub enum CliCmd {
-    Annotate(cmd_annotate::Cmd),
+    Annotate(Box<cmd_annotate::Cmd>),
     Completion(cmd_completion::Cmd),
+    Metadata(cmd_metadata::Cmd),
     #[cfg(feature = "serve")]
     Open(cmd_open::Cmd),
     #[cfg(feature = "serve")]
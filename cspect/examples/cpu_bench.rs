@@ -0,0 +1,101 @@
+//! Measure the throughput of [`Context`] on the workload of the `cpu` example:
+//! Per cycle, six CPU tracks and 512 RAM content tracks set their slice with
+//! `compress` (most of them unchanged), the stack track begins or ends a slice
+//! every few cycles, and memory accesses are logged as instant events.
+//!
+//! Unlike `synthetto`'s `encode_bench`, this includes the slice bookkeeping
+//! and writing of the trace file.
+//!
+//! Run with `cargo run --release -p cspect --example cpu_bench`.
+
+use std::time::Instant;
+
+use cspect::{Context, ReplacementBehaviour};
+
+const CYCLES: u64 = 20_000;
+
+fn main() {
+    // Names are formatted up-front, as the simulator hands them over as
+    // strings:
+    let values: Vec<String> = (0..256).map(|i| format!("0x{i:02x}")).collect();
+    let ops: Vec<String> = (0..256)
+        .map(|i| format!("Read @ 0x{i:02x}: 0x{:02x}", 255 - i))
+        .collect();
+
+    let path = std::env::temp_dir().join(format!("cspect-cpu-bench-{}", std::process::id()));
+    let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+    let mut new_track = |name: String| ctx.new_track(name, None, None, None, None).unwrap();
+    let cpu_tracks: Vec<u64> = (0..6).map(|i| new_track(format!("cpu{i}"))).collect();
+    let stack = new_track("Stack".into());
+    let ram_tracks: Vec<u64> = (0..512).map(|i| new_track(format!("ram{i}"))).collect();
+    let ops_track = new_track("Operations".into());
+
+    let mut calls = 0u64;
+    let start = Instant::now();
+    for cycle in 0..CYCLES {
+        let ts = (cycle * 10) as f64;
+        for (i, track) in cpu_tracks.iter().enumerate() {
+            // Registers change every few cycles:
+            let value = &values[((cycle >> i) % 256) as usize];
+            ctx.slice_begin_evt(
+                *track,
+                ts,
+                Some(value),
+                &[],
+                &[],
+                ReplacementBehaviour::ReplaceIfDifferent,
+                None,
+            )
+            .unwrap();
+        }
+        // Calls and returns:
+        match cycle % 8 {
+            0 | 1 => {
+                let value = Some(values[(cycle % 256) as usize].as_str());
+                let behaviour = ReplacementBehaviour::NewSlice;
+                ctx.slice_begin_evt(stack, ts, value, &[], &[], behaviour, None)
+                    .unwrap();
+                calls += 1;
+            }
+            4 | 5 => {
+                ctx.slice_end_evt(stack, ts, &[], &[], false, None).unwrap();
+                calls += 1;
+            }
+            _ => {}
+        }
+        for (i, track) in ram_tracks.iter().enumerate() {
+            // One memory location is written per cycle:
+            let value = if i as u64 == cycle % 512 { cycle } else { 0 };
+            ctx.slice_begin_evt(
+                *track,
+                ts,
+                Some(&values[(value % 256) as usize]),
+                &[],
+                &[],
+                ReplacementBehaviour::ReplaceIfDifferent,
+                None,
+            )
+            .unwrap();
+        }
+        ctx.instant_evt(
+            ops_track,
+            ts,
+            Some(&ops[(cycle % 256) as usize]),
+            &[],
+            &[],
+            None,
+        )
+        .unwrap();
+        calls += 6 + 512 + 1;
+    }
+    drop(ctx);
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    std::fs::remove_file(&path).unwrap();
+    println!(
+        "{:.2} Mcalls/s ({:.1} MB written)",
+        calls as f64 / elapsed / 1e6,
+        bytes as f64 / 1e6
+    );
+}
@@ -0,0 +1,231 @@
+//! Counters derived from raw increments, sampled at a fixed interval.
+
+use std::collections::VecDeque;
+
+use crate::state::{split_tokens, Token};
+
+/// What a derived counter plots, given the increments logged to it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DerivedCounterKind {
+    /// Sum of the increments of each sample interval, scaled to a rate per
+    /// `per` time units (in timescale units, e.g. the clock period for
+    /// "instructions per cycle").
+    Rate { per: f64 },
+    /// Mean of the per-interval sums of the last `samples` sample intervals.
+    MovingAverage { samples: usize },
+    /// Running total of all increments.
+    Cumulative,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DerivedCounter {
+    kind: DerivedCounterKind,
+    /// Sample interval in trace timestamp units.
+    interval: u64,
+    /// Factor converting the sum of an interval to the value of a rate.
+    rate_scale: f64,
+    /// End of the current sample interval, once the first increment arrived.
+    next_sample: Option<u64>,
+    /// Sum of increments in the current interval.
+    acc: f64,
+    total: f64,
+    window: VecDeque<f64>,
+    last_value: Option<f64>,
+}
+
+impl DerivedCounter {
+    pub fn new(kind: DerivedCounterKind, interval: u64, per: u64) -> Self {
+        let window = match kind {
+            DerivedCounterKind::MovingAverage { samples } => VecDeque::with_capacity(samples),
+            _ => VecDeque::new(),
+        };
+        Self {
+            kind,
+            interval,
+            rate_scale: per as f64 / interval as f64,
+            next_sample: None,
+            acc: 0.0,
+            total: 0.0,
+            window,
+            last_value: None,
+        }
+    }
+
+    /// Add an increment at `ts`. All samples due must have been taken with
+    /// [`DerivedCounter::pop_sample`] first.
+    pub fn add(&mut self, ts: u64, increment: f64) {
+        if self.next_sample.is_none() {
+            // Align intervals to multiples of the interval:
+            self.next_sample = Some((ts / self.interval + 1) * self.interval);
+        }
+        self.acc += increment;
+    }
+
+    /// Complete the next sample interval that ended at or before `ts`,
+    /// returning the sample timestamp (end of the interval) and value if it
+    /// changed. Must be called until it returns `None` before every
+    /// [`DerivedCounter::add`].
+    pub fn pop_sample(&mut self, ts: u64) -> Option<(u64, f64)> {
+        loop {
+            let sample_ts = self.next_sample.filter(|next| *next <= ts)?;
+            let sum = std::mem::take(&mut self.acc);
+            let value = self.finish_interval(sum);
+
+            let mut next = sample_ts + self.interval;
+            if self.is_idle(sum) && next <= ts {
+                // Further empty intervals can't change the value, skip them:
+                next += (ts - next) / self.interval * self.interval;
+            }
+            self.next_sample = Some(next);
+
+            if self.last_value != Some(value) {
+                self.last_value = Some(value);
+                return Some((sample_ts, value));
+            }
+        }
+    }
+
+    /// Complete the interval still open, returning its sample (at the end of
+    /// the interval) if the value changed. No increments can be added
+    /// afterwards.
+    pub fn finish(&mut self) -> Option<(u64, f64)> {
+        let sample = self.pop_sample(self.next_sample?);
+        self.next_sample = None;
+        sample
+    }
+
+    fn finish_interval(&mut self, sum: f64) -> f64 {
+        self.total += sum;
+        match self.kind {
+            DerivedCounterKind::Rate { .. } => sum * self.rate_scale,
+            DerivedCounterKind::MovingAverage { samples } => {
+                if self.window.len() == samples {
+                    self.window.pop_front();
+                }
+                self.window.push_back(sum);
+                self.window.iter().sum::<f64>() / self.window.len() as f64
+            }
+            DerivedCounterKind::Cumulative => self.total,
+        }
+    }
+
+    /// Whether empty intervals following one with `sum` leave the value
+    /// unchanged.
+    fn is_idle(&self, sum: f64) -> bool {
+        sum == 0.0 && self.window.iter().all(|v| *v == 0.0)
+    }
+
+    /// State as written into a state file, see [`crate::state`].
+    pub fn state_tokens(&self) -> Vec<String> {
+        let window: Vec<f64> = self.window.iter().copied().collect();
+        vec![
+            self.kind.token(),
+            self.interval.token(),
+            self.rate_scale.token(),
+            self.next_sample.token(),
+            self.acc.token(),
+            self.total.token(),
+            window.token(),
+            self.last_value.token(),
+        ]
+    }
+
+    pub fn from_state_tokens(tokens: &[&str]) -> Result<Self, String> {
+        let [kind, interval, rate_scale, next_sample, acc, total, window, last_value] =
+            split_tokens(tokens)?;
+        Ok(Self {
+            kind: Token::parse_token(kind)?,
+            interval: Token::parse_token(interval)?,
+            rate_scale: Token::parse_token(rate_scale)?,
+            next_sample: Token::parse_token(next_sample)?,
+            acc: Token::parse_token(acc)?,
+            total: Token::parse_token(total)?,
+            window: Vec::<f64>::parse_token(window)?.into(),
+            last_value: Token::parse_token(last_value)?,
+        })
+    }
+}
+
+impl Token for DerivedCounterKind {
+    fn token(&self) -> String {
+        match self {
+            DerivedCounterKind::Rate { per } => format!("rate={}", per.token()),
+            DerivedCounterKind::MovingAverage { samples } => format!("avg={samples}"),
+            DerivedCounterKind::Cumulative => "cumulative".to_string(),
+        }
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some(("rate", per)) => Ok(DerivedCounterKind::Rate {
+                per: Token::parse_token(per)?,
+            }),
+            Some(("avg", samples)) => Ok(DerivedCounterKind::MovingAverage {
+                samples: Token::parse_token(samples)?,
+            }),
+            None if s == "cumulative" => Ok(DerivedCounterKind::Cumulative),
+            _ => Err(format!("invalid derived counter kind '{s}'")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(counter: &mut DerivedCounter, events: &[(u64, f64)], end: u64) -> Vec<(u64, f64)> {
+        let mut samples = vec![];
+        for (ts, increment) in events {
+            while let Some(sample) = counter.pop_sample(*ts) {
+                samples.push(sample);
+            }
+            counter.add(*ts, *increment);
+        }
+        while let Some(sample) = counter.pop_sample(end) {
+            samples.push(sample);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut counter = DerivedCounter::new(DerivedCounterKind::Rate { per: 1.0 }, 10, 1);
+        let samples = run(
+            &mut counter,
+            &[(1, 4.0), (5, 6.0), (12, 2.0), (45, 5.0)],
+            1000,
+        );
+        assert_eq!(
+            samples,
+            vec![(10, 1.0), (20, 0.2), (30, 0.0), (50, 0.5), (60, 0.0)]
+        );
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut counter = DerivedCounter::new(DerivedCounterKind::Cumulative, 10, 10);
+        assert_eq!(counter.finish(), None);
+        let samples = run(&mut counter, &[(1, 4.0), (12, 2.0), (15, 3.0)], 15);
+        assert_eq!(samples, vec![(10, 4.0)]);
+        // The increments of the open interval are emitted at its end:
+        assert_eq!(counter.finish(), Some((20, 9.0)));
+        assert_eq!(counter.finish(), None);
+    }
+
+    #[test]
+    fn test_state_tokens() {
+        let mut counter =
+            DerivedCounter::new(DerivedCounterKind::MovingAverage { samples: 2 }, 10, 10);
+        run(&mut counter, &[(1, 4.0), (12, 2.0), (15, 0.5)], 15);
+        let tokens = counter.state_tokens();
+        let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        let mut restored = DerivedCounter::from_state_tokens(&tokens).unwrap();
+
+        let events = [(25, 1.0), (38, 6.0)];
+        assert_eq!(
+            run(&mut restored, &events, 100),
+            run(&mut counter, &events, 100)
+        );
+        assert!(DerivedCounter::from_state_tokens(&tokens[1..]).is_err());
+    }
+}
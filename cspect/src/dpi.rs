@@ -1,12 +1,24 @@
 use synthetto::ChildOrder;
 
-use crate::{svdpi::svBit, Context, CounterValue, ReplacementBehaviour};
+use crate::{
+    exit_hooks, metadata,
+    svdpi::{self, svBit, svOpenArrayHandle},
+    Context, CounterSampling, CounterValue, DerivedCounterKind, FlushPolicy, HistogramMode,
+    ReplacementBehaviour,
+};
 use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
     ffi::{c_char, c_double, c_int, c_uint, c_ulonglong, c_void, CStr},
-    path::PathBuf,
+    ops::Deref,
+    path::{Path, PathBuf},
     ptr::null_mut,
     str::Utf8Error,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
 };
 
 // ==== UUID Vector Object =====================================================
@@ -80,16 +92,137 @@ pub extern "C" fn cspect_dpi_uuid_vec_delete(uuid_vec: *mut c_void) -> c_int {
 // ==== Context Object Management ==============================================
 
 // Type backing  cspect_ctx chandles
-type CtxCHandle = Mutex<Context>;
+//
+// DPI calls may originate from several threads (e.g. `verilator --threads`).
+// Each thread is given its own sequence (a fork of the initial context), so
+// that events can be recorded without contending on a single lock.
+//
+// Slice, counter, derived counter and histogram state is kept per sequence,
+// and trace processor keeps incremental counter state per sequence as well.
+// Each track is therefore pinned to the sequence that created it (or first
+// wrote to it), and all its events are written by that sequence, no matter
+// which thread they originate from.
+pub(crate) struct CtxCHandle {
+    id: u64,
+    sequences: Mutex<Vec<Arc<Mutex<Context>>>>,
+    track_sequences: RwLock<HashMap<u64, Arc<Mutex<Context>>>>,
+}
+
+static NEXT_CTX_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Sequence used by this thread, for each context (by id).
+    static THREAD_SEQUENCES: RefCell<HashMap<u64, Weak<Mutex<Context>>>> =
+        RefCell::new(HashMap::new());
+}
+
+impl CtxCHandle {
+    fn new(ctx: Context) -> Self {
+        let id = NEXT_CTX_ID.fetch_add(1, Ordering::Relaxed);
+        // Tracks with resumed state stay with the initial sequence holding it:
+        let resumed_tracks: Vec<u64> = ctx.stateful_tracks().collect();
+        let ctx = Arc::new(Mutex::new(ctx));
+        THREAD_SEQUENCES.with_borrow_mut(|seqs| seqs.insert(id, Arc::downgrade(&ctx)));
+        let track_sequences = resumed_tracks
+            .into_iter()
+            .map(|uuid| (uuid, ctx.clone()))
+            .collect();
+        CtxCHandle {
+            id,
+            sequences: Mutex::new(vec![ctx]),
+            track_sequences: RwLock::new(track_sequences),
+        }
+    }
+
+    /// Sequence of the calling thread, creating it if required.
+    fn thread_sequence(&self) -> Arc<Mutex<Context>> {
+        THREAD_SEQUENCES.with_borrow_mut(|seqs| {
+            if let Some(seq) = seqs.get(&self.id).and_then(Weak::upgrade) {
+                return seq;
+            }
+            let mut sequences = self.sequences.lock().unwrap();
+            let seq = Arc::new(Mutex::new(sequences[0].lock().unwrap().fork()));
+            sequences.push(seq.clone());
+            seqs.insert(self.id, Arc::downgrade(&seq));
+            seq
+        })
+    }
+
+    /// Run `f` with the sequence of the calling thread.
+    fn with_sequence<R>(&self, f: impl FnOnce(&mut Context) -> R) -> R {
+        let seq = self.thread_sequence();
+        let mut ctx = seq.lock().unwrap();
+        f(&mut ctx)
+    }
+
+    /// Run `f`, which creates a track and returns its UUID (0 on failure),
+    /// with the sequence of the calling thread, pinning the track to it.
+    fn with_new_track(&self, f: impl FnOnce(&mut Context) -> u64) -> u64 {
+        let seq = self.thread_sequence();
+        let uuid = f(&mut seq.lock().unwrap());
+        if uuid != 0 {
+            self.track_sequences.write().unwrap().insert(uuid, seq);
+        }
+        uuid
+    }
+
+    /// Run `f` with the sequence `track_uuid` is pinned to, pinning it to the
+    /// sequence of the calling thread if it is not yet.
+    fn with_track_sequence<R>(&self, track_uuid: u64, f: impl FnOnce(&mut Context) -> R) -> R {
+        let pinned = self
+            .track_sequences
+            .read()
+            .unwrap()
+            .get(&track_uuid)
+            .cloned();
+        let seq = match pinned {
+            Some(seq) => seq,
+            None => self
+                .track_sequences
+                .write()
+                .unwrap()
+                .entry(track_uuid)
+                .or_insert_with(|| self.thread_sequence())
+                .clone(),
+        };
+        let mut ctx = seq.lock().unwrap();
+        f(&mut ctx)
+    }
+
+    /// Run `f` for every sequence of this context.
+    fn for_each_sequence(
+        &self,
+        mut f: impl FnMut(&mut Context) -> Result<(), String>,
+    ) -> Result<(), String> {
+        for seq in self.sequences.lock().unwrap().iter() {
+            f(&mut seq.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Flush all sequences without blocking on any lock.
+    pub(crate) fn try_flush(&self) {
+        let Ok(sequences) = self.sequences.try_lock() else {
+            return;
+        };
+        for seq in sequences.iter() {
+            if let Ok(mut ctx) = seq.try_lock() {
+                let _ = ctx.try_flush();
+            }
+        }
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn cspect_dpi_new(
     trace_path: *const c_char,
     timescale: c_double,
     time_mult: c_uint,
+    exit_hooks: svBit,
+    metadata: *const c_char,
 ) -> *mut c_void {
-    match cspect_new(trace_path, timescale, time_mult) {
-        Ok(ctx) => Box::into_raw(ctx) as *mut c_void,
+    match cspect_new(trace_path, timescale, time_mult, metadata) {
+        Ok(ctx) => into_ctx_chandle(ctx, recover_bool(exit_hooks)),
         Err(e) => {
             println!("cspect: {}", e);
             null_mut()
@@ -101,14 +234,57 @@ fn cspect_new(
     trace_path: *const c_char,
     timescale: c_double,
     time_mult: c_uint,
+    metadata: *const c_char,
+) -> Result<Box<CtxCHandle>, String> {
+    let trace_path = unsafe { recover_cstr(trace_path)? };
+    let trace_path = PathBuf::from(trace_path);
+    let metadata = unsafe { recover_optional_cstr(metadata)? };
+    let metadata = metadata::parse_metadata(metadata.unwrap_or_default())?;
+    let mut ctx = Context::new(trace_path, timescale, time_mult)?;
+    if !metadata.is_empty() {
+        ctx.add_metadata(metadata)?;
+    }
+    Ok(Box::new(CtxCHandle::new(ctx)))
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_resume(
+    trace_path: *const c_char,
+    state_path: *const c_char,
+    exit_hooks: svBit,
+) -> *mut c_void {
+    match cspect_resume(trace_path, state_path) {
+        Ok(ctx) => into_ctx_chandle(ctx, recover_bool(exit_hooks)),
+        Err(e) => {
+            println!("cspect: {}", e);
+            null_mut()
+        }
+    }
+}
+
+fn cspect_resume(
+    trace_path: *const c_char,
+    state_path: *const c_char,
 ) -> Result<Box<CtxCHandle>, String> {
     let trace_path = unsafe { recover_cstr(trace_path)? };
     let trace_path = PathBuf::from(trace_path);
-    let ctx: Box<CtxCHandle> =
-        Box::new(Mutex::new(Context::new(trace_path, timescale, time_mult)?));
+    let state_path = unsafe { recover_cstr(state_path)? };
+    let ctx: Box<CtxCHandle> = Box::new(CtxCHandle::new(Context::resume(
+        trace_path,
+        Path::new(state_path),
+    )?));
     Ok(ctx)
 }
 
+fn into_ctx_chandle(ctx: Box<CtxCHandle>, exit_hooks: bool) -> *mut c_void {
+    let ctx = Box::into_raw(ctx);
+    if exit_hooks {
+        // Safety: Unregistered in `cspect_dpi_finish` before being freed.
+        unsafe { exit_hooks::register(ctx) };
+    }
+    ctx as *mut c_void
+}
+
 #[no_mangle]
 pub extern "C" fn cspect_dpi_finish(cspect_ctx: *mut c_void) -> c_int {
     // Re-introduce chandle objects into the rust memory model.
@@ -116,7 +292,8 @@ pub extern "C" fn cspect_dpi_finish(cspect_ctx: *mut c_void) -> c_int {
         println!("cspect: cspect_ctx is nullptr!");
         return 1;
     }
-    let cspect_ctx: Box<Mutex<Context>> = unsafe { Box::from_raw(cspect_ctx as *mut CtxCHandle) };
+    exit_hooks::unregister(cspect_ctx as *const CtxCHandle);
+    let cspect_ctx: Box<CtxCHandle> = unsafe { Box::from_raw(cspect_ctx as *mut CtxCHandle) };
 
     // Since this function also deletes the context, we don't have to
     // re-leak the context.
@@ -129,9 +306,8 @@ pub extern "C" fn cspect_dpi_finish(cspect_ctx: *mut c_void) -> c_int {
     }
 }
 
-fn cspect_finish(ctx: &Mutex<Context>) -> Result<(), String> {
-    let mut ctx = ctx.lock().unwrap();
-    ctx.flush()
+fn cspect_finish(ctx: &CtxCHandle) -> Result<(), String> {
+    ctx.for_each_sequence(|ctx| ctx.flush())
 }
 
 // ==== Object Functions =======================================================
@@ -146,19 +322,48 @@ macro_rules! object_function_body_err_ret {
             println!("cspect: cspect_ctx is nullptr!");
             return 1;
         }
-        let cspect_ctx: Box<Mutex<Context>> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
+        let cspect_ctx: Box<CtxCHandle> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
 
-        // Lock + call actual function:
-        let result = {
-          let mut ctx = cspect_ctx.lock().unwrap();
-          match $func(&mut ctx $(, $arg)*) {
+        // Lock this thread's sequence + call actual function:
+        let result = cspect_ctx.with_sequence(|ctx| {
+          match $func(ctx $(, $arg)*) {
               Ok(_) => 0,
               Err(e) => {
                   println!("cspect: {}", e);
                   1
               }
           }
-        };
+        });
+
+        // Re-leak since we don't own the memory.
+        let _ = Box::into_raw(cspect_ctx);
+        result
+    }}
+}
+
+// DPI wrapper function body for functions with return type Result<(), String>
+// that write to the track given as first argument.
+// Note: Only generates function body. Generating the whole function would
+// easily be possibly but confuses cbindgen.
+macro_rules! track_function_body_err_ret {
+    ($func:ident, $ctx:ident, $track_uuid:ident $(, $arg:expr)* $(,)?) => {{
+        // Re-introduce chandle objects into the rust memory model.
+        if $ctx.is_null() {
+            println!("cspect: cspect_ctx is nullptr!");
+            return 1;
+        }
+        let cspect_ctx: Box<CtxCHandle> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
+
+        // Lock the track's sequence + call actual function:
+        let result = cspect_ctx.with_track_sequence($track_uuid, |ctx| {
+          match $func(ctx, $track_uuid $(, $arg)*) {
+              Ok(_) => 0,
+              Err(e) => {
+                  println!("cspect: {}", e);
+                  1
+              }
+          }
+        });
 
         // Re-leak since we don't own the memory.
         let _ = Box::into_raw(cspect_ctx);
@@ -167,6 +372,7 @@ macro_rules! object_function_body_err_ret {
 }
 
 // DPI wrapper function body for functions with return type Result<u64, String>
+// creating a track, which is pinned to the calling thread's sequence.
 // Note: Only generates function body. Generating the whole function would
 // easily be possibly but confuses cbindgen.
 macro_rules! object_function_body_uuid_ret {
@@ -176,18 +382,45 @@ macro_rules! object_function_body_uuid_ret {
             println!("cspect: cspect_ctx is nullptr!");
             return 1;
         }
-        let cspect_ctx: Box<Mutex<Context>> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
+        let cspect_ctx: Box<CtxCHandle> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
 
-        // Lock + call actual function:
-        let result = {
-          let mut ctx = cspect_ctx.lock().unwrap();
-          match $func(&mut ctx $(, $arg)*) {
+        // Lock this thread's sequence + call actual function:
+        let result = cspect_ctx.with_new_track(|ctx| {
+          match $func(ctx $(, $arg)*) {
               Ok(v) => v,
               Err(e) => {
                   println!("cspect: {}", e);
                   0
               }
           }
+        });
+
+        // Re-leak since we don't own the memory.
+        let _ = Box::into_raw(cspect_ctx);
+        result
+    }}
+}
+
+// DPI wrapper function body for functions with return type Result<(), String>
+// that operate on all sequences of a context.
+// Note: Only generates function body. Generating the whole function would
+// easily be possibly but confuses cbindgen.
+macro_rules! handle_function_body_err_ret {
+    ($func:ident, $ctx:ident $(, $arg:expr)* $(,)?) => {{
+        // Re-introduce chandle objects into the rust memory model.
+        if $ctx.is_null() {
+            println!("cspect: cspect_ctx is nullptr!");
+            return 1;
+        }
+        let cspect_ctx: Box<CtxCHandle> = unsafe { Box::from_raw($ctx as *mut CtxCHandle) };
+
+        // Call actual function:
+        let result = match $func(&cspect_ctx $(, $arg)*) {
+            Ok(_) => 0,
+            Err(e) => {
+                println!("cspect: {}", e);
+                1
+            }
         };
 
         // Re-leak since we don't own the memory.
@@ -198,20 +431,125 @@ macro_rules! object_function_body_uuid_ret {
 
 #[no_mangle]
 pub extern "C" fn cspect_dpi_flush(cspect_ctx: *mut c_void) -> c_int {
-    object_function_body_err_ret!(cspect_flush, cspect_ctx)
+    handle_function_body_err_ret!(cspect_flush, cspect_ctx)
 }
 
-fn cspect_flush(ctx: &mut Context) -> Result<(), String> {
-    ctx.flush()
+fn cspect_flush(ctx: &CtxCHandle) -> Result<(), String> {
+    ctx.for_each_sequence(|ctx| ctx.flush())
 }
 
 #[no_mangle]
-pub extern "C" fn cspect_dpi_new_uuid(cspect_ctx: *mut c_void) -> c_ulonglong {
-    object_function_body_uuid_ret!(cspect_new_uuid, cspect_ctx)
+pub extern "C" fn cspect_dpi_set_flush_policy(
+    cspect_ctx: *mut c_void,
+    every_events: c_ulonglong,
+    every_bytes: c_ulonglong,
+    every_time: c_double,
+) -> c_int {
+    handle_function_body_err_ret!(
+        cspect_set_flush_policy,
+        cspect_ctx,
+        every_events,
+        every_bytes,
+        every_time
+    )
 }
 
-fn cspect_new_uuid(ctx: &mut Context) -> Result<u64, String> {
-    Ok(ctx.new_uuid())
+fn cspect_set_flush_policy(
+    ctx: &CtxCHandle,
+    every_events: c_ulonglong,
+    every_bytes: c_ulonglong,
+    every_time: c_double,
+) -> Result<(), String> {
+    let policy = FlushPolicy {
+        every_events: (every_events != 0).then_some(every_events),
+        every_bytes: (every_bytes != 0).then_some(every_bytes),
+        every_time: (every_time > 0.0).then_some(every_time),
+    };
+    ctx.for_each_sequence(|ctx| {
+        ctx.set_flush_policy(policy.clone());
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_save_state(
+    cspect_ctx: *mut c_void,
+    state_path: *const c_char,
+) -> c_int {
+    handle_function_body_err_ret!(cspect_save_state, cspect_ctx, state_path)
+}
+
+fn cspect_save_state(ctx: &CtxCHandle, state_path: *const c_char) -> Result<(), String> {
+    let state_path = unsafe { recover_cstr(state_path)? };
+    let sequences = ctx.sequences.lock().unwrap();
+    let mut locked: Vec<_> = sequences.iter().map(|seq| seq.lock().unwrap()).collect();
+    let (first, others) = locked.split_first_mut().expect("context has no sequence");
+    let mut others: Vec<&mut Context> = others.iter_mut().map(|ctx| &mut **ctx).collect();
+    first.save_state_merged(&mut others, Path::new(state_path))
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_add_metadata(
+    cspect_ctx: *mut c_void,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    object_function_body_err_ret!(cspect_add_metadata, cspect_ctx, key, value)
+}
+
+fn cspect_add_metadata(
+    ctx: &mut Context,
+    key: *const c_char,
+    value: *const c_char,
+) -> Result<(), String> {
+    let key = unsafe { recover_cstr(key)?.to_string() };
+    let value = unsafe { recover_cstr(value)?.to_string() };
+    ctx.add_metadata(vec![(key, value)])
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_omit_volatile_metadata(cspect_ctx: *mut c_void) -> c_int {
+    object_function_body_err_ret!(cspect_omit_volatile_metadata, cspect_ctx)
+}
+
+fn cspect_omit_volatile_metadata(ctx: &mut Context) -> Result<(), String> {
+    ctx.omit_volatile_metadata();
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_set_uuid_salt(cspect_ctx: *mut c_void, salt: *const c_char) -> c_int {
+    object_function_body_err_ret!(cspect_set_uuid_salt, cspect_ctx, salt)
+}
+
+fn cspect_set_uuid_salt(ctx: &mut Context, salt: *const c_char) -> Result<(), String> {
+    let salt = unsafe { recover_cstr(salt)? };
+    ctx.set_uuid_salt(salt);
+    Ok(())
+}
+
+/// Set the packet sequence ID of the calling thread's sequence.
+#[no_mangle]
+pub extern "C" fn cspect_dpi_set_sequence_id(
+    cspect_ctx: *mut c_void,
+    sequence_id: c_uint,
+) -> c_int {
+    object_function_body_err_ret!(cspect_set_sequence_id, cspect_ctx, sequence_id)
+}
+
+fn cspect_set_sequence_id(ctx: &mut Context, sequence_id: c_uint) -> Result<(), String> {
+    ctx.set_sequence_id(sequence_id)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_new_uuid(cspect_ctx: *mut c_void) -> c_ulonglong {
+    // Not a track, so not pinned to a sequence:
+    if cspect_ctx.is_null() {
+        println!("cspect: cspect_ctx is nullptr!");
+        return 1;
+    }
+    let cspect_ctx = unsafe { &*(cspect_ctx as *const CtxCHandle) };
+    cspect_ctx.with_sequence(|ctx| ctx.new_uuid())
 }
 
 #[no_mangle]
@@ -275,7 +613,7 @@ pub extern "C" fn cspect_dpi_slice_begin(
     replacement_behaviour: c_int,
     correlation_id: c_ulonglong,
 ) -> c_int {
-    object_function_body_err_ret!(
+    track_function_body_err_ret!(
         cspect_slice_begin,
         cspect_ctx,
         parent_uuid,
@@ -316,7 +654,7 @@ fn cspect_slice_begin(
 ) -> Result<(), String> {
     let parent_uuid = recover_required_uuid(parent_uuid)?;
     let ts: f64 = ts;
-    let name = unsafe { recover_optional_cstr(name)?.map(String::from) };
+    let name = unsafe { recover_optional_cstr(name)? };
     let replace_behaviour = recover_replacement_behaviour(replacement_behaviour)?;
     let flows = recover_uuid_vec(flow0, flow1, flow2, flow3, flow_others);
     let flows_end = recover_uuid_vec(flow_end0, flow_end1, flow_end2, flow_end3, flow_end_others);
@@ -325,8 +663,8 @@ fn cspect_slice_begin(
         parent_uuid,
         ts,
         name,
-        flows,
-        flows_end,
+        &flows,
+        &flows_end,
         replace_behaviour,
         correlation_id,
     )
@@ -350,7 +688,7 @@ pub extern "C" fn cspect_dpi_slice_end(
     force: svBit,
     correlation_id: c_ulonglong,
 ) -> c_int {
-    object_function_body_err_ret!(
+    track_function_body_err_ret!(
         cspect_slice_end,
         cspect_ctx,
         parent_uuid,
@@ -393,7 +731,7 @@ fn cspect_slice_end(
     let flows_end = recover_uuid_vec(flow_end0, flow_end1, flow_end2, flow_end3, flow_end_others);
     let force = recover_bool(force);
     let correlation_id = recover_optional_uuid(correlation_id);
-    ctx.slice_end_evt(parent_uuid, ts, flows, flows_end, force, correlation_id)
+    ctx.slice_end_evt(parent_uuid, ts, &flows, &flows_end, force, correlation_id)
 }
 
 #[no_mangle]
@@ -414,7 +752,7 @@ pub extern "C" fn cspect_dpi_instant_evt(
     flow_end_others: *mut c_void,
     correlation_id: c_ulonglong,
 ) -> c_int {
-    object_function_body_err_ret!(
+    track_function_body_err_ret!(
         cspect_instant_evt,
         cspect_ctx,
         parent_uuid,
@@ -453,11 +791,143 @@ fn cspect_instant_evt(
 ) -> Result<(), String> {
     let parent_uuid = recover_required_uuid(parent_uuid)?;
     let ts: f64 = ts;
-    let name = unsafe { recover_optional_cstr(name)?.map(String::from) };
+    let name = unsafe { recover_optional_cstr(name)? };
     let flows = recover_uuid_vec(flow0, flow1, flow2, flow3, flow_others);
     let flows_end = recover_uuid_vec(flow_end0, flow_end1, flow_end2, flow_end3, flow_end_others);
     let correlation_id = recover_optional_uuid(correlation_id);
-    ctx.instant_evt(parent_uuid, ts, name, flows, flows_end, correlation_id)
+    ctx.instant_evt(parent_uuid, ts, name, &flows, &flows_end, correlation_id)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_slice_begin_arr(
+    cspect_ctx: *mut c_void,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    name: *const c_char,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    replacement_behaviour: c_int,
+    correlation_id: c_ulonglong,
+) -> c_int {
+    track_function_body_err_ret!(
+        cspect_slice_begin_arr,
+        cspect_ctx,
+        parent_uuid,
+        ts,
+        name,
+        flows,
+        flows_end,
+        replacement_behaviour,
+        correlation_id,
+    )
+}
+
+fn cspect_slice_begin_arr(
+    ctx: &mut Context,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    name: *const c_char,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    replacement_behaviour: c_int,
+    correlation_id: c_ulonglong,
+) -> Result<(), String> {
+    let parent_uuid = recover_required_uuid(parent_uuid)?;
+    let ts: f64 = ts;
+    let name = unsafe { recover_optional_cstr(name)? };
+    let replace_behaviour = recover_replacement_behaviour(replacement_behaviour)?;
+    let flows = unsafe { recover_uuid_open_array(flows)? };
+    let flows_end = unsafe { recover_uuid_open_array(flows_end)? };
+    let correlation_id = recover_optional_uuid(correlation_id);
+    ctx.slice_begin_evt(
+        parent_uuid,
+        ts,
+        name,
+        &flows,
+        &flows_end,
+        replace_behaviour,
+        correlation_id,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_slice_end_arr(
+    cspect_ctx: *mut c_void,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    force: svBit,
+    correlation_id: c_ulonglong,
+) -> c_int {
+    track_function_body_err_ret!(
+        cspect_slice_end_arr,
+        cspect_ctx,
+        parent_uuid,
+        ts,
+        flows,
+        flows_end,
+        force,
+        correlation_id,
+    )
+}
+
+fn cspect_slice_end_arr(
+    ctx: &mut Context,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    force: svBit,
+    correlation_id: c_ulonglong,
+) -> Result<(), String> {
+    let parent_uuid = recover_required_uuid(parent_uuid)?;
+    let ts: f64 = ts;
+    let flows = unsafe { recover_uuid_open_array(flows)? };
+    let flows_end = unsafe { recover_uuid_open_array(flows_end)? };
+    let force = recover_bool(force);
+    let correlation_id = recover_optional_uuid(correlation_id);
+    ctx.slice_end_evt(parent_uuid, ts, &flows, &flows_end, force, correlation_id)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_instant_evt_arr(
+    cspect_ctx: *mut c_void,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    name: *const c_char,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    correlation_id: c_ulonglong,
+) -> c_int {
+    track_function_body_err_ret!(
+        cspect_instant_evt_arr,
+        cspect_ctx,
+        parent_uuid,
+        ts,
+        name,
+        flows,
+        flows_end,
+        correlation_id,
+    )
+}
+
+fn cspect_instant_evt_arr(
+    ctx: &mut Context,
+    parent_uuid: c_ulonglong,
+    ts: c_double,
+    name: *const c_char,
+    flows: svOpenArrayHandle,
+    flows_end: svOpenArrayHandle,
+    correlation_id: c_ulonglong,
+) -> Result<(), String> {
+    let parent_uuid = recover_required_uuid(parent_uuid)?;
+    let ts: f64 = ts;
+    let name = unsafe { recover_optional_cstr(name)? };
+    let flows = unsafe { recover_uuid_open_array(flows)? };
+    let flows_end = unsafe { recover_uuid_open_array(flows_end)? };
+    let correlation_id = recover_optional_uuid(correlation_id);
+    ctx.instant_evt(parent_uuid, ts, name, &flows, &flows_end, correlation_id)
 }
 
 #[no_mangle]
@@ -620,7 +1090,7 @@ pub extern "C" fn cspect_dpi_int_counter_evt(
     val: c_ulonglong,
     compress: svBit,
 ) -> c_int {
-    object_function_body_err_ret!(
+    track_function_body_err_ret!(
         cspect_int_counter_evt,
         cspect_ctx,
         track_uuid,
@@ -652,7 +1122,7 @@ pub extern "C" fn cspect_dpi_float_counter_evt(
     val: c_double,
     compress: svBit,
 ) -> c_int {
-    object_function_body_err_ret!(
+    track_function_body_err_ret!(
         cspect_float_counter_evt,
         cspect_ctx,
         track_uuid,
@@ -676,6 +1146,215 @@ fn cspect_float_counter_evt(
     ctx.counter_evt(track_uuid, ts, val, compress)
 }
 
+#[no_mangle]
+pub extern "C" fn cspect_dpi_set_counter_sampling(
+    cspect_ctx: *mut c_void,
+    track_uuid: c_ulonglong,
+    deadband: c_double,
+    decimate: c_double,
+    min_interval: c_double,
+) -> c_int {
+    track_function_body_err_ret!(
+        cspect_set_counter_sampling,
+        cspect_ctx,
+        track_uuid,
+        deadband,
+        decimate,
+        min_interval
+    )
+}
+
+fn cspect_set_counter_sampling(
+    ctx: &mut Context,
+    track_uuid: c_ulonglong,
+    deadband: c_double,
+    decimate: c_double,
+    min_interval: c_double,
+) -> Result<(), String> {
+    let track_uuid = recover_required_uuid(track_uuid)?;
+    let sampling = CounterSampling {
+        deadband: (deadband > 0.0).then_some(deadband),
+        decimate: (decimate > 0.0).then_some(decimate),
+        min_interval: (min_interval > 0.0).then_some(min_interval),
+    };
+    ctx.set_counter_sampling(track_uuid, sampling)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_new_derived_counter(
+    cspect_ctx: *mut c_void,
+    name: *const c_char,
+    unit_name: *const c_char,
+    kind: c_int,
+    kind_param: c_double,
+    sample_interval: c_double,
+    parent_uuid: c_ulonglong,
+    description: *const c_char,
+    child_ordering: c_int,
+    child_order_rank: c_int,
+) -> c_ulonglong {
+    object_function_body_uuid_ret!(
+        cspect_new_derived_counter,
+        cspect_ctx,
+        name,
+        unit_name,
+        kind,
+        kind_param,
+        sample_interval,
+        parent_uuid,
+        description,
+        child_ordering,
+        child_order_rank
+    )
+}
+
+fn cspect_new_derived_counter(
+    ctx: &mut Context,
+    name: *const c_char,
+    unit_name: *const c_char,
+    kind: c_int,
+    kind_param: c_double,
+    sample_interval: c_double,
+    parent_uuid: c_ulonglong,
+    description: *const c_char,
+    child_ordering: c_int,
+    child_order_rank: c_int,
+) -> Result<u64, String> {
+    let name = unsafe { recover_cstr(name)?.to_string() };
+    let unit_name = unsafe { recover_optional_cstr(unit_name)?.map(String::from) };
+    let kind = recover_derived_counter_kind(kind, kind_param)?;
+    let parent_uuid = recover_optional_uuid(parent_uuid);
+    let description = unsafe { recover_optional_cstr(description)?.map(String::from) };
+    let child_ordering = recover_child_ordering(child_ordering)?;
+    let child_order_rank = recover_optional_i32(child_order_rank);
+
+    ctx.new_derived_counter(
+        name,
+        unit_name,
+        kind,
+        sample_interval,
+        parent_uuid,
+        description,
+        child_ordering,
+        child_order_rank,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_derived_counter_evt(
+    cspect_ctx: *mut c_void,
+    track_uuid: c_ulonglong,
+    ts: c_double,
+    increment: c_double,
+) -> c_int {
+    track_function_body_err_ret!(
+        cspect_derived_counter_evt,
+        cspect_ctx,
+        track_uuid,
+        ts,
+        increment
+    )
+}
+
+fn cspect_derived_counter_evt(
+    ctx: &mut Context,
+    track_uuid: c_ulonglong,
+    ts: c_double,
+    increment: c_double,
+) -> Result<(), String> {
+    let track_uuid = recover_required_uuid(track_uuid)?;
+    let ts = ctx.convert_ts(ts);
+    ctx.derived_counter_evt(track_uuid, ts, increment)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_new_histogram(
+    cspect_ctx: *mut c_void,
+    name: *const c_char,
+    bounds: svOpenArrayHandle,
+    snapshot_interval: c_double,
+    parent_uuid: c_ulonglong,
+    description: *const c_char,
+    child_order_rank: c_int,
+) -> c_ulonglong {
+    object_function_body_uuid_ret!(
+        cspect_new_histogram,
+        cspect_ctx,
+        name,
+        bounds,
+        snapshot_interval,
+        parent_uuid,
+        description,
+        child_order_rank
+    )
+}
+
+fn cspect_new_histogram(
+    ctx: &mut Context,
+    name: *const c_char,
+    bounds: svOpenArrayHandle,
+    snapshot_interval: c_double,
+    parent_uuid: c_ulonglong,
+    description: *const c_char,
+    child_order_rank: c_int,
+) -> Result<u64, String> {
+    let name = unsafe { recover_cstr(name)?.to_string() };
+    let bounds = unsafe { recover_open_array::<f64>(bounds)? }.into_owned();
+    let mode = if snapshot_interval > 0.0 {
+        HistogramMode::Snapshot {
+            interval: snapshot_interval,
+        }
+    } else {
+        HistogramMode::Buckets
+    };
+    let parent_uuid = recover_optional_uuid(parent_uuid);
+    let description = unsafe { recover_optional_cstr(description)?.map(String::from) };
+    let child_order_rank = recover_optional_i32(child_order_rank);
+
+    ctx.new_histogram(
+        name,
+        bounds,
+        mode,
+        parent_uuid,
+        description,
+        child_order_rank,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_histogram_record(
+    cspect_ctx: *mut c_void,
+    track_uuid: c_ulonglong,
+    ts: c_double,
+    value: c_double,
+) -> c_int {
+    track_function_body_err_ret!(cspect_histogram_record, cspect_ctx, track_uuid, ts, value)
+}
+
+fn cspect_histogram_record(
+    ctx: &mut Context,
+    track_uuid: c_ulonglong,
+    ts: c_double,
+    value: c_double,
+) -> Result<(), String> {
+    let track_uuid = recover_required_uuid(track_uuid)?;
+    let ts = ctx.convert_ts(ts);
+    ctx.histogram_record(track_uuid, ts, value)
+}
+
+#[no_mangle]
+pub extern "C" fn cspect_dpi_finish_histogram(
+    cspect_ctx: *mut c_void,
+    track_uuid: c_ulonglong,
+) -> c_int {
+    track_function_body_err_ret!(cspect_finish_histogram, cspect_ctx, track_uuid)
+}
+
+fn cspect_finish_histogram(ctx: &mut Context, track_uuid: c_ulonglong) -> Result<(), String> {
+    let track_uuid = recover_required_uuid(track_uuid)?;
+    ctx.finish_histogram(track_uuid)
+}
+
 // ==== Utils ==================================================================
 
 fn utf8err_to_str(e: Utf8Error) -> String {
@@ -729,35 +1408,110 @@ fn recover_optional_uuid(val: c_ulonglong) -> Option<u64> {
     }
 }
 
+/// UUIDs passed as up to four scalars, stored inline, and an optional UUID
+/// vector.
+enum UuidList {
+    Inline([u64; 4], usize),
+    Vec(Vec<u64>),
+}
+
+impl Deref for UuidList {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        match self {
+            UuidList::Inline(uuids, len) => &uuids[..*len],
+            UuidList::Vec(uuids) => uuids,
+        }
+    }
+}
+
 fn recover_uuid_vec(
     uuid0: c_ulonglong,
     uuid1: c_ulonglong,
     uuid2: c_ulonglong,
     uuid3: c_ulonglong,
     vec_handle: *mut c_void,
-) -> Vec<u64> {
-    let mut v = vec![];
-
+) -> UuidList {
+    let mut inline = [0; 4];
+    let mut len = 0;
     for uuid in [uuid0, uuid1, uuid2, uuid3] {
         if let Some(uuid) = recover_optional_uuid(uuid) {
-            v.push(uuid);
+            inline[len] = uuid;
+            len += 1;
         }
     }
 
-    if !vec_handle.is_null() {
-        let vec: Box<Mutex<Vec<u64>>> = unsafe { Box::from_raw(vec_handle as *mut UUIDVecCHandle) };
-        {
-            let vec = vec.lock().unwrap();
-            for val in vec.iter() {
-                v.push(*val);
-            }
-            drop(vec); // re-lock
-        }
-        // Don't keep ownership:
-        let _ = Box::into_raw(vec) as *mut c_void;
+    if vec_handle.is_null() {
+        return UuidList::Inline(inline, len);
     }
 
-    v
+    let mut v = inline[..len].to_vec();
+    let vec: Box<Mutex<Vec<u64>>> = unsafe { Box::from_raw(vec_handle as *mut UUIDVecCHandle) };
+    {
+        let vec = vec.lock().unwrap();
+        v.extend_from_slice(&vec);
+        drop(vec); // re-lock
+    }
+    // Don't keep ownership:
+    let _ = Box::into_raw(vec) as *mut c_void;
+
+    UuidList::Vec(v)
+}
+
+/// The (non-zero) UUIDs of a `longint unsigned` open array, borrowed from the
+/// simulator unless it has to be copied.
+///
+/// # Safety
+/// `arr` must be null or a valid open array handle of a one-dimensional
+/// `longint unsigned` array, that outlives the returned slice.
+unsafe fn recover_uuid_open_array<'a>(arr: svOpenArrayHandle) -> Result<Cow<'a, [u64]>, String> {
+    let uuids: Cow<[u64]> = unsafe { recover_open_array(arr)? };
+    if !uuids.contains(&0) {
+        return Ok(uuids);
+    }
+    Ok(uuids
+        .iter()
+        .copied()
+        .filter_map(recover_optional_uuid)
+        .collect())
+}
+
+/// The elements of an open array, borrowed from the simulator if they are
+/// stored contiguously. Fails if the simulator can't provide an element.
+///
+/// # Safety
+/// `arr` must be null or a valid open array handle of a one-dimensional array
+/// with elements of type `T` (e.g. `u64` for `longint unsigned`, `f64` for
+/// `real`), that outlives the returned slice.
+unsafe fn recover_open_array<'a, T: Copy>(arr: svOpenArrayHandle) -> Result<Cow<'a, [T]>, String> {
+    if arr.is_null() {
+        return Ok(Cow::Borrowed(&[]));
+    }
+
+    let size = unsafe { svdpi::svSize(arr, 1) };
+    if size <= 0 {
+        return Ok(Cow::Borrowed(&[]));
+    }
+
+    let data = unsafe { svdpi::svGetArrayPtr(arr) } as *const T;
+    if !data.is_null() {
+        // Array is stored contiguously in memory:
+        return Ok(Cow::Borrowed(unsafe {
+            std::slice::from_raw_parts(data, size as usize)
+        }));
+    }
+
+    let mut v = Vec::with_capacity(size as usize);
+    let low = unsafe { svdpi::svLow(arr, 1) };
+    for idx in low..low + size {
+        let elem = unsafe { svdpi::svGetArrElemPtr1(arr, idx) } as *const T;
+        if elem.is_null() {
+            return Err(format!("invalid open array - no element at index {idx}"));
+        }
+        v.push(unsafe { *elem });
+    }
+    Ok(Cow::Owned(v))
 }
 
 fn recover_child_ordering(child_order: c_int) -> Result<Option<ChildOrder>, String> {
@@ -770,6 +1524,20 @@ fn recover_child_ordering(child_order: c_int) -> Result<Option<ChildOrder>, Stri
     }
 }
 
+fn recover_derived_counter_kind(
+    kind: c_int,
+    kind_param: c_double,
+) -> Result<DerivedCounterKind, String> {
+    match kind {
+        0 => Ok(DerivedCounterKind::Rate { per: kind_param }),
+        1 => Ok(DerivedCounterKind::MovingAverage {
+            samples: kind_param as usize,
+        }),
+        2 => Ok(DerivedCounterKind::Cumulative),
+        i => Err(format!("invalid derived counter kind {i}")),
+    }
+}
+
 fn recover_replacement_behaviour(
     replacement_behaviour: c_int,
 ) -> Result<ReplacementBehaviour, String> {
@@ -780,3 +1548,133 @@ fn recover_replacement_behaviour(
         i => Err(format!("invalid replacement behaviour {i}")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, ptr::null, thread};
+
+    use synthetto::{trace_packet, track_event, TraceReader};
+
+    use super::*;
+
+    #[test]
+    fn test_recover_open_array() {
+        let mut arr = svdpi::MockOpenArray {
+            low: 2,
+            elems: vec![7, 0, 9],
+            contiguous: true,
+            missing: None,
+        };
+        let handle = &mut arr as *mut svdpi::MockOpenArray as svOpenArrayHandle;
+
+        let elems = unsafe { recover_open_array::<u64>(handle) }.unwrap();
+        assert!(matches!(elems, Cow::Borrowed(_)));
+        assert_eq!(*elems, [7, 0, 9]);
+        let uuids = unsafe { recover_uuid_open_array(handle) }.unwrap();
+        assert_eq!(*uuids, [7, 9]);
+
+        // Element by element:
+        arr.contiguous = false;
+        let handle = &mut arr as *mut svdpi::MockOpenArray as svOpenArrayHandle;
+        let elems = unsafe { recover_open_array::<u64>(handle) }.unwrap();
+        assert!(matches!(elems, Cow::Owned(_)));
+        assert_eq!(*elems, [7, 0, 9]);
+
+        arr.missing = Some(3);
+        let handle = &mut arr as *mut svdpi::MockOpenArray as svOpenArrayHandle;
+        assert_eq!(
+            unsafe { recover_uuid_open_array(handle) }.unwrap_err(),
+            "invalid open array - no element at index 3"
+        );
+
+        let empty = unsafe { recover_open_array::<u64>(null_mut()) }.unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_slice_across_threads() {
+        let path = std::env::temp_dir().join(format!("cspect-threads-{}", std::process::id()));
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let ctx = cspect_dpi_new(path_c.as_ptr(), 1e-9, 1, 0, null()) as usize;
+        assert_ne!(ctx, 0);
+
+        // Create the track on one thread, begin the slice on another and end it
+        // on a third one:
+        let track = thread::spawn(move || {
+            let name = CString::new("track").unwrap();
+            cspect_dpi_new_track(ctx as *mut c_void, name.as_ptr(), 0, null(), 0, 0)
+        })
+        .join()
+        .unwrap();
+        assert_ne!(track, 0);
+        let begin = thread::spawn(move || {
+            let name = CString::new("slice").unwrap();
+            cspect_dpi_slice_begin(
+                ctx as *mut c_void,
+                track,
+                10.0,
+                name.as_ptr(),
+                0,
+                0,
+                0,
+                0,
+                null_mut(),
+                0,
+                0,
+                0,
+                0,
+                null_mut(),
+                0,
+                0,
+            )
+        });
+        assert_eq!(begin.join().unwrap(), 0);
+        let end = thread::spawn(move || {
+            cspect_dpi_slice_end(
+                ctx as *mut c_void,
+                track,
+                20.0,
+                0,
+                0,
+                0,
+                0,
+                null_mut(),
+                0,
+                0,
+                0,
+                0,
+                null_mut(),
+                0,
+                0,
+            )
+        });
+        assert_eq!(end.join().unwrap(), 0);
+        assert_eq!(cspect_dpi_finish(ctx as *mut c_void), 0);
+
+        let mut events = vec![];
+        for packet in TraceReader::open(&path).unwrap() {
+            let packet = packet.unwrap().1;
+            let Some(trace_packet::Data::TrackEvent(evt)) = packet.data else {
+                continue;
+            };
+            if evt.track_uuid == Some(track) {
+                let Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    seq,
+                )) = packet.optional_trusted_packet_sequence_id
+                else {
+                    panic!("event without sequence ID");
+                };
+                events.push((evt.r#type(), packet.timestamp, seq));
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, track_event::Type::SliceBegin);
+        assert_eq!(events[1].0, track_event::Type::SliceEnd);
+        assert_eq!(events[0].1, Some(10));
+        assert_eq!(events[1].1, Some(20));
+        // Both written by the sequence of the track:
+        assert_eq!(events[0].2, events[1].2);
+    }
+}
@@ -0,0 +1,266 @@
+//! `atexit` and signal hooks that flush all live contexts, so that a simulation
+//! that is aborted (or never calls `finish`) still leaves a valid trace.
+//!
+//! Flushing takes locks and writes files, which must not happen inside a
+//! signal handler. The handler therefore only notifies a watcher thread
+//! through a pipe (the "self-pipe trick"), waits (bounded) for it to flush all
+//! contexts, and then passes the signal on to the previously installed
+//! handler.
+
+use std::sync::{Mutex, Once};
+
+use crate::dpi::CtxCHandle;
+
+// Addresses of all contexts that should be flushed on exit.
+static LIVE_CONTEXTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+static INSTALL: Once = Once::new();
+
+/// Register a context to be flushed on exit, installing the hooks if this has
+/// not happened yet.
+///
+/// # Safety
+/// `ctx` must remain valid until it is removed with [`unregister`].
+pub(crate) unsafe fn register(ctx: *const CtxCHandle) {
+    INSTALL.call_once(install);
+    LIVE_CONTEXTS.lock().unwrap().push(ctx as usize);
+}
+
+pub(crate) fn unregister(ctx: *const CtxCHandle) {
+    LIVE_CONTEXTS
+        .lock()
+        .unwrap()
+        .retain(|&addr| addr != ctx as usize);
+}
+
+fn install() {
+    #[cfg(unix)]
+    {
+        unsafe {
+            libc::atexit(on_exit);
+        }
+        signals::install();
+    }
+}
+
+#[cfg(unix)]
+fn flush_all() {
+    // Never block: A thread interrupted by a signal may hold one of these
+    // locks until the signal has been handled.
+    let Ok(live) = LIVE_CONTEXTS.try_lock() else {
+        return;
+    };
+    for &addr in live.iter() {
+        let ctx = unsafe { &*(addr as *const CtxCHandle) };
+        ctx.try_flush();
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_exit() {
+    flush_all();
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::{
+        ffi::{c_int, c_void},
+        mem::MaybeUninit,
+        ptr::null_mut,
+        sync::atomic::{AtomicI32, AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::flush_all;
+
+    const SIGNALS: [c_int; 4] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGABRT];
+
+    // How long the signal handler waits for the watcher thread to flush.
+    const FLUSH_TIMEOUT_MS: c_int = 5000;
+
+    // Handlers (and their `sa_flags`) that were installed before ours, indexed
+    // like `SIGNALS`. Atomics, so that the signal handler can read them.
+    static PREV_HANDLERS: [AtomicUsize; SIGNALS.len()] =
+        [const { AtomicUsize::new(libc::SIG_DFL) }; SIGNALS.len()];
+    static PREV_FLAGS: [AtomicI32; SIGNALS.len()] = [const { AtomicI32::new(0) }; SIGNALS.len()];
+
+    // Write end of the pipe notifying the watcher thread of a signal, and read
+    // end of the pipe on which it acknowledges having flushed.
+    static NOTIFY_FD: AtomicI32 = AtomicI32::new(-1);
+    static DONE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    unsafe fn pipe() -> Option<[c_int; 2]> {
+        let mut fds = [-1; 2];
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return None;
+        }
+        for fd in fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Some(fds)
+    }
+
+    pub(super) fn install() {
+        unsafe {
+            let (Some(notify), Some(done)) = (pipe(), pipe()) else {
+                println!("cspect: failed to create pipes - not flushing on signals");
+                return;
+            };
+            NOTIFY_FD.store(notify[1], Ordering::Relaxed);
+            DONE_FD.store(done[0], Ordering::Relaxed);
+            let (notify_rx, done_tx) = (notify[0], done[1]);
+
+            let spawned = thread::Builder::new()
+                .name("cspect-signals".into())
+                .spawn(move || watch(notify_rx, done_tx));
+            if spawned.is_err() {
+                println!("cspect: failed to start signal thread - not flushing on signals");
+                return;
+            }
+
+            for (i, &sig) in SIGNALS.iter().enumerate() {
+                let mut action: libc::sigaction = MaybeUninit::zeroed().assume_init();
+                action.sa_sigaction = on_signal
+                    as extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void)
+                    as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut prev: libc::sigaction = MaybeUninit::zeroed().assume_init();
+                if libc::sigaction(sig, &action, &mut prev) == 0 {
+                    PREV_HANDLERS[i].store(prev.sa_sigaction, Ordering::Relaxed);
+                    PREV_FLAGS[i].store(prev.sa_flags, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Flush all contexts whenever a signal is reported on `notify_rx`,
+    /// acknowledging it on `done_tx`.
+    fn watch(notify_rx: c_int, done_tx: c_int) {
+        unsafe {
+            // Signals must be handled by the simulation's threads, which this
+            // thread waits for:
+            let mut mask: libc::sigset_t = MaybeUninit::zeroed().assume_init();
+            libc::sigemptyset(&mut mask);
+            for sig in SIGNALS {
+                libc::sigaddset(&mut mask, sig);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &mask, null_mut());
+        }
+
+        let mut sig = 0u8;
+        loop {
+            let n = unsafe { libc::read(notify_rx, &mut sig as *mut u8 as *mut c_void, 1) };
+            if n == 0
+                || (n < 0
+                    && std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted)
+            {
+                return;
+            }
+            if n == 1 {
+                flush_all();
+                unsafe { libc::write(done_tx, &sig as *const u8 as *const c_void, 1) };
+            }
+        }
+    }
+
+    /// Signal handler, only using async-signal-safe functions.
+    extern "C" fn on_signal(sig: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+        unsafe {
+            let saved_errno = *errno();
+
+            // Have the watcher thread flush all contexts:
+            let byte = sig as u8;
+            let notify_fd = NOTIFY_FD.load(Ordering::Relaxed);
+            if libc::write(notify_fd, &byte as *const u8 as *const c_void, 1) == 1 {
+                let done_fd = DONE_FD.load(Ordering::Relaxed);
+                let mut pollfd = libc::pollfd {
+                    fd: done_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if libc::poll(&mut pollfd, 1, FLUSH_TIMEOUT_MS) == 1 {
+                    let mut ack = 0u8;
+                    libc::read(done_fd, &mut ack as *mut u8 as *mut c_void, 1);
+                }
+            }
+
+            let Some(i) = SIGNALS.iter().position(|s| *s == sig) else {
+                *errno() = saved_errno;
+                return;
+            };
+            let prev = PREV_HANDLERS[i].load(Ordering::Relaxed);
+            let prev_flags = PREV_FLAGS[i].load(Ordering::Relaxed);
+            if prev == libc::SIG_DFL {
+                // Restore default behaviour and re-raise to terminate as usual
+                // (the signal is delivered once this handler returns):
+                let mut action: libc::sigaction = MaybeUninit::zeroed().assume_init();
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(sig, &action, null_mut());
+                libc::raise(sig);
+            } else if prev != libc::SIG_IGN && prev != libc::SIG_ERR {
+                // Chain to the previously installed handler (e.g. the verilator
+                // harness' SIGINT handler requesting a graceful stop):
+                if prev_flags & libc::SA_SIGINFO != 0 {
+                    let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                        std::mem::transmute(prev);
+                    handler(sig, info, context);
+                } else {
+                    let handler: extern "C" fn(c_int) = std::mem::transmute(prev);
+                    handler(sig);
+                }
+            }
+
+            *errno() = saved_errno;
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn errno() -> *mut c_int {
+        libc::__errno_location()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    unsafe fn errno() -> *mut c_int {
+        libc::__error()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        ffi::{c_int, c_void},
+        mem::MaybeUninit,
+        ptr::null_mut,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    use super::*;
+
+    static CHAINED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn prev_handler(sig: c_int, info: *mut libc::siginfo_t, _context: *mut c_void) {
+        if !info.is_null() && unsafe { (*info).si_signo } == sig {
+            CHAINED_SIGNAL.store(sig, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_signal_chains_siginfo_handler() {
+        unsafe {
+            let mut action: libc::sigaction = MaybeUninit::zeroed().assume_init();
+            action.sa_sigaction = prev_handler
+                as extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void)
+                as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGHUP, &action, null_mut());
+
+            INSTALL.call_once(install);
+            libc::raise(libc::SIGHUP);
+        }
+        assert_eq!(CHAINED_SIGNAL.load(Ordering::SeqCst), libc::SIGHUP);
+    }
+}
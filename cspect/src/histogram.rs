@@ -0,0 +1,231 @@
+//! Histograms recording value distributions (e.g. latencies) into buckets.
+
+use synthetto::{debug_arg, protos::debug_annotation::Value, DebugAnnotation};
+
+use crate::state::{split_tokens, Token};
+
+/// How a histogram is written into the trace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistogramMode {
+    /// One counter track per bucket, updated whenever a value is recorded.
+    Buckets,
+    /// An instant event with the count of every bucket as arguments, emitted
+    /// every `interval` (in timescale units).
+    Snapshot { interval: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Histogram {
+    /// Upper (exclusive) bounds of all but the last bucket, ascending.
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    /// Counter track per bucket, if in [`HistogramMode::Buckets`] mode.
+    pub bucket_uuids: Vec<u64>,
+    /// Snapshot interval in trace timestamp units, if in
+    /// [`HistogramMode::Snapshot`] mode.
+    snapshot_interval: Option<u64>,
+    next_snapshot: Option<u64>,
+    pub last_ts: Option<u64>,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<f64>, snapshot_interval: Option<u64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            bucket_uuids: vec![],
+            snapshot_interval,
+            next_snapshot: None,
+            last_ts: None,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Names of all buckets, e.g. `[10, 20)`.
+    pub fn bucket_names(bounds: &[f64]) -> Vec<String> {
+        let (Some(first), Some(last)) = (bounds.first(), bounds.last()) else {
+            return vec!["all".to_string()];
+        };
+        let mut names = Vec::with_capacity(bounds.len() + 1);
+        names.push(format!("< {first}"));
+        for pair in bounds.windows(2) {
+            names.push(format!("[{}, {})", pair[0], pair[1]));
+        }
+        names.push(format!(">= {last}"));
+        names
+    }
+
+    /// Record a value, returning the index of its bucket and the bucket's new
+    /// count.
+    pub fn record(&mut self, ts: u64, value: f64) -> (usize, u64) {
+        if self.next_snapshot.is_none() {
+            if let Some(interval) = self.snapshot_interval {
+                self.next_snapshot = Some((ts / interval + 1) * interval);
+            }
+        }
+        self.last_ts = Some(self.last_ts.map_or(ts, |last| last.max(ts)));
+
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        let idx = self.bounds.partition_point(|b| *b <= value);
+        self.counts[idx] += 1;
+        (idx, self.counts[idx])
+    }
+
+    /// Timestamp of the next snapshot that is due at `ts`, if any.
+    pub fn pop_snapshot(&mut self, ts: u64) -> Option<u64> {
+        let (interval, next) = (self.snapshot_interval?, self.next_snapshot?);
+        if next > ts {
+            return None;
+        }
+        // Snapshots of intervals without new values would be identical:
+        self.next_snapshot = Some(next + (ts - next) / interval * interval + interval);
+        Some(next)
+    }
+
+    pub fn has_snapshots(&self) -> bool {
+        self.snapshot_interval.is_some()
+    }
+
+    /// Bucket counts as event arguments.
+    pub fn snapshot_args(&self) -> Vec<DebugAnnotation> {
+        Self::bucket_names(&self.bounds)
+            .into_iter()
+            .zip(&self.counts)
+            .map(|(name, count)| debug_arg(name, Value::UintValue(*count)))
+            .collect()
+    }
+
+    /// Count, min, max, mean, p50 and p99 as event arguments.
+    pub fn summary_args(&self) -> Vec<DebugAnnotation> {
+        let mut args = vec![debug_arg("count".into(), Value::UintValue(self.count))];
+        if self.count != 0 {
+            let stats = [
+                ("min", self.min),
+                ("max", self.max),
+                ("mean", self.sum / self.count as f64),
+                ("p50", self.quantile(0.5)),
+                ("p99", self.quantile(0.99)),
+            ];
+            args.extend(
+                stats
+                    .into_iter()
+                    .map(|(name, v)| debug_arg(name.into(), Value::DoubleValue(v))),
+            );
+        }
+        args
+    }
+
+    /// Estimate the `q` quantile by interpolating linearly within its bucket.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = q * self.count as f64;
+        let mut below = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            if *count == 0 || ((below + count) as f64) < rank {
+                below += count;
+                continue;
+            }
+            let lower = match idx {
+                0 => self.min,
+                idx => self.bounds[idx - 1].max(self.min),
+            };
+            let upper = self.bounds.get(idx).map_or(self.max, |b| b.min(self.max));
+            let frac = (rank - below as f64) / *count as f64;
+            return lower + (upper - lower) * frac;
+        }
+        self.max
+    }
+
+    /// State as written into a state file, see [`crate::state`].
+    pub fn state_tokens(&self) -> Vec<String> {
+        vec![
+            self.bounds.token(),
+            self.counts.token(),
+            self.bucket_uuids.token(),
+            self.snapshot_interval.token(),
+            self.next_snapshot.token(),
+            self.last_ts.token(),
+            self.count.token(),
+            self.sum.token(),
+            self.min.token(),
+            self.max.token(),
+        ]
+    }
+
+    pub fn from_state_tokens(tokens: &[&str]) -> Result<Self, String> {
+        let [bounds, counts, bucket_uuids, snapshot_interval, next_snapshot, last_ts, count, sum, min, max] =
+            split_tokens(tokens)?;
+        let histogram = Self {
+            bounds: Token::parse_token(bounds)?,
+            counts: Token::parse_token(counts)?,
+            bucket_uuids: Token::parse_token(bucket_uuids)?,
+            snapshot_interval: Token::parse_token(snapshot_interval)?,
+            next_snapshot: Token::parse_token(next_snapshot)?,
+            last_ts: Token::parse_token(last_ts)?,
+            count: Token::parse_token(count)?,
+            sum: Token::parse_token(sum)?,
+            min: Token::parse_token(min)?,
+            max: Token::parse_token(max)?,
+        };
+        if histogram.counts.len() != histogram.bounds.len() + 1 {
+            return Err("invalid histogram - bucket count does not match bounds".to_string());
+        }
+        Ok(histogram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        assert_eq!(
+            Histogram::bucket_names(&[10.0, 20.5]),
+            vec!["< 10", "[10, 20.5)", ">= 20.5"]
+        );
+
+        let mut hist = Histogram::new(vec![10.0, 20.0, 30.0], Some(100));
+        for (ts, v) in [(5, 2.0), (10, 12.0), (20, 14.0), (30, 16.0), (40, 18.0)] {
+            hist.record(ts, v);
+        }
+        assert_eq!(hist.counts, vec![1, 4, 0, 0]);
+        assert_eq!(hist.quantile(0.5), 13.0);
+        assert_eq!(hist.quantile(1.0), 18.0);
+
+        assert_eq!(hist.pop_snapshot(99), None);
+        assert_eq!(hist.pop_snapshot(350), Some(100));
+        assert_eq!(hist.pop_snapshot(350), None);
+        assert_eq!(hist.pop_snapshot(400), Some(400));
+    }
+
+    #[test]
+    fn test_state_tokens() {
+        let mut hist = Histogram::new(vec![10.0, 20.0], Some(100));
+        hist.record(5, 12.0);
+        let tokens = hist.state_tokens();
+        let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        let mut restored = Histogram::from_state_tokens(&tokens).unwrap();
+
+        for hist in [&mut hist, &mut restored] {
+            hist.record(150, -3.5);
+            assert_eq!(hist.pop_snapshot(150), Some(100));
+        }
+        assert_eq!(restored.counts, hist.counts);
+        assert_eq!(restored.summary_args(), hist.summary_args());
+
+        let mut tokens = tokens;
+        tokens[1] = "1,2";
+        assert!(Histogram::from_state_tokens(&tokens).is_err());
+    }
+}
@@ -2,21 +2,37 @@
 
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{self, BufWriter, Write},
-    path::PathBuf,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
+use derived::DerivedCounter;
+use histogram::Histogram;
+use metadata::TraceMetadata;
+use sampling::CounterSampler;
+use state::ContextState;
 use synthetto::{ChildOrder, Synthetto};
 
+mod derived;
 pub mod dpi;
+mod exit_hooks;
+mod histogram;
+mod metadata;
+mod sampling;
+mod state;
 mod svdpi;
 
+pub use derived::DerivedCounterKind;
+pub use histogram::HistogramMode;
+pub use sampling::CounterSampling;
+
 fn ioerr_to_str(e: io::Error) -> String {
     format!("Failed to write to file - {e}")
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct TrackSlice {
     name: Option<String>,
     flows: Vec<u64>,
@@ -27,23 +43,39 @@ impl TrackSlice {
         flows.sort_unstable();
         Self { name, flows }
     }
-}
 
-impl PartialEq for TrackSlice {
-    fn eq(&self, other: &Self) -> bool {
-        if self.name != other.name {
-            return false;
+    /// Re-initialize this slice, reusing its allocations.
+    fn set(&mut self, name: Option<&str>, flows: &[u64]) {
+        match (name, &mut self.name) {
+            (Some(name), Some(own)) => {
+                own.clear();
+                own.push_str(name);
+            }
+            (name, own) => *own = name.map(String::from),
         }
+        self.flows.clear();
+        self.flows.extend_from_slice(flows);
+        self.flows.sort_unstable();
+    }
 
-        if self.flows.len() != other.flows.len() {
+    /// Whether this slice has the given name and (unordered) flows.
+    fn matches(&self, name: Option<&str>, flows: &[u64]) -> bool {
+        if self.name.as_deref() != name || self.flows.len() != flows.len() {
             return false;
         }
+        if flows.is_sorted() {
+            return self.flows == flows;
+        }
+        let mut flows = flows.to_vec();
+        flows.sort_unstable();
+        self.flows == flows
+    }
+}
 
-        let mut self_flows = self.flows.clone();
-        let mut other_flows = other.flows.clone();
-        self_flows.sort_unstable();
-        other_flows.sort_unstable();
-        self_flows == other_flows
+impl PartialEq for TrackSlice {
+    fn eq(&self, other: &Self) -> bool {
+        // Flows are sorted on construction:
+        self.name == other.name && self.flows == other.flows
     }
 }
 
@@ -53,7 +85,7 @@ pub enum CounterValue {
     Float(f64),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct Counter {
     last_value: CounterValue,
 }
@@ -64,20 +96,93 @@ impl Counter {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 struct Track {
     active_slices: Vec<TrackSlice>,
+    /// Slices that ended, kept to be reused by the next slices.
+    ended_slices: Vec<TrackSlice>,
+}
+
+// Size at which a sequence hands its buffered packets to the trace writer.
+const SEQUENCE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// State shared between all sequences writing into the same trace file.
+#[derive(Debug)]
+struct Shared {
+    w: Mutex<BufWriter<File>>,
+    path: PathBuf,
+    synthetto: Mutex<Synthetto>,
+    metadata: Mutex<TraceMetadata>,
 }
 
+impl Shared {
+    fn new(f: File, path: PathBuf, synthetto: Synthetto) -> Arc<Self> {
+        Arc::new(Shared {
+            w: Mutex::new(BufWriter::new(f)),
+            path,
+            synthetto: Mutex::new(synthetto),
+            metadata: Mutex::new(TraceMetadata::default()),
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), String> {
+        self.w.lock().unwrap().write_all(data).map_err(ioerr_to_str)
+    }
+}
+
+/// A sequence of events written into a trace.
+///
+/// Events are buffered per context and only handed to the (shared) trace
+/// writer in large chunks, each context using its own packet sequence ID
+/// (allocated by the trace's [`Synthetto`], see
+/// [multiple writers](Synthetto#multiple-writers)).
+/// Additional contexts writing into the same trace can be created with
+/// [`Context::fork`], e.g. to give each simulator thread its own sequence
+/// without contending on a single lock.
+///
+/// Slice, counter, derived counter and histogram state is tracked per
+/// context (as is incremental counter state by trace processor), so each track
+/// must only be written from a single context. The DPI layer ensures this by
+/// writing all events of a track with the sequence that created it.
 #[derive(Debug)]
 pub struct Context {
-    w: BufWriter<File>,
-    synthetto: Synthetto,
+    shared: Arc<Shared>,
+    sequence_id: u32,
     timescale: f64,
     time_mult: u32,
     tracks: HashMap<u64, Track>,
     counters: HashMap<u64, Counter>,
+    derived_counters: HashMap<u64, DerivedCounter>,
+    counter_samplers: HashMap<u64, CounterSampler>,
+    histograms: HashMap<u64, Histogram>,
+    sample_buffer: Vec<(u64, CounterValue)>,
     encode_buffer: Vec<u8>,
+    sequence_buffer: Vec<u8>,
+    flush_policy: FlushPolicy,
+    flush_every_ts: Option<u64>,
+    flush_state: FlushState,
+}
+
+/// Controls when a [`Context`] flushes buffered events to the trace file.
+///
+/// Each criterion is independent - the trace is flushed as soon as any of
+/// them is met. With the default policy, the trace is only flushed explicitly
+/// (and when the context is dropped).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlushPolicy {
+    /// Flush after this many events have been written.
+    pub every_events: Option<u64>,
+    /// Flush after this many bytes have been written.
+    pub every_bytes: Option<u64>,
+    /// Flush once this much simulation time (in timescale units) has passed.
+    pub every_time: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+struct FlushState {
+    events: u64,
+    bytes: u64,
+    last_ts: Option<u64>,
 }
 
 pub enum ReplacementBehaviour {
@@ -87,20 +192,219 @@ pub enum ReplacementBehaviour {
 }
 
 impl Context {
+    /// Create a new trace, writing the cspect version, creation time,
+    /// hostname, command line and timescale as metadata (see
+    /// [`Context::omit_volatile_metadata`]).
     pub fn new(path: PathBuf, timescale: f64, time_mult: u32) -> Result<Self, String> {
-        let f = BufWriter::new(
-            File::create(&path).map_err(|e| format!("Failed to open trace file - {e}"))?,
-        );
+        let f = File::create(&path).map_err(|e| format!("Failed to open trace file - {e}"))?;
+        let shared = Shared::new(f, path, Synthetto::new());
+        {
+            let mut metadata = shared.metadata.lock().unwrap();
+            metadata.pending = metadata::default_metadata(timescale, time_mult);
+            metadata.volatile = metadata::volatile_metadata();
+        }
+        Ok(Self::new_sequence(shared, timescale, time_mult))
+    }
 
-        Ok(Context {
-            w: f,
-            synthetto: Synthetto::new(),
+    fn new_sequence(shared: Arc<Shared>, timescale: f64, time_mult: u32) -> Self {
+        let sequence_id = shared.synthetto.lock().unwrap().new_sequence_id();
+        Context {
+            shared,
+            sequence_id,
             timescale,
             time_mult,
             tracks: HashMap::new(),
             counters: HashMap::new(),
+            derived_counters: HashMap::new(),
+            counter_samplers: HashMap::new(),
+            histograms: HashMap::new(),
+            sample_buffer: Vec::new(),
             encode_buffer: Vec::with_capacity(64),
-        })
+            sequence_buffer: Vec::with_capacity(SEQUENCE_BUFFER_SIZE),
+            flush_policy: FlushPolicy::default(),
+            flush_every_ts: None,
+            flush_state: FlushState::default(),
+        }
+    }
+
+    /// Create a new context that writes into the same trace using its own
+    /// packet sequence, inheriting this context's configuration.
+    pub fn fork(&self) -> Self {
+        let mut ctx = Self::new_sequence(self.shared.clone(), self.timescale, self.time_mult);
+        ctx.flush_policy = self.flush_policy.clone();
+        ctx.flush_every_ts = self.flush_every_ts;
+        ctx
+    }
+
+    /// Tracks with slice, counter, derived counter, sampling or histogram
+    /// state, e.g. after resuming.
+    pub(crate) fn stateful_tracks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.tracks
+            .keys()
+            .chain(self.counters.keys())
+            .chain(self.derived_counters.keys())
+            .chain(self.counter_samplers.keys())
+            .chain(self.histograms.keys())
+            .copied()
+    }
+
+    pub fn sequence_id(&self) -> u32 {
+        self.sequence_id
+    }
+
+    /// Write all further events of this context with `sequence_id` instead of
+    /// the automatically allocated one, e.g. to coordinate IDs with other
+    /// tools writing into the same trace. The ID must not be used by any other
+    /// writer of the trace.
+    pub fn set_sequence_id(&mut self, sequence_id: u32) -> Result<(), String> {
+        if sequence_id == 0 {
+            return Err("Failed to set sequence ID - 0 is not a valid sequence ID".into());
+        }
+        self.sequence_id = sequence_id;
+        Ok(())
+    }
+
+    /// Re-open a trace from a state file previously written by
+    /// [`Context::save_state`], continuing the trace in append mode.
+    ///
+    /// If `path` is the trace the state was saved from, it is truncated back to
+    /// its length at the time of saving. Otherwise, that prefix of the saved
+    /// trace is copied into `path`, allowing multiple runs to be resumed from
+    /// the same state.
+    pub fn resume(path: PathBuf, state_path: &Path) -> Result<Self, String> {
+        let state = ContextState::read(state_path)?;
+
+        let same_file = match (path.canonicalize(), state.trace_path.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        };
+
+        let mut f = if same_file {
+            let f = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| format!("Failed to open trace file - {e}"))?;
+            let len = f
+                .metadata()
+                .map_err(|e| format!("Failed to open trace file - {e}"))?
+                .len();
+            if len < state.trace_len {
+                return Err(format!(
+                    "Failed to resume trace - trace file is shorter ({len} bytes) than saved state ({} bytes)",
+                    state.trace_len
+                ));
+            }
+            f.set_len(state.trace_len)
+                .map_err(|e| format!("Failed to truncate trace file - {e}"))?;
+            f
+        } else {
+            let mut src = File::open(&state.trace_path)
+                .map_err(|e| format!("Failed to open saved trace file - {e}"))?
+                .take(state.trace_len);
+            let mut f =
+                File::create(&path).map_err(|e| format!("Failed to open trace file - {e}"))?;
+            let copied = io::copy(&mut src, &mut f)
+                .map_err(|e| format!("Failed to copy saved trace file - {e}"))?;
+            if copied != state.trace_len {
+                return Err(format!(
+                    "Failed to resume trace - saved trace file is shorter ({copied} bytes) than saved state ({} bytes)",
+                    state.trace_len
+                ));
+            }
+            f
+        };
+        f.seek(io::SeekFrom::End(0))
+            .map_err(|e| format!("Failed to seek trace file - {e}"))?;
+
+        let mut synthetto = Synthetto::from_uuid_cnt(state.uuid_cnt);
+        if let Some(salt) = state.uuid_salt {
+            synthetto.restore_uuid_salt(salt, state.derived_uuids);
+        }
+        // Continue allocating sequence IDs, so that sequences after the resume
+        // point never reuse an ID from before:
+        if let Some((salt, cnt)) = state.sequence_ids {
+            synthetto.restore_sequence_ids(salt, cnt);
+            synthetto.set_sequence_id(state.descriptor_sequence_id);
+        }
+        let shared = Shared::new(f, path, synthetto);
+        shared.metadata.lock().unwrap().track = state.metadata_track;
+        let mut ctx = Self::new_sequence(shared, state.timescale, state.time_mult);
+        ctx.tracks = state.tracks;
+        ctx.counters = state.counters;
+        ctx.derived_counters = state.derived_counters;
+        ctx.counter_samplers = state.counter_samplers;
+        ctx.histograms = state.histograms;
+        Ok(ctx)
+    }
+
+    /// Flush the trace and write the state required to resume it to
+    /// `state_path`.
+    ///
+    /// Only the slice, counter, derived counter, sampling and histogram state
+    /// of this context is saved. Use [`Context::save_state_merged`] to include forked
+    /// contexts.
+    pub fn save_state(&mut self, state_path: &Path) -> Result<(), String> {
+        self.save_state_merged(&mut [], state_path)
+    }
+
+    /// Flush the trace and write the state required to resume it to
+    /// `state_path`, merging the state of all `others`
+    /// contexts (which must write into the same trace) into that of this
+    /// context.
+    pub fn save_state_merged(
+        &mut self,
+        others: &mut [&mut Context],
+        state_path: &Path,
+    ) -> Result<(), String> {
+        for other in others.iter_mut() {
+            if !Arc::ptr_eq(&self.shared, &other.shared) {
+                return Err("Failed to save state - contexts write to different traces".into());
+            }
+            other.flush()?;
+        }
+        self.flush()?;
+
+        let trace_len = self
+            .shared
+            .w
+            .lock()
+            .unwrap()
+            .get_mut()
+            .stream_position()
+            .map_err(|e| format!("Failed to query trace file position - {e}"))?;
+
+        let path = &self.shared.path;
+        let synthetto = self.shared.synthetto.lock().unwrap();
+        let mut state = ContextState {
+            timescale: self.timescale,
+            time_mult: self.time_mult,
+            uuid_cnt: synthetto.uuid_cnt(),
+            uuid_salt: synthetto.uuid_salt(),
+            derived_uuids: synthetto.derived_uuids().collect(),
+            sequence_ids: Some(synthetto.sequence_state()),
+            descriptor_sequence_id: synthetto.sequence_id(),
+            trace_path: path.canonicalize().unwrap_or(path.clone()),
+            trace_len,
+            metadata_track: self.shared.metadata.lock().unwrap().track,
+            tracks: self.tracks.clone(),
+            counters: self.counters.clone(),
+            derived_counters: self.derived_counters.clone(),
+            counter_samplers: self.counter_samplers.clone(),
+            histograms: self.histograms.clone(),
+        };
+        drop(synthetto);
+        for other in others.iter() {
+            state.tracks.extend(other.tracks.clone());
+            state.counters.extend(other.counters.clone());
+            state
+                .derived_counters
+                .extend(other.derived_counters.clone());
+            state
+                .counter_samplers
+                .extend(other.counter_samplers.clone());
+            state.histograms.extend(other.histograms.clone());
+        }
+        state.write(state_path)
     }
 
     fn convert_ts(&self, ts: f64) -> u64 {
@@ -120,14 +424,211 @@ impl Context {
         }
     }
 
+    /// Hand all buffered packets of this sequence to the trace writer.
+    fn write_sequence_buffer(&mut self) -> Result<(), String> {
+        if !self.sequence_buffer.is_empty() {
+            self.shared.write(&self.sequence_buffer)?;
+            self.sequence_buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Write the track descriptor in `encode_buffer` directly to the trace,
+    /// ensuring it precedes any event (in any sequence) that references it.
+    fn write_descriptor(&mut self) -> Result<(), String> {
+        self.write_sequence_buffer()?;
+        self.shared.write(&self.encode_buffer)?;
+        self.trim_encode_buffer();
+        Ok(())
+    }
+
+    /// Write the encoded packet in `encode_buffer` to the trace, flushing
+    /// afterwards if required by the flush policy.
+    fn write_encode_buffer(&mut self, ts: Option<u64>) -> Result<(), String> {
+        self.sequence_buffer.extend_from_slice(&self.encode_buffer);
+        if self.sequence_buffer.len() >= SEQUENCE_BUFFER_SIZE {
+            self.write_sequence_buffer()?;
+        }
+
+        self.flush_state.events += 1;
+        self.flush_state.bytes += self.encode_buffer.len() as u64;
+        self.trim_encode_buffer();
+
+        let policy = &self.flush_policy;
+        let mut do_flush = false;
+        if let Some(n) = policy.every_events {
+            do_flush |= self.flush_state.events >= n;
+        }
+        if let Some(n) = policy.every_bytes {
+            do_flush |= self.flush_state.bytes >= n;
+        }
+        if let (Some(n), Some(ts)) = (self.flush_every_ts, ts) {
+            match self.flush_state.last_ts {
+                Some(last_ts) => do_flush |= ts.saturating_sub(last_ts) >= n,
+                None => self.flush_state.last_ts = Some(ts),
+            }
+        }
+
+        if do_flush {
+            self.flush()?;
+            if let Some(ts) = ts {
+                self.flush_state.last_ts = Some(ts);
+            }
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<(), String> {
-        self.w
+        self.write_metadata()?;
+        self.flush_state.events = 0;
+        self.flush_state.bytes = 0;
+        self.write_sequence_buffer()?;
+        self.shared
+            .w
+            .lock()
+            .unwrap()
             .flush()
             .map_err(|e| format!("Failed to flush to trace file - {e}"))
     }
 
+    /// Flush like [`Context::flush`], but without blocking on locks shared
+    /// with other contexts (e.g. held by a thread interrupted by a signal).
+    /// Returns `Ok(false)` without writing anything if the trace file is
+    /// locked. Pending metadata is only written if that doesn't block either.
+    pub fn try_flush(&mut self) -> Result<bool, String> {
+        let shared = self.shared.clone();
+        let Ok(mut w) = shared.w.try_lock() else {
+            return Ok(false);
+        };
+        if let Ok(mut metadata) = shared.metadata.try_lock() {
+            let has_pending = !metadata.pending.is_empty() || !metadata.volatile.is_empty();
+            let track_uuid = match (metadata.track, shared.synthetto.try_lock()) {
+                (Some(uuid), _) => Some(uuid),
+                (None, Ok(mut synthetto)) if has_pending => {
+                    self.encode_buffer.clear();
+                    let uuid = synthetto
+                        .new_track(
+                            synthetto::METADATA_TRACK_NAME.to_string(),
+                            None,
+                            None,
+                            None,
+                            None,
+                            &mut self.encode_buffer,
+                        )
+                        .expect(
+                            "prost encode should only fail if buffer is too small, but buffer is vec",
+                        );
+                    // The descriptor must precede the events referencing it:
+                    w.write_all(&self.sequence_buffer).map_err(ioerr_to_str)?;
+                    self.sequence_buffer.clear();
+                    w.write_all(&self.encode_buffer).map_err(ioerr_to_str)?;
+                    metadata.track = Some(uuid);
+                    Some(uuid)
+                }
+                (None, _) => None,
+            };
+            if let Some(track_uuid) = track_uuid.filter(|_| has_pending) {
+                self.encode_buffer.clear();
+                synthetto::metadata_evt(
+                    track_uuid,
+                    metadata.take_pending(),
+                    self.sequence_id,
+                    &mut self.encode_buffer,
+                )
+                .expect("prost encode should only fail if buffer is too small, but buffer is vec");
+                self.sequence_buffer.extend_from_slice(&self.encode_buffer);
+            }
+            self.trim_encode_buffer();
+        }
+
+        self.flush_state.events = 0;
+        self.flush_state.bytes = 0;
+        w.write_all(&self.sequence_buffer).map_err(ioerr_to_str)?;
+        self.sequence_buffer.clear();
+        w.flush()
+            .map_err(|e| format!("Failed to flush to trace file - {e}"))?;
+        Ok(true)
+    }
+
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_every_ts = policy.every_time.map(|t| self.convert_ts(t));
+        self.flush_policy = policy;
+    }
+
+    /// Write key/value pairs (e.g. test name, seed, git revision) into the
+    /// metadata track of the trace, which can be printed with
+    /// `cspect metadata`. Entries are buffered until the trace is flushed for
+    /// the first time.
+    pub fn add_metadata(&mut self, entries: Vec<(String, String)>) -> Result<(), String> {
+        let written = {
+            let mut metadata = self.shared.metadata.lock().unwrap();
+            metadata.pending.extend(entries);
+            metadata.track.is_some()
+        };
+        if written {
+            self.write_metadata()?;
+        }
+        Ok(())
+    }
+
+    /// Don't write the creation time, hostname and command line into the
+    /// metadata track, so that traces of identical runs are identical. Only
+    /// has an effect before the trace is flushed for the first time, and is
+    /// implied by [`Context::set_uuid_salt`].
+    pub fn omit_volatile_metadata(&mut self) {
+        self.shared.metadata.lock().unwrap().volatile.clear();
+    }
+
+    /// Write all pending metadata, creating the metadata track if required.
+    fn write_metadata(&mut self) -> Result<(), String> {
+        let shared = self.shared.clone();
+        // Never block: Flushing on a signal must not wait for a thread that
+        // is writing metadata, which writes all pending entries anyway.
+        let Ok(mut metadata) = shared.metadata.try_lock() else {
+            return Ok(());
+        };
+        let entries = metadata.take_pending();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let track_uuid = match metadata.track {
+            Some(uuid) => uuid,
+            None => {
+                let name = synthetto::METADATA_TRACK_NAME.to_string();
+                let uuid = self.new_track(name, None, None, None, None)?;
+                metadata.track = Some(uuid);
+                uuid
+            }
+        };
+
+        self.encode_buffer.clear();
+        synthetto::metadata_evt(
+            track_uuid,
+            entries,
+            self.sequence_id,
+            &mut self.encode_buffer,
+        )
+        .expect("prost encode should only fail if buffer is too small, but buffer is vec");
+        self.write_encode_buffer(None)
+    }
+
+    /// Derive the UUIDs of all tracks created from now on from `salt` and
+    /// their path, making them stable across runs. Should be called before
+    /// any track is created. See [`Synthetto::set_uuid_salt`].
+    ///
+    /// Sequence IDs are derived from the salt as well, re-allocating the
+    /// sequence ID of this context. Volatile metadata is omitted, see
+    /// [`Context::omit_volatile_metadata`].
+    pub fn set_uuid_salt(&mut self, salt: &str) {
+        let mut synthetto = self.shared.synthetto.lock().unwrap();
+        synthetto.set_uuid_salt(salt);
+        self.sequence_id = synthetto.new_sequence_id();
+        drop(synthetto);
+        self.omit_volatile_metadata();
+    }
+
     pub fn new_uuid(&mut self) -> u64 {
-        self.synthetto.new_uuid()
+        self.shared.synthetto.lock().unwrap().new_uuid()
     }
 
     pub fn new_track(
@@ -141,7 +642,10 @@ impl Context {
         self.encode_buffer.clear();
 
         let uuid = self
+            .shared
             .synthetto
+            .lock()
+            .unwrap()
             .new_track(
                 name,
                 parent_uuid,
@@ -152,10 +656,7 @@ impl Context {
             )
             .expect("prost encode should only fail if buffer is too small, but buffer is vec");
 
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        self.write_descriptor()?;
 
         Ok(uuid)
     }
@@ -173,7 +674,10 @@ impl Context {
         self.encode_buffer.clear();
 
         let uuid = self
+            .shared
             .synthetto
+            .lock()
+            .unwrap()
             .new_process(
                 pid,
                 process_name,
@@ -186,10 +690,7 @@ impl Context {
             )
             .expect("prost encode should only fail if buffer is too small, but buffer is vec");
 
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        self.write_descriptor()?;
 
         Ok(uuid)
     }
@@ -206,7 +707,10 @@ impl Context {
         self.encode_buffer.clear();
 
         let uuid = self
+            .shared
             .synthetto
+            .lock()
+            .unwrap()
             .new_thread(
                 pid,
                 tid,
@@ -218,10 +722,7 @@ impl Context {
             )
             .expect("prost encode should only fail if buffer is too small, but buffer is vec");
 
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        self.write_descriptor()?;
 
         Ok(uuid)
     }
@@ -240,7 +741,10 @@ impl Context {
 
         let unit = synthetto::CounterTrackUnit::from_string(unit_name);
         let uuid = self
+            .shared
             .synthetto
+            .lock()
+            .unwrap()
             .new_counter(
                 name,
                 unit,
@@ -253,31 +757,218 @@ impl Context {
             )
             .expect("prost encode should only fail if buffer is too small, but buffer is vec");
 
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        self.write_descriptor()?;
+
+        Ok(uuid)
+    }
+
+    /// Create a counter track whose values are derived from the increments
+    /// logged with [`Context::derived_counter_evt`], sampled every
+    /// `sample_interval` (in timescale units).
+    ///
+    /// Each sample is emitted at the end of its interval, and only if its
+    /// value changed. The interval still open when the counter is finished
+    /// (at the latest when the context is dropped) is emitted as well.
+    pub fn new_derived_counter(
+        &mut self,
+        name: String,
+        unit_name: Option<String>,
+        kind: DerivedCounterKind,
+        sample_interval: f64,
+        parent_uuid: Option<u64>,
+        description: Option<String>,
+        child_ordering: Option<ChildOrder>,
+        sibling_order_rank: Option<i32>,
+    ) -> Result<u64, String> {
+        let interval = self.convert_ts(sample_interval);
+        if interval == 0 {
+            return Err(format!(
+                "Invalid sample interval {sample_interval} - must be at least 1ns"
+            ));
+        }
+        let per = match kind {
+            DerivedCounterKind::Rate { per } if self.convert_ts(per) == 0 => {
+                return Err(format!("Invalid rate unit {per} - must be at least 1ns"));
+            }
+            DerivedCounterKind::Rate { per } => self.convert_ts(per),
+            DerivedCounterKind::MovingAverage { samples: 0 } => {
+                return Err("Invalid moving average - must cover at least 1 sample".into());
+            }
+            _ => interval,
+        };
+
+        let uuid = self.new_counter(
+            name,
+            unit_name,
+            false,
+            parent_uuid,
+            description,
+            child_ordering,
+            sibling_order_rank,
+        )?;
+        self.derived_counters
+            .insert(uuid, DerivedCounter::new(kind, interval, per));
+        Ok(uuid)
+    }
+
+    /// Log an increment to a derived counter, emitting the samples of all
+    /// intervals that completed before `ts`.
+    pub fn derived_counter_evt(
+        &mut self,
+        track_uuid: u64,
+        ts: u64,
+        increment: f64,
+    ) -> Result<(), String> {
+        let Some(mut counter) = self.derived_counters.get_mut(&track_uuid) else {
+            return Err(format!("Unknown derived counter {track_uuid:#x}"));
+        };
+        while let Some((sample_ts, value)) = counter.pop_sample(ts) {
+            self.counter_evt(track_uuid, sample_ts, CounterValue::Float(value), false)?;
+            counter = self.derived_counters.get_mut(&track_uuid).unwrap();
+        }
+        counter.add(ts, increment);
+        Ok(())
+    }
+
+    /// Emit the sample of the interval still open at the end of that interval.
+    /// No increments can be logged afterwards.
+    pub fn finish_derived_counter(&mut self, track_uuid: u64) -> Result<(), String> {
+        let Some(mut counter) = self.derived_counters.remove(&track_uuid) else {
+            return Err(format!("Unknown derived counter {track_uuid:#x}"));
+        };
+        if let Some((ts, value)) = counter.finish() {
+            self.counter_evt(track_uuid, ts, CounterValue::Float(value), false)?;
+        }
+        Ok(())
+    }
+
+    /// Create a histogram track recording values into the buckets delimited
+    /// by `bounds`: Below the first bound, between each pair of bounds, and
+    /// from the last bound upwards.
+    ///
+    /// When the histogram is finished (at the latest when the context is
+    /// dropped), a summary of all recorded values is emitted as the arguments
+    /// of an instant event.
+    pub fn new_histogram(
+        &mut self,
+        name: String,
+        mut bounds: Vec<f64>,
+        mode: HistogramMode,
+        parent_uuid: Option<u64>,
+        description: Option<String>,
+        sibling_order_rank: Option<i32>,
+    ) -> Result<u64, String> {
+        if bounds.iter().any(|b| b.is_nan()) {
+            return Err("Invalid histogram bounds - must not be NaN".into());
+        }
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
 
+        let snapshot_interval = match mode {
+            HistogramMode::Buckets => None,
+            HistogramMode::Snapshot { interval } => match self.convert_ts(interval) {
+                0 => {
+                    return Err(format!(
+                        "Invalid snapshot interval {interval} - must be at least 1ns"
+                    ))
+                }
+                interval => Some(interval),
+            },
+        };
+
+        let uuid = self.new_track(
+            name,
+            parent_uuid,
+            description,
+            Some(ChildOrder::Explicit),
+            sibling_order_rank,
+        )?;
+        let mut histogram = Histogram::new(bounds.clone(), snapshot_interval);
+        if mode == HistogramMode::Buckets {
+            for (rank, bucket) in Histogram::bucket_names(&bounds).into_iter().enumerate() {
+                let bucket_uuid = self.new_counter(
+                    bucket,
+                    None,
+                    false,
+                    Some(uuid),
+                    None,
+                    None,
+                    Some(rank as i32),
+                )?;
+                histogram.bucket_uuids.push(bucket_uuid);
+            }
+        }
+        self.histograms.insert(uuid, histogram);
         Ok(uuid)
     }
 
+    pub fn histogram_record(&mut self, track_uuid: u64, ts: u64, value: f64) -> Result<(), String> {
+        let Some(histogram) = self.histograms.get_mut(&track_uuid) else {
+            return Err(format!("Unknown histogram {track_uuid:#x}"));
+        };
+        if let Some(snapshot_ts) = histogram.pop_snapshot(ts) {
+            let args = histogram.snapshot_args();
+            self.annotated_instant_evt(track_uuid, snapshot_ts, "snapshot", args)?;
+        }
+
+        let histogram = self.histograms.get_mut(&track_uuid).unwrap();
+        let (bucket, count) = histogram.record(ts, value);
+        if let Some(bucket_uuid) = histogram.bucket_uuids.get(bucket).copied() {
+            self.counter_evt(bucket_uuid, ts, CounterValue::Int(count as i64), false)?;
+        }
+        Ok(())
+    }
+
+    /// Emit the summary (and a final snapshot) of a histogram at the time of
+    /// its last recorded value. No values can be recorded afterwards.
+    pub fn finish_histogram(&mut self, track_uuid: u64) -> Result<(), String> {
+        let Some(histogram) = self.histograms.remove(&track_uuid) else {
+            return Err(format!("Unknown histogram {track_uuid:#x}"));
+        };
+        let Some(ts) = histogram.last_ts else {
+            return Ok(());
+        };
+        if histogram.has_snapshots() {
+            self.annotated_instant_evt(track_uuid, ts, "snapshot", histogram.snapshot_args())?;
+        }
+        self.annotated_instant_evt(track_uuid, ts, "summary", histogram.summary_args())
+    }
+
+    fn annotated_instant_evt(
+        &mut self,
+        track_uuid: u64,
+        ts: u64,
+        name: &str,
+        args: Vec<synthetto::DebugAnnotation>,
+    ) -> Result<(), String> {
+        self.encode_buffer.clear();
+        synthetto::annotated_instant_evt(
+            track_uuid,
+            ts,
+            Some(name.to_string()),
+            args,
+            self.sequence_id,
+            &mut self.encode_buffer,
+        )
+        .expect("prost encode should only fail if buffer is too small, but buffer is vec");
+        self.write_encode_buffer(Some(ts))
+    }
+
     pub fn slice_begin_evt(
         &mut self,
         track_uuid: u64,
         ts: f64,
-        name: Option<String>,
-        flows: Vec<u64>,
-        flows_end: Vec<u64>,
+        name: Option<&str>,
+        flows: &[u64],
+        flows_end: &[u64],
         replace_behaviour: ReplacementBehaviour,
         correlation_id: Option<u64>,
     ) -> Result<(), String> {
-        let new_slice = TrackSlice::new(name.clone(), flows.clone());
-
         match replace_behaviour {
             ReplacementBehaviour::Replace => {
                 let track = self.get_mut_track(track_uuid);
                 if !track.active_slices.is_empty() {
-                    self.slice_end_evt(track_uuid, ts, vec![], vec![], true, None)?;
+                    self.slice_end_evt(track_uuid, ts, &[], &[], true, None)?;
                 }
             }
             ReplacementBehaviour::NewSlice => {
@@ -286,11 +977,11 @@ impl Context {
             ReplacementBehaviour::ReplaceIfDifferent => {
                 let track = self.get_mut_track(track_uuid);
                 if let Some(current_slice) = track.active_slices.last() {
-                    if *current_slice == new_slice {
+                    if current_slice.matches(name, flows) {
                         // Same slice, do nothing
                         return Ok(());
                     } else {
-                        self.slice_end_evt(track_uuid, ts, vec![], vec![], true, None)?;
+                        self.slice_end_evt(track_uuid, ts, &[], &[], true, None)?;
                     }
                 }
             }
@@ -298,22 +989,22 @@ impl Context {
 
         self.encode_buffer.clear();
         let ts = self.convert_ts(ts);
-        synthetto::slice_begin_evt(
+        synthetto::raw::slice_begin_evt(
             track_uuid,
             ts,
             name,
             flows,
             flows_end,
             correlation_id,
+            self.sequence_id,
             &mut self.encode_buffer,
-        )
-        .expect("prost encode should only fail if buffer is too small, but buffer is vec");
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        );
+        self.write_encode_buffer(Some(ts))?;
 
-        self.get_mut_track(track_uuid).active_slices.push(new_slice);
+        let track = self.get_mut_track(track_uuid);
+        let mut new_slice = track.ended_slices.pop().unwrap_or_default();
+        new_slice.set(name, flows);
+        track.active_slices.push(new_slice);
         Ok(())
     }
 
@@ -321,8 +1012,8 @@ impl Context {
         &mut self,
         track_uuid: u64,
         ts: f64,
-        flows: Vec<u64>,
-        flows_end: Vec<u64>,
+        flows: &[u64],
+        flows_end: &[u64],
         force: bool,
         correlation_id: Option<u64>,
     ) -> Result<(), String> {
@@ -334,22 +1025,21 @@ impl Context {
             return Ok(());
         }
 
-        synthetto::slice_end_evt(
+        synthetto::raw::slice_end_evt(
             track_uuid,
             ts,
             flows,
             flows_end,
             correlation_id,
+            self.sequence_id,
             &mut self.encode_buffer,
-        )
-        .expect("prost encode should only fail if buffer is too small, but buffer is vec");
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        );
+        self.write_encode_buffer(Some(ts))?;
 
         let track = self.get_mut_track(track_uuid);
-        track.active_slices.pop();
+        if let Some(slice) = track.active_slices.pop() {
+            track.ended_slices.push(slice);
+        }
         Ok(())
     }
 
@@ -357,27 +1047,24 @@ impl Context {
         &mut self,
         track_uuid: u64,
         ts: f64,
-        name: Option<String>,
-        flows: Vec<u64>,
-        flows_end: Vec<u64>,
+        name: Option<&str>,
+        flows: &[u64],
+        flows_end: &[u64],
         correlation_id: Option<u64>,
     ) -> Result<(), String> {
         self.encode_buffer.clear();
         let ts = self.convert_ts(ts);
-        synthetto::instant_evt(
+        synthetto::raw::instant_evt(
             track_uuid,
             ts,
             name,
             flows,
             flows_end,
             correlation_id,
+            self.sequence_id,
             &mut self.encode_buffer,
-        )
-        .expect("prost encode should only fail if buffer is too small, but buffer is vec");
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
+        );
+        self.write_encode_buffer(Some(ts))?;
         Ok(())
     }
 
@@ -396,23 +1083,13 @@ impl Context {
             }
         }
 
-        self.encode_buffer.clear();
-        match value {
-            CounterValue::Int(val) => {
-                synthetto::int_counter_evt(track_uuid, ts, val, &mut self.encode_buffer).expect(
-                    "prost encode should only fail if buffer is too small, but buffer is vec",
-                );
-            }
-            CounterValue::Float(val) => {
-                synthetto::float_counter_evt(track_uuid, ts, val, &mut self.encode_buffer).expect(
-                    "prost encode should only fail if buffer is too small, but buffer is vec",
-                );
-            }
+        if let Some(sampler) = self.counter_samplers.get_mut(&track_uuid) {
+            let mut samples = std::mem::take(&mut self.sample_buffer);
+            sampler.push(ts, value.clone(), &mut samples);
+            self.write_counter_samples(track_uuid, samples)?;
+        } else {
+            self.write_counter_value(track_uuid, ts, &value)?;
         }
-        self.w
-            .write_all(&self.encode_buffer)
-            .map_err(ioerr_to_str)?;
-        self.trim_encode_buffer();
 
         if compress {
             self.counters.insert(track_uuid, Counter::new(value));
@@ -420,4 +1097,345 @@ impl Context {
 
         Ok(())
     }
+
+    /// Set the sampling policy of a counter, replacing any previous one.
+    ///
+    /// Values held back by the previous policy are written first. Pending
+    /// values are also written when the context is dropped.
+    pub fn set_counter_sampling(
+        &mut self,
+        track_uuid: u64,
+        sampling: CounterSampling,
+    ) -> Result<(), String> {
+        self.finish_counter_sampling(track_uuid)?;
+
+        if sampling == CounterSampling::default() {
+            return Ok(());
+        }
+        let decimate = sampling.decimate.map(|t| self.convert_ts(t).max(1));
+        let min_interval = sampling.min_interval.map(|t| self.convert_ts(t));
+        self.counter_samplers.insert(
+            track_uuid,
+            CounterSampler::new(sampling.deadband, decimate, min_interval),
+        );
+        Ok(())
+    }
+
+    fn finish_counter_sampling(&mut self, track_uuid: u64) -> Result<(), String> {
+        if let Some(mut sampler) = self.counter_samplers.remove(&track_uuid) {
+            let mut samples = std::mem::take(&mut self.sample_buffer);
+            sampler.finish(&mut samples);
+            self.write_counter_samples(track_uuid, samples)?;
+        }
+        Ok(())
+    }
+
+    fn write_counter_samples(
+        &mut self,
+        track_uuid: u64,
+        mut samples: Vec<(u64, CounterValue)>,
+    ) -> Result<(), String> {
+        let result = samples
+            .drain(..)
+            .try_for_each(|(ts, value)| self.write_counter_value(track_uuid, ts, &value));
+        self.sample_buffer = samples;
+        result
+    }
+
+    fn write_counter_value(
+        &mut self,
+        track_uuid: u64,
+        ts: u64,
+        value: &CounterValue,
+    ) -> Result<(), String> {
+        self.encode_buffer.clear();
+        match *value {
+            CounterValue::Int(val) => synthetto::raw::int_counter_evt(
+                track_uuid,
+                ts,
+                val,
+                self.sequence_id,
+                &mut self.encode_buffer,
+            ),
+            CounterValue::Float(val) => synthetto::raw::float_counter_evt(
+                track_uuid,
+                ts,
+                val,
+                self.sequence_id,
+                &mut self.encode_buffer,
+            ),
+        }
+        self.write_encode_buffer(Some(ts))
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        let histograms: Vec<u64> = self.histograms.keys().copied().collect();
+        for track_uuid in histograms {
+            if let Err(e) = self.finish_histogram(track_uuid) {
+                println!("cspect: {e}");
+            }
+        }
+        let derived: Vec<u64> = self.derived_counters.keys().copied().collect();
+        for track_uuid in derived {
+            if let Err(e) = self.finish_derived_counter(track_uuid) {
+                println!("cspect: {e}");
+            }
+        }
+        let sampled: Vec<u64> = self.counter_samplers.keys().copied().collect();
+        for track_uuid in sampled {
+            if let Err(e) = self.finish_counter_sampling(track_uuid) {
+                println!("cspect: {e}");
+            }
+        }
+        if let Err(e) = self.flush() {
+            println!("cspect: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use synthetto::{trace_packet, track_event, TraceReader};
+
+    use super::*;
+
+    /// All `(timestamp, value)` samples of floating point counters in a trace.
+    fn counter_samples(path: &Path) -> Vec<(u64, f64)> {
+        let mut samples = vec![];
+        for packet in TraceReader::open(path).unwrap() {
+            let packet = packet.unwrap().1;
+            let Some(trace_packet::Data::TrackEvent(evt)) = packet.data else {
+                continue;
+            };
+            if let Some(track_event::CounterValueField::DoubleCounterValue(value)) =
+                evt.counter_value_field
+            {
+                samples.push((packet.timestamp.unwrap(), value));
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_derived_counter_finished_on_drop() {
+        let path = std::env::temp_dir().join(format!("cspect-derived-{}", std::process::id()));
+        let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+        let track = ctx
+            .new_derived_counter(
+                "total".into(),
+                None,
+                DerivedCounterKind::Cumulative,
+                10.0,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        ctx.derived_counter_evt(track, 1, 4.0).unwrap();
+        ctx.derived_counter_evt(track, 12, 2.0).unwrap();
+        ctx.derived_counter_evt(track, 15, 3.0).unwrap();
+        drop(ctx);
+
+        let samples = counter_samples(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        // The last interval is still open when the context is dropped:
+        assert_eq!(samples, vec![(10, 4.0), (20, 9.0)]);
+    }
+
+    #[test]
+    fn test_resume_derived_counter() {
+        let tmp = |name: &str| {
+            std::env::temp_dir().join(format!("cspect-resume-{name}-{}", std::process::id()))
+        };
+        let (path, state_path, resumed_path) = (tmp("trace"), tmp("state"), tmp("resumed"));
+
+        let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+        let track = ctx
+            .new_derived_counter(
+                "avg".into(),
+                None,
+                DerivedCounterKind::MovingAverage { samples: 2 },
+                10.0,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let sampling = CounterSampling {
+            min_interval: Some(15.0),
+            ..CounterSampling::default()
+        };
+        ctx.set_counter_sampling(track, sampling).unwrap();
+        for (ts, increment) in [(1, 4.0), (12, 2.0), (25, 6.0)] {
+            ctx.derived_counter_evt(track, ts, increment).unwrap();
+        }
+        ctx.save_state(&state_path).unwrap();
+
+        let finish = |mut ctx: Context| {
+            for (ts, increment) in [(33, 1.0), (47, 3.0)] {
+                ctx.derived_counter_evt(track, ts, increment).unwrap();
+            }
+            ctx.finish_derived_counter(track).unwrap();
+        };
+        finish(ctx);
+        finish(Context::resume(resumed_path.clone(), &state_path).unwrap());
+
+        let samples = counter_samples(&path);
+        assert_eq!(counter_samples(&resumed_path), samples);
+        assert_eq!(samples.len(), 4);
+        for path in [path, state_path, resumed_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resume_histogram() {
+        let tmp = |name: &str| {
+            std::env::temp_dir().join(format!("cspect-resume-hist-{name}-{}", std::process::id()))
+        };
+        let (path, state_path) = (tmp("trace"), tmp("state"));
+
+        let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+        let track = ctx
+            .new_histogram(
+                "latency".into(),
+                vec![10.0],
+                HistogramMode::Buckets,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        ctx.histogram_record(track, 5, 3.0).unwrap();
+        ctx.save_state(&state_path).unwrap();
+        let saved = ctx.histograms[&track].summary_args();
+        drop(ctx);
+
+        let mut ctx = Context::resume(path.clone(), &state_path).unwrap();
+        assert_eq!(ctx.histograms[&track].summary_args(), saved);
+        ctx.histogram_record(track, 8, 4.0).unwrap();
+        ctx.finish_histogram(track).unwrap();
+        drop(ctx);
+
+        let buckets: Vec<(u64, i64)> = TraceReader::open(&path)
+            .unwrap()
+            .filter_map(|packet| {
+                let packet = packet.unwrap().1;
+                let Some(trace_packet::Data::TrackEvent(evt)) = packet.data else {
+                    return None;
+                };
+                match evt.counter_value_field {
+                    Some(track_event::CounterValueField::CounterValue(v)) => {
+                        Some((packet.timestamp.unwrap(), v))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        // The count of each bucket carries on from the saved state:
+        assert_eq!(buckets, vec![(5, 1), (8, 2)]);
+        for path in [path, state_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resume_metadata() {
+        let tmp = |name: &str| {
+            std::env::temp_dir().join(format!("cspect-resume-meta-{name}-{}", std::process::id()))
+        };
+        let (path, state_path) = (tmp("trace"), tmp("state"));
+
+        let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+        ctx.save_state(&state_path).unwrap();
+        drop(ctx);
+        let mut ctx = Context::resume(path.clone(), &state_path).unwrap();
+        ctx.add_metadata(vec![("test".into(), "a".into())]).unwrap();
+        drop(ctx);
+
+        let metadata_tracks = TraceReader::open(&path)
+            .unwrap()
+            .filter(|packet| match &packet.as_ref().unwrap().1.data {
+                Some(trace_packet::Data::TrackDescriptor(desc)) => {
+                    desc.static_or_dynamic_name
+                        == Some(synthetto::track_descriptor::StaticOrDynamicName::Name(
+                            synthetto::METADATA_TRACK_NAME.into(),
+                        ))
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(metadata_tracks, 1);
+        for path in [path, state_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_salted_trace_reproducible() {
+        let write_trace = |name: &str| {
+            let path = std::env::temp_dir().join(format!("cspect-{name}-{}", std::process::id()));
+            let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+            ctx.set_uuid_salt("test");
+            ctx.add_metadata(vec![("seed".into(), "1".into())]).unwrap();
+            let track = ctx
+                .new_track("track".into(), None, None, None, None)
+                .unwrap();
+            ctx.instant_evt(track, 10.0, Some("evt"), &[], &[], None)
+                .unwrap();
+            drop(ctx);
+            let trace = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            trace
+        };
+        let trace = write_trace("salted-a");
+        assert_eq!(trace, write_trace("salted-b"));
+
+        let contains = |s: &str| trace.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains(synthetto::METADATA_TRACK_NAME));
+        assert!(contains("cspect_version"));
+        assert!(contains("seed"));
+        assert!(!contains("creation_time"));
+        assert!(!contains("hostname"));
+    }
+
+    #[test]
+    fn test_try_flush() {
+        let path = std::env::temp_dir().join(format!("cspect-try-flush-{}", std::process::id()));
+        let mut ctx = Context::new(path.clone(), 1e-9, 1).unwrap();
+        ctx.add_metadata(vec![("seed".into(), "1".into())]).unwrap();
+        let track = ctx
+            .new_track("track".into(), None, None, None, None)
+            .unwrap();
+        ctx.instant_evt(track, 10.0, Some("evt"), &[], &[], None)
+            .unwrap();
+
+        let contains = |s: &str| {
+            let trace = std::fs::read(&path).unwrap();
+            trace.windows(s.len()).any(|w| w == s.as_bytes())
+        };
+        // Nothing is written while another thread holds the trace file:
+        let shared = ctx.shared.clone();
+        let w = shared.w.lock().unwrap();
+        assert_eq!(ctx.try_flush(), Ok(false));
+        drop(w);
+        assert!(!contains("evt"));
+
+        // Metadata is held back while a track is being created:
+        let synthetto = shared.synthetto.lock().unwrap();
+        assert_eq!(ctx.try_flush(), Ok(true));
+        drop(synthetto);
+        assert!(contains("evt"));
+        assert!(!contains("seed"));
+
+        assert_eq!(ctx.try_flush(), Ok(true));
+        assert!(contains("seed"));
+        drop(ctx);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
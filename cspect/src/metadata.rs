@@ -0,0 +1,116 @@
+//! Run information written into the metadata track of a trace.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Metadata of a trace, written into its metadata track at the first flush
+/// (so that a UUID salt set after creating the trace applies to the track).
+#[derive(Debug, Default)]
+pub(crate) struct TraceMetadata {
+    pub track: Option<u64>,
+    pub pending: Vec<(String, String)>,
+    /// Entries that differ between otherwise identical runs, see
+    /// [`volatile_metadata`].
+    pub volatile: Vec<(String, String)>,
+}
+
+impl TraceMetadata {
+    /// Entries not written yet, including the volatile ones unless omitted.
+    pub fn take_pending(&mut self) -> Vec<(String, String)> {
+        let mut entries = std::mem::take(&mut self.pending);
+        entries.append(&mut self.volatile);
+        entries
+    }
+}
+
+/// Metadata written automatically for every new trace.
+pub(crate) fn default_metadata(timescale: f64, time_mult: u32) -> Vec<(String, String)> {
+    vec![
+        (
+            "cspect_version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        ("timescale".into(), format!("{timescale:e}s")),
+        ("time_mult".into(), time_mult.to_string()),
+    ]
+}
+
+/// Metadata written automatically for every new trace, unless omitted for
+/// reproducible traces: creation time, hostname and command line.
+pub(crate) fn volatile_metadata() -> Vec<(String, String)> {
+    let mut entries = vec![];
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        entries.push(("creation_time".into(), format_utc(now.as_secs())));
+    }
+    if let Some(hostname) = hostname() {
+        entries.push(("hostname".into(), hostname));
+    }
+    let cmdline: Vec<String> = std::env::args().collect();
+    entries.push(("cmdline".into(), cmdline.join(" ")));
+    entries
+}
+
+/// Parse newline-separated `key=value` pairs.
+pub(crate) fn parse_metadata(s: &str) -> Result<Vec<(String, String)>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(format!(
+                "invalid metadata entry '{line}' - expected key=value"
+            )),
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Format seconds since the unix epoch as ISO 8601 UTC timestamp.
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Civil date from days since epoch (Howard Hinnant's algorithm):
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951782400 + 3723), "2000-02-29T01:02:03Z");
+    }
+}
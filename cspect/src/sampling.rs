@@ -0,0 +1,271 @@
+//! Sampling policies reducing the number of values emitted for a counter.
+
+use crate::{
+    state::{split_tokens, Token},
+    CounterValue,
+};
+
+/// Controls which of the values logged to a counter are written to the trace.
+///
+/// The criteria are applied in order: deadband, decimation, rate limit. With
+/// the default policy, every value is written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CounterSampling {
+    /// Drop values that differ by no more than this from the last value that
+    /// passed.
+    pub deadband: Option<f64>,
+    /// Reduce the values within each bucket of this length (in timescale
+    /// units) to the bucket's minimum, maximum and final value.
+    pub decimate: Option<f64>,
+    /// Write at most one value per this much time (in timescale units).
+    /// Values in between are dropped, except for the last one, which is
+    /// written once the interval has passed.
+    pub min_interval: Option<f64>,
+}
+
+impl CounterValue {
+    fn as_f64(&self) -> f64 {
+        match self {
+            CounterValue::Int(v) => *v as f64,
+            CounterValue::Float(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    idx: u64,
+    min: (u64, CounterValue),
+    max: (u64, CounterValue),
+    last: (u64, CounterValue),
+}
+
+impl Bucket {
+    fn new(idx: u64, ts: u64, value: CounterValue) -> Self {
+        Self {
+            idx,
+            min: (ts, value.clone()),
+            max: (ts, value.clone()),
+            last: (ts, value),
+        }
+    }
+
+    fn push(&mut self, ts: u64, value: CounterValue) {
+        if value.as_f64() < self.min.1.as_f64() {
+            self.min = (ts, value.clone());
+        }
+        if value.as_f64() > self.max.1.as_f64() {
+            self.max = (ts, value.clone());
+        }
+        self.last = (ts, value);
+    }
+
+    /// The minimum, maximum and final value in chronological order.
+    fn into_points(self) -> Vec<(u64, CounterValue)> {
+        let mut points = vec![self.min, self.max, self.last];
+        points.sort_by_key(|(ts, _)| *ts);
+        points.dedup_by_key(|(ts, _)| *ts);
+        points
+    }
+}
+
+impl Token for Bucket {
+    fn token(&self) -> String {
+        let points = vec![self.min.clone(), self.max.clone(), self.last.clone()];
+        (self.idx, points).token()
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        let (idx, points) = <(u64, Vec<(u64, CounterValue)>)>::parse_token(s)?;
+        let Ok([min, max, last]) = <[_; 3]>::try_from(points) else {
+            return Err(format!("invalid sampling bucket '{s}'"));
+        };
+        Ok(Self {
+            idx,
+            min,
+            max,
+            last,
+        })
+    }
+}
+
+/// Per-counter state of a [`CounterSampling`] policy, with all times in trace
+/// timestamp units.
+#[derive(Debug, Clone)]
+pub(crate) struct CounterSampler {
+    deadband: Option<f64>,
+    decimate: Option<u64>,
+    min_interval: Option<u64>,
+    last_passed: Option<f64>,
+    bucket: Option<Bucket>,
+    last_emit_ts: Option<u64>,
+    pending: Option<(u64, CounterValue)>,
+}
+
+impl CounterSampler {
+    pub fn new(deadband: Option<f64>, decimate: Option<u64>, min_interval: Option<u64>) -> Self {
+        Self {
+            deadband,
+            decimate,
+            min_interval,
+            last_passed: None,
+            bucket: None,
+            last_emit_ts: None,
+            pending: None,
+        }
+    }
+
+    /// Feed a logged value, appending the values to write to `out`.
+    pub fn push(&mut self, ts: u64, value: CounterValue, out: &mut Vec<(u64, CounterValue)>) {
+        if let Some(deadband) = self.deadband {
+            let v = value.as_f64();
+            if self
+                .last_passed
+                .is_some_and(|last| (v - last).abs() <= deadband)
+            {
+                return;
+            }
+            self.last_passed = Some(v);
+        }
+
+        let Some(decimate) = self.decimate else {
+            self.limit(ts, value, out);
+            return;
+        };
+        let idx = ts / decimate;
+        match &mut self.bucket {
+            Some(bucket) if bucket.idx == idx => bucket.push(ts, value),
+            bucket => {
+                let prev = bucket.replace(Bucket::new(idx, ts, value));
+                for (ts, value) in prev.into_iter().flat_map(Bucket::into_points) {
+                    self.limit(ts, value, out);
+                }
+            }
+        }
+    }
+
+    /// Append all values still held back to `out`.
+    pub fn finish(&mut self, out: &mut Vec<(u64, CounterValue)>) {
+        if let Some(bucket) = self.bucket.take() {
+            for (ts, value) in bucket.into_points() {
+                self.limit(ts, value, out);
+            }
+        }
+        out.extend(self.pending.take());
+    }
+
+    fn limit(&mut self, ts: u64, value: CounterValue, out: &mut Vec<(u64, CounterValue)>) {
+        if let (Some(min_interval), Some(last)) = (self.min_interval, self.last_emit_ts) {
+            let next = last + min_interval;
+            if ts < next {
+                self.pending = Some((ts, value));
+                return;
+            }
+            // The value held back was still current when the interval passed
+            // (unless replaced at that very time):
+            if let Some((_, pending)) = self.pending.take().filter(|_| ts > next) {
+                self.last_emit_ts = Some(next);
+                out.push((next, pending));
+                if ts < next + min_interval {
+                    self.pending = Some((ts, value));
+                    return;
+                }
+            }
+        }
+        self.pending = None;
+        self.last_emit_ts = Some(ts);
+        out.push((ts, value));
+    }
+
+    /// State as written into a state file, see [`crate::state`].
+    pub fn state_tokens(&self) -> Vec<String> {
+        vec![
+            self.deadband.token(),
+            self.decimate.token(),
+            self.min_interval.token(),
+            self.last_passed.token(),
+            self.bucket.token(),
+            self.last_emit_ts.token(),
+            self.pending.token(),
+        ]
+    }
+
+    pub fn from_state_tokens(tokens: &[&str]) -> Result<Self, String> {
+        let [deadband, decimate, min_interval, last_passed, bucket, last_emit_ts, pending] =
+            split_tokens(tokens)?;
+        Ok(Self {
+            deadband: Token::parse_token(deadband)?,
+            decimate: Token::parse_token(decimate)?,
+            min_interval: Token::parse_token(min_interval)?,
+            last_passed: Token::parse_token(last_passed)?,
+            bucket: Token::parse_token(bucket)?,
+            last_emit_ts: Token::parse_token(last_emit_ts)?,
+            pending: Token::parse_token(pending)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling() {
+        let mut sampler = CounterSampler::new(Some(0.5), Some(10), None);
+        let mut out = vec![];
+        let values = [1.0, 1.2, 5.0, 0.0, 3.0, 3.1, 4.0];
+        for (ts, v) in values.iter().enumerate() {
+            sampler.push(ts as u64 * 4, CounterValue::Float(*v), &mut out);
+        }
+        sampler.finish(&mut out);
+
+        let expected = [(0, 1.0), (8, 5.0), (12, 0.0), (16, 3.0), (24, 4.0)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(ts, v)| (*ts, CounterValue::Float(*v)))
+            .collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_state_tokens() {
+        let mut sampler = CounterSampler::new(Some(0.5), Some(10), Some(5));
+        let mut out = vec![];
+        let push = |sampler: &mut CounterSampler, out: &mut Vec<_>, values: &[(u64, i64)]| {
+            for (ts, v) in values {
+                sampler.push(*ts, CounterValue::Int(*v), out);
+            }
+        };
+        push(&mut sampler, &mut out, &[(0, 1), (3, -4), (12, 5), (14, 9)]);
+        let tokens = sampler.state_tokens();
+        let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        let mut restored = CounterSampler::from_state_tokens(&tokens).unwrap();
+
+        let values = [(15, 2), (27, 8), (33, 0)];
+        let (mut expected, mut resumed) = (vec![], vec![]);
+        push(&mut sampler, &mut expected, &values);
+        sampler.finish(&mut expected);
+        push(&mut restored, &mut resumed, &values);
+        restored.finish(&mut resumed);
+        assert_eq!(resumed, expected);
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let mut sampler = CounterSampler::new(None, None, Some(10));
+        let mut out = vec![];
+        let values = [(0, 1), (3, 2), (25, 3), (28, 4), (31, 5), (45, 6)];
+        for (ts, v) in values {
+            sampler.push(ts, CounterValue::Int(v), &mut out);
+        }
+        sampler.finish(&mut out);
+
+        // Steps within an interval are written at its end:
+        let expected = [(0, 1), (10, 2), (25, 3), (35, 5), (45, 6)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(ts, v)| (*ts, CounterValue::Int(*v)))
+            .collect();
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,408 @@
+//! Sidecar file holding the state of a [`Context`](crate::Context), allowing a
+//! trace to be resumed (e.g. after a simulator checkpoint restore).
+//!
+//! The file is line-based text. Strings (slice names, paths) are hex-encoded
+//! so that they can never break the line structure:
+//!
+//! ```text
+//! cspect-state 1
+//! timescale <f64>
+//! time_mult <u32>
+//! uuid_cnt <u64>
+//! uuid_salt <u64> <derived uuid,uuid,.. | ->
+//! sequence_ids <salt u32> <allocated count u32> <descriptor sequence id u32>
+//! trace <len> <hex path>
+//! metadata <metadata track uuid>
+//! slice <track uuid> <hex name | -> <flow,flow,.. | ->
+//! counter <track uuid> int <i64>
+//! counter <track uuid> float <f64 bits>
+//! derived <track uuid> <derived counter state..>
+//! sampler <track uuid> <counter sampler state..>
+//! histogram <track uuid> <histogram state..>
+//! ```
+//!
+//! The state of derived counters, counter samplers and histograms is written by their
+//! modules as [`Token`]s, with floats as their bits and `-` for `None` or an
+//! empty list.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    derived::DerivedCounter, histogram::Histogram, sampling::CounterSampler, Counter, CounterValue,
+    Track, TrackSlice,
+};
+
+const MAGIC: &str = "cspect-state 1";
+
+#[derive(Debug, Default)]
+pub(crate) struct ContextState {
+    pub timescale: f64,
+    pub time_mult: u32,
+    pub uuid_cnt: u64,
+    pub uuid_salt: Option<u64>,
+    pub derived_uuids: Vec<u64>,
+    /// Sequence ID allocator state (see `Synthetto::sequence_state`).
+    pub sequence_ids: Option<(u32, u32)>,
+    pub descriptor_sequence_id: u32,
+    pub trace_path: PathBuf,
+    pub trace_len: u64,
+    pub metadata_track: Option<u64>,
+    pub tracks: HashMap<u64, Track>,
+    pub counters: HashMap<u64, Counter>,
+    pub derived_counters: HashMap<u64, DerivedCounter>,
+    pub counter_samplers: HashMap<u64, CounterSampler>,
+    pub histograms: HashMap<u64, Histogram>,
+}
+
+impl ContextState {
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let mut s = String::new();
+        s.push_str(MAGIC);
+        s.push('\n');
+        s.push_str(&format!("timescale {:e}\n", self.timescale));
+        s.push_str(&format!("time_mult {}\n", self.time_mult));
+        s.push_str(&format!("uuid_cnt {}\n", self.uuid_cnt));
+        if let Some(salt) = self.uuid_salt {
+            let mut derived = self.derived_uuids.clone();
+            derived.sort_unstable();
+            s.push_str(&format!("uuid_salt {salt} {}\n", join_uuids(&derived)));
+        }
+        if let Some((salt, cnt)) = self.sequence_ids {
+            s.push_str(&format!(
+                "sequence_ids {salt} {cnt} {}\n",
+                self.descriptor_sequence_id
+            ));
+        }
+        s.push_str(&format!(
+            "trace {} {}\n",
+            self.trace_len,
+            hex_encode(self.trace_path.to_string_lossy().as_bytes())
+        ));
+        if let Some(uuid) = self.metadata_track {
+            s.push_str(&format!("metadata {uuid}\n"));
+        }
+
+        let mut track_uuids: Vec<&u64> = self.tracks.keys().collect();
+        track_uuids.sort_unstable();
+        for uuid in track_uuids {
+            for slice in &self.tracks[uuid].active_slices {
+                let name = match &slice.name {
+                    Some(name) => hex_encode(name.as_bytes()),
+                    None => "-".to_string(),
+                };
+                let flows = join_uuids(&slice.flows);
+                s.push_str(&format!("slice {uuid} {name} {flows}\n"));
+            }
+        }
+
+        let mut counter_uuids: Vec<&u64> = self.counters.keys().collect();
+        counter_uuids.sort_unstable();
+        for uuid in counter_uuids {
+            match self.counters[uuid].last_value {
+                CounterValue::Int(v) => s.push_str(&format!("counter {uuid} int {v}\n")),
+                CounterValue::Float(v) => {
+                    s.push_str(&format!("counter {uuid} float {}\n", v.to_bits()))
+                }
+            }
+        }
+
+        for (uuid, counter) in sorted(&self.derived_counters) {
+            s.push_str(&format!(
+                "derived {uuid} {}\n",
+                counter.state_tokens().join(" ")
+            ));
+        }
+        for (uuid, sampler) in sorted(&self.counter_samplers) {
+            s.push_str(&format!(
+                "sampler {uuid} {}\n",
+                sampler.state_tokens().join(" ")
+            ));
+        }
+        for (uuid, histogram) in sorted(&self.histograms) {
+            s.push_str(&format!(
+                "histogram {uuid} {}\n",
+                histogram.state_tokens().join(" ")
+            ));
+        }
+
+        fs::write(path, s).map_err(|e| format!("Failed to write state file - {e}"))
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read state file - {e}"))?;
+        let mut lines = content.lines().enumerate();
+
+        match lines.next() {
+            Some((_, MAGIC)) => (),
+            _ => return Err("Failed to read state file - not a cspect state file".to_string()),
+        }
+
+        let mut state = ContextState::default();
+        for (idx, line) in lines {
+            parse_line(&mut state, line)
+                .map_err(|e| format!("Failed to read state file - line {}: {e}", idx + 1))?;
+        }
+        Ok(state)
+    }
+}
+
+fn parse_line(state: &mut ContextState, line: &str) -> Result<(), String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    match parts.as_slice() {
+        [] => (),
+        ["timescale", v] => state.timescale = parse(v)?,
+        ["time_mult", v] => state.time_mult = parse(v)?,
+        ["uuid_cnt", v] => state.uuid_cnt = parse(v)?,
+        ["uuid_salt", salt, derived] => {
+            state.uuid_salt = Some(parse(salt)?);
+            state.derived_uuids = split_uuids(derived)?;
+        }
+        ["sequence_ids", salt, cnt, descriptor_id] => {
+            state.sequence_ids = Some((parse(salt)?, parse(cnt)?));
+            state.descriptor_sequence_id = parse(descriptor_id)?;
+        }
+        ["trace", len, path] => {
+            state.trace_len = parse(len)?;
+            let path = String::from_utf8(hex_decode(path)?).map_err(|e| e.to_string())?;
+            state.trace_path = PathBuf::from(path);
+        }
+        ["metadata", uuid] => state.metadata_track = Some(parse(uuid)?),
+        ["slice", uuid, name, flows] => {
+            let name = match *name {
+                "-" => None,
+                name => Some(String::from_utf8(hex_decode(name)?).map_err(|e| e.to_string())?),
+            };
+            let flows = split_uuids(flows)?;
+            let track = state.tracks.entry(parse(uuid)?).or_default();
+            track.active_slices.push(TrackSlice::new(name, flows));
+        }
+        ["counter", uuid, "int", v] => {
+            let value = CounterValue::Int(parse(v)?);
+            state.counters.insert(parse(uuid)?, Counter::new(value));
+        }
+        ["counter", uuid, "float", v] => {
+            let value = CounterValue::Float(f64::from_bits(parse(v)?));
+            state.counters.insert(parse(uuid)?, Counter::new(value));
+        }
+        ["derived", uuid, tokens @ ..] => {
+            let counter = DerivedCounter::from_state_tokens(tokens)?;
+            state.derived_counters.insert(parse(uuid)?, counter);
+        }
+        ["sampler", uuid, tokens @ ..] => {
+            let sampler = CounterSampler::from_state_tokens(tokens)?;
+            state.counter_samplers.insert(parse(uuid)?, sampler);
+        }
+        ["histogram", uuid, tokens @ ..] => {
+            let histogram = Histogram::from_state_tokens(tokens)?;
+            state.histograms.insert(parse(uuid)?, histogram);
+        }
+        _ => return Err(format!("unexpected entry '{line}'")),
+    }
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(s: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>()
+        .map_err(|e| format!("invalid value '{s}' - {e}"))
+}
+
+/// Entries of `map`, ordered by UUID.
+fn sorted<T>(map: &HashMap<u64, T>) -> Vec<(&u64, &T)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_unstable_by_key(|(uuid, _)| **uuid);
+    entries
+}
+
+/// Value written as a single whitespace-free token of a state file line.
+pub(crate) trait Token: Sized {
+    fn token(&self) -> String;
+    fn parse_token(s: &str) -> Result<Self, String>;
+}
+
+macro_rules! impl_token_from_str {
+    ($($ty:ty),*) => {$(
+        impl Token for $ty {
+            fn token(&self) -> String {
+                self.to_string()
+            }
+
+            fn parse_token(s: &str) -> Result<Self, String> {
+                parse(s)
+            }
+        }
+    )*};
+}
+
+impl_token_from_str!(u64, usize);
+
+impl Token for f64 {
+    fn token(&self) -> String {
+        self.to_bits().to_string()
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        Ok(f64::from_bits(parse(s)?))
+    }
+}
+
+impl Token for CounterValue {
+    fn token(&self) -> String {
+        match self {
+            CounterValue::Int(v) => format!("i{v}"),
+            CounterValue::Float(v) => format!("f{}", v.token()),
+        }
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        match (s.get(..1), s.get(1..)) {
+            (Some("i"), Some(v)) => Ok(CounterValue::Int(parse(v)?)),
+            (Some("f"), Some(v)) => Ok(CounterValue::Float(f64::parse_token(v)?)),
+            _ => Err(format!("invalid counter value '{s}'")),
+        }
+    }
+}
+
+impl<T: Token> Token for Option<T> {
+    fn token(&self) -> String {
+        self.as_ref().map_or("-".to_string(), T::token)
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        match s {
+            "-" => Ok(None),
+            s => T::parse_token(s).map(Some),
+        }
+    }
+}
+
+/// Comma-separated list (so elements must not contain commas).
+impl<T: Token> Token for Vec<T> {
+    fn token(&self) -> String {
+        if self.is_empty() {
+            return "-".to_string();
+        }
+        let tokens: Vec<String> = self.iter().map(T::token).collect();
+        tokens.join(",")
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        match s {
+            "-" => Ok(vec![]),
+            s => s.split(',').map(T::parse_token).collect(),
+        }
+    }
+}
+
+/// Colon-separated pair (so the first element must not contain colons).
+impl<A: Token, B: Token> Token for (A, B) {
+    fn token(&self) -> String {
+        format!("{}:{}", self.0.token(), self.1.token())
+    }
+
+    fn parse_token(s: &str) -> Result<Self, String> {
+        let (a, b) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid pair '{s}'"))?;
+        Ok((A::parse_token(a)?, B::parse_token(b)?))
+    }
+}
+
+/// Parse the tokens of a state file line, which must be exactly `N`.
+pub(crate) fn split_tokens<'a, const N: usize>(tokens: &[&'a str]) -> Result<[&'a str; N], String> {
+    tokens
+        .try_into()
+        .map_err(|_| format!("expected {N} values, found {}", tokens.len()))
+}
+
+fn join_uuids(uuids: &[u64]) -> String {
+    if uuids.is_empty() {
+        return "-".to_string();
+    }
+    let uuids: Vec<String> = uuids.iter().map(|f| f.to_string()).collect();
+    uuids.join(",")
+}
+
+fn split_uuids(s: &str) -> Result<Vec<u64>, String> {
+    match s {
+        "-" => Ok(vec![]),
+        s => s.split(',').map(parse).collect(),
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hex_decode(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex string '{s}'"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let mut state = ContextState {
+            timescale: 1e-9,
+            time_mult: 3,
+            uuid_cnt: 0x023DEAD042,
+            uuid_salt: Some(0x1234),
+            derived_uuids: vec![5, 9],
+            sequence_ids: Some((0xABCD, 3)),
+            descriptor_sequence_id: 77,
+            trace_path: PathBuf::from("/some path/trace.pftrace"),
+            trace_len: 1234,
+            metadata_track: Some(11),
+            ..ContextState::default()
+        };
+        let track = state.tracks.entry(42).or_default();
+        track
+            .active_slices
+            .push(TrackSlice::new(Some("a b\nc".into()), vec![]));
+        track
+            .active_slices
+            .push(TrackSlice::new(None, vec![3, 1, 2]));
+        state
+            .counters
+            .insert(7, Counter::new(CounterValue::Float(0.1)));
+        state
+            .counters
+            .insert(8, Counter::new(CounterValue::Int(-5)));
+
+        let path = std::env::temp_dir().join(format!("cspect-state-{}", std::process::id()));
+        state.write(&path).unwrap();
+        let restored = ContextState::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.timescale, state.timescale);
+        assert_eq!(restored.time_mult, state.time_mult);
+        assert_eq!(restored.uuid_cnt, state.uuid_cnt);
+        assert_eq!(restored.uuid_salt, state.uuid_salt);
+        assert_eq!(restored.derived_uuids, state.derived_uuids);
+        assert_eq!(restored.sequence_ids, state.sequence_ids);
+        assert_eq!(restored.descriptor_sequence_id, 77);
+        assert_eq!(restored.trace_path, state.trace_path);
+        assert_eq!(restored.trace_len, state.trace_len);
+        assert_eq!(restored.metadata_track, Some(11));
+        assert_eq!(
+            restored.tracks[&42].active_slices,
+            state.tracks[&42].active_slices
+        );
+        assert_eq!(restored.counters, state.counters);
+    }
+}
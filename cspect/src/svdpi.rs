@@ -1,6 +1,8 @@
-#![allow(non_camel_case_types)]
+#![allow(non_camel_case_types, non_snake_case)]
 #![allow(dead_code)]
-//! Select FFI types from `svdpi.sv`
+//! Select FFI types and functions from `svdpi.h`
+
+use std::ffi::{c_int, c_void};
 
 // typedef uint8_t svScalar;
 pub type svScalar = u8;
@@ -10,3 +12,77 @@ pub type svBit = svScalar;
 
 // typedef svScalar svLogic; /* scalar */
 pub type svLogic = svScalar;
+
+// typedef void* svOpenArrayHandle;
+pub type svOpenArrayHandle = *mut c_void;
+
+// Open array querying, provided by the simulator.
+#[cfg(not(test))]
+extern "C" {
+    // int svLow(const svOpenArrayHandle h, int d);
+    pub fn svLow(h: svOpenArrayHandle, d: c_int) -> c_int;
+
+    // int svSize(const svOpenArrayHandle h, int d);
+    pub fn svSize(h: svOpenArrayHandle, d: c_int) -> c_int;
+
+    // void *svGetArrayPtr(const svOpenArrayHandle);
+    pub fn svGetArrayPtr(h: svOpenArrayHandle) -> *mut c_void;
+
+    // void *svGetArrElemPtr1(const svOpenArrayHandle, int indx1);
+    pub fn svGetArrElemPtr1(h: svOpenArrayHandle, indx1: c_int) -> *mut c_void;
+}
+
+#[cfg(test)]
+pub use mock::*;
+
+/// Stand-ins for the simulator's open array functions, so that unit tests
+/// don't need a simulator to link against. An `svOpenArrayHandle` must point
+/// to a [`MockOpenArray`].
+#[cfg(test)]
+mod mock {
+    use super::*;
+
+    /// A one-dimensional open array of `u64` elements.
+    pub struct MockOpenArray {
+        pub low: c_int,
+        pub elems: Vec<u64>,
+        /// Whether `svGetArrayPtr` provides the elements.
+        pub contiguous: bool,
+        /// An index for which `svGetArrElemPtr1` returns null.
+        pub missing: Option<c_int>,
+    }
+
+    unsafe fn mock<'a>(h: svOpenArrayHandle) -> &'a MockOpenArray {
+        unsafe { &*(h as *const MockOpenArray) }
+    }
+
+    pub unsafe fn svLow(h: svOpenArrayHandle, d: c_int) -> c_int {
+        assert_eq!(d, 1);
+        unsafe { mock(h) }.low
+    }
+
+    pub unsafe fn svSize(h: svOpenArrayHandle, d: c_int) -> c_int {
+        assert_eq!(d, 1);
+        unsafe { mock(h) }.elems.len() as c_int
+    }
+
+    pub unsafe fn svGetArrayPtr(h: svOpenArrayHandle) -> *mut c_void {
+        let arr = unsafe { mock(h) };
+        if arr.contiguous {
+            arr.elems.as_ptr() as *mut c_void
+        } else {
+            std::ptr::null_mut()
+        }
+    }
+
+    pub unsafe fn svGetArrElemPtr1(h: svOpenArrayHandle, indx1: c_int) -> *mut c_void {
+        let arr = unsafe { mock(h) };
+        if arr.missing == Some(indx1) {
+            return std::ptr::null_mut();
+        }
+        match arr.elems.get((indx1 - arr.low) as usize) {
+            Some(elem) => elem as *const u64 as *mut c_void,
+            None => std::ptr::null_mut(),
+        }
+    }
+}
@@ -23,6 +23,12 @@ module top;
   counter error_rate;
   counter processing_time_us;
 
+  derived_counter bytes_per_us;
+  derived_counter avg_packets;
+  derived_counter total_bytes;
+
+  histogram latency;
+
   initial begin
     // Initialize cspect context
     cspect = new("trace_counters.pftrace");
@@ -194,6 +200,50 @@ module top;
       processing_time_us.log_int(longint'($urandom_range(5, 25)));
     end
 
+    #30;
+
+    // ========================================
+    // 5. DERIVED COUNTERS - Sampled from increments
+    // ========================================
+
+    // Derived counters take raw increments and plot a rate, moving average or
+    // cumulative sum, sampled at a fixed interval:
+
+    bytes_per_us = parent.new_derived_counter(
+        "BytesPerUs", Rate, 100ns,
+        /* rate per 1us: */
+        .kind_param(1000)
+    );
+    avg_packets = parent.new_derived_counter(
+        "AvgPackets", MovingAverage, 100ns,
+        /* average over the last 4 samples: */
+        .kind_param(4)
+    );
+    total_bytes = parent.new_derived_counter("TotalBytes", Cumulative, 100ns);
+
+    for (int i = 0; i < 95; i++) begin
+      automatic int len = (i % 4) * 16;
+      #10;
+      bytes_per_us.add(len);
+      avg_packets.add(len != 0);
+      total_bytes.add(len);
+    end
+
+    // ========================================
+    // 6. HISTOGRAMS - Value distributions
+    // ========================================
+
+    // Histograms count recorded values per bucket (here: <10, [10, 20),
+    // [20, 50), >=50). A summary with min/max/mean/p50/p99 is emitted when the
+    // context is finished:
+
+    latency = parent.new_histogram("Latency", '{10, 20, 50});
+
+    for (int i = 0; i < 100; i++) begin
+      #10;
+      latency.record(5 + (i * 7) % 76);
+    end
+
     $display("=== Demo Complete ===");
 
     // Clean up
@@ -6,6 +6,8 @@ package cspect_pkg;
   // Forward typedefs:
   typedef class track;
   typedef class counter;
+  typedef class derived_counter;
+  typedef class histogram;
   typedef class thread;
 
   typedef longint unsigned uuid_t;
@@ -17,62 +19,11 @@ package cspect_pkg;
     Explicit = 3
   } child_ordering_e;
 
-  typedef struct {
-    uuid_t  uuid0;
-    uuid_t  uuid1;
-    uuid_t  uuid2;
-    uuid_t  uuid3;
-    chandle others;
-  } __dpi_uuid_array_t;
-
-  function automatic __dpi_uuid_array_t __dpi_uuid_vec(uuid_t uuids[]);
-    automatic __dpi_uuid_array_t result;
-    automatic chandle array = 0;
-
-    if (uuids.size() > 4) begin
-      for (int i = 4; i < uuids.size(); i += 4) begin
-        if (array == null) begin
-          array = cspect_dpi_uuid_vec_new(
-              uuids.size() > i + 0 ? uuids[i+0] : 0,
-              uuids.size() > i + 1 ? uuids[i+1] : 0,
-              uuids.size() > i + 2 ? uuids[i+2] : 0,
-              uuids.size() > i + 3 ? uuids[i+3] : 0
-          );
-          if (array == null) begin
-            $error("cspect: cspect_dpi_uuid_vec_new failed.");
-          end
-        end else begin
-          automatic int err = 0;
-          err = cspect_dpi_uuid_vec_append(
-              array,
-              uuids.size() > i + 0 ? uuids[i+0] : 0,
-              uuids.size() > i + 1 ? uuids[i+1] : 0,
-              uuids.size() > i + 2 ? uuids[i+2] : 0,
-              uuids.size() > i + 3 ? uuids[i+3] : 0
-          );
-          if (err != 0) begin
-            $error("cspect: cspect_dpi_uuid_vec_append failed with error code %0d.", err);
-          end
-        end
-      end
-    end
-
-    result.uuid0  = uuids.size() > 0 ? uuids[0] : 0;
-    result.uuid1  = uuids.size() > 1 ? uuids[1] : 0;
-    result.uuid2  = uuids.size() > 2 ? uuids[2] : 0;
-    result.uuid3  = uuids.size() > 3 ? uuids[3] : 0;
-    result.others = array;
-    return result;
-  endfunction
-
-  function automatic void __dpi_uuid_vec_delete(__dpi_uuid_array_t vec);
-    if (vec.others != 0) begin
-      automatic int result = cspect_dpi_uuid_vec_delete(vec.others);
-      if (result != 0) begin
-        $error("cspect: cspect_dpi_uuid_vec_delete failed with error code %0d.", result);
-      end
-    end
-  endfunction
+  typedef enum int {
+    Rate = `CSPECT_DERIVED_RATE,
+    MovingAverage = `CSPECT_DERIVED_MOVING_AVERAGE,
+    Cumulative = `CSPECT_DERIVED_CUMULATIVE
+  } derived_counter_kind_e;
 
   class cspect_ctx_chandle;
     chandle ctx_chandle;
@@ -134,6 +85,63 @@ package cspect_pkg;
       return new_counter;
     endfunction
 
+    // Histogram of the recorded values, with buckets delimited by `bounds`.
+    // Emits one counter track per bucket, or - if `snapshot_interval` is given -
+    // periodic instant events with all bucket counts. A summary (min, max, mean,
+    // p50, p99) is emitted when the histogram or context is finished.
+    function histogram new_histogram(string name, real bounds[], realtime snapshot_interval = 0,
+                                     string description = "", int child_order_rank = 0);
+      histogram new_histogram;
+      uuid_t uuid = cspect_dpi_new_histogram(
+          ctx_chandle,
+          name,
+          bounds,
+          snapshot_interval,
+          this.scope_uuid,
+          description,
+          child_order_rank
+      );
+      if (uuid == null) begin
+        $error("cspect: cspect_dpi_new_histogram failed for histogram '%s'.", name);
+        return null;
+      end
+      new_histogram = new(this.ctx_chandle, uuid);
+      return new_histogram;
+    endfunction
+
+    // Counter plotting the rate, moving average or cumulative sum of the
+    // increments added to it, sampled every `sample_interval`. `kind_param` is
+    // the time unit of a rate (defaults to `sample_interval`) resp. the number
+    // of samples of a moving average.
+    function derived_counter new_derived_counter(
+        string name, derived_counter_kind_e kind, realtime sample_interval, real kind_param = 0,
+        string unit_name = "", string description = "", child_ordering_e child_ordering = Unknown,
+        int child_order_rank = 0);
+      derived_counter new_counter;
+      uuid_t uuid;
+      if (kind == Rate && kind_param == 0) begin
+        kind_param = sample_interval;
+      end
+      uuid = cspect_dpi_new_derived_counter(
+          ctx_chandle,
+          name,
+          unit_name,
+          kind,
+          kind_param,
+          sample_interval,
+          this.scope_uuid,
+          description,
+          child_ordering,
+          child_order_rank
+      );
+      if (uuid == null) begin
+        $error("cspect: cspect_dpi_new_derived_counter failed for counter '%s'.", name);
+        return null;
+      end
+      new_counter = new(this.ctx_chandle, uuid);
+      return new_counter;
+    endfunction
+
 
   endclass
 
@@ -145,128 +153,85 @@ package cspect_pkg;
     function void slice_begin(string name, uuid_t flows[] = {}, uuid_t flows_end[] = {},
                               uuid_t correlation_id = 0);
       automatic int result;
-      automatic __dpi_uuid_array_t dpi_flows, dpi_flows_end;
-      dpi_flows = __dpi_uuid_vec(flows);
-      dpi_flows_end = __dpi_uuid_vec(flows_end);
-      result = cspect_dpi_slice_begin(
+      result = cspect_dpi_slice_begin_arr(
           this.ctx_chandle,
           this.scope_uuid,
           $realtime,
           name,
-          dpi_flows.uuid0,
-          dpi_flows.uuid1,
-          dpi_flows.uuid2,
-          dpi_flows.uuid3,
-          dpi_flows.others,
-          dpi_flows_end.uuid0,
-          dpi_flows_end.uuid1,
-          dpi_flows_end.uuid2,
-          dpi_flows_end.uuid3,
-          dpi_flows_end.others,
+          flows,
+          flows_end,
           `CSPECT_REPLACE_OFF,
           correlation_id
       );
       if (result != 0) begin
-        $error("cspect: cspect_dpi_slice_begin failed for slice '%s' with error code %0d.", name,
-               result);
+        $error(
+            "cspect: cspect_dpi_slice_begin_arr failed for slice '%s' with error code %0d.",
+            name,
+            result
+        );
       end
-      __dpi_uuid_vec_delete(dpi_flows);
-      __dpi_uuid_vec_delete(dpi_flows_end);
     endfunction
 
     function void slice_set(string name, uuid_t flows[] = {}, uuid_t flows_end[] = {},
                             bit compress = 0, uuid_t correlation_id = 0);
       automatic int result;
       automatic int replacement_behaviour;
-      automatic __dpi_uuid_array_t dpi_flows, dpi_flows_end;
-      dpi_flows = __dpi_uuid_vec(flows);
-      dpi_flows_end = __dpi_uuid_vec(flows_end);
       replacement_behaviour = compress ? `CSPECT_REPLACE_IF_DIFFERENT : `CSPECT_REPLACE;
-      result = cspect_dpi_slice_begin(
+      result = cspect_dpi_slice_begin_arr(
           this.ctx_chandle,
           this.scope_uuid,
           $realtime,
           name,
-          dpi_flows.uuid0,
-          dpi_flows.uuid1,
-          dpi_flows.uuid2,
-          dpi_flows.uuid3,
-          dpi_flows.others,
-          dpi_flows_end.uuid0,
-          dpi_flows_end.uuid1,
-          dpi_flows_end.uuid2,
-          dpi_flows_end.uuid3,
-          dpi_flows_end.others,
+          flows,
+          flows_end,
           replacement_behaviour,
           correlation_id
       );
       if (result != 0) begin
-        $error("cspect: cspect_dpi_slice_begin failed for slice '%s' with error code %0d.", name,
-               result);
+        $error(
+            "cspect: cspect_dpi_slice_begin_arr failed for slice '%s' with error code %0d.",
+            name,
+            result
+        );
       end
-      __dpi_uuid_vec_delete(dpi_flows);
-      __dpi_uuid_vec_delete(dpi_flows_end);
     endfunction
 
     function void slice_end(uuid_t flows[] = {}, uuid_t flows_end[] = {}, bit force_end = 0,
                             uuid_t correlation_id = 0);
       automatic int result;
-      automatic __dpi_uuid_array_t dpi_flows, dpi_flows_end;
-      dpi_flows = __dpi_uuid_vec(flows);
-      dpi_flows_end = __dpi_uuid_vec(flows_end);
-      result = cspect_dpi_slice_end(
+      result = cspect_dpi_slice_end_arr(
           this.ctx_chandle,
           this.scope_uuid,
           $realtime,
-          dpi_flows.uuid0,
-          dpi_flows.uuid1,
-          dpi_flows.uuid2,
-          dpi_flows.uuid3,
-          dpi_flows.others,
-          dpi_flows_end.uuid0,
-          dpi_flows_end.uuid1,
-          dpi_flows_end.uuid2,
-          dpi_flows_end.uuid3,
-          dpi_flows_end.others,
+          flows,
+          flows_end,
           force_end,
           correlation_id
       );
       if (result != 0) begin
-        $error("cspect: cspect_dpi_slice_end failed with error code %0d.", result);
+        $error("cspect: cspect_dpi_slice_end_arr failed with error code %0d.", result);
       end
-      __dpi_uuid_vec_delete(dpi_flows);
-      __dpi_uuid_vec_delete(dpi_flows_end);
     endfunction
 
     function void instant_evt(string name, uuid_t flows[] = {}, uuid_t flows_end[] = {},
                               uuid_t correlation_id = 0);
       automatic int result;
-      automatic __dpi_uuid_array_t dpi_flows, dpi_flows_end;
-      dpi_flows = __dpi_uuid_vec(flows);
-      dpi_flows_end = __dpi_uuid_vec(flows_end);
-      result = cspect_dpi_instant_evt(
+      result = cspect_dpi_instant_evt_arr(
           this.ctx_chandle,
           this.scope_uuid,
           $realtime,
           name,
-          dpi_flows.uuid0,
-          dpi_flows.uuid1,
-          dpi_flows.uuid2,
-          dpi_flows.uuid3,
-          dpi_flows.others,
-          dpi_flows_end.uuid0,
-          dpi_flows_end.uuid1,
-          dpi_flows_end.uuid2,
-          dpi_flows_end.uuid3,
-          dpi_flows_end.others,
+          flows,
+          flows_end,
           correlation_id
       );
       if (result != 0) begin
-        $error("cspect: cspect_dpi_instant_evt failed for event '%s' with error code %0d.", name,
-               result);
+        $error(
+            "cspect: cspect_dpi_instant_evt_arr failed for event '%s' with error code %0d.",
+            name,
+            result
+        );
       end
-      __dpi_uuid_vec_delete(dpi_flows);
-      __dpi_uuid_vec_delete(dpi_flows_end);
     endfunction
   endclass
 
@@ -299,6 +264,71 @@ package cspect_pkg;
         $error("cspect: cspect_dpi_float_counter_evt failed with error code %0d.", result);
       end
     endfunction
+
+    // Reduce the logged values written to the trace: drop values within
+    // `deadband` of the last one, keep only the min/max/final value of each
+    // `decimate` bucket, and write at most one value per `min_interval`.
+    // Zero disables the respective criterion.
+    function void set_sampling(real deadband = 0, realtime decimate = 0,
+                               realtime min_interval = 0);
+      automatic
+      int
+      result = cspect_dpi_set_counter_sampling(
+          this.ctx_chandle, this.counter_uuid, deadband, decimate, min_interval
+      );
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_set_counter_sampling failed with error code %0d.", result);
+      end
+    endfunction
+  endclass
+
+  class histogram extends cspect_ctx_chandle;
+    uuid_t histogram_uuid;
+
+    function new(chandle handle, uuid_t uuid);
+      super.new(handle);
+      histogram_uuid = uuid;
+    endfunction
+
+    function void record(real value);
+      automatic
+      int
+      result = cspect_dpi_histogram_record(
+          this.ctx_chandle, this.histogram_uuid, $realtime, value
+      );
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_histogram_record failed with error code %0d.", result);
+      end
+    endfunction
+
+    // Emit the summary now instead of when the context is finished. No values
+    // can be recorded afterwards.
+    function void finish();
+      automatic int result = cspect_dpi_finish_histogram(this.ctx_chandle, this.histogram_uuid);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_finish_histogram failed with error code %0d.", result);
+      end
+    endfunction
+  endclass
+
+  class derived_counter extends cspect_ctx_chandle;
+    uuid_t counter_uuid;
+
+    function new(chandle handle, uuid_t uuid);
+      super.new(handle);
+      counter_uuid = uuid;
+    endfunction
+
+    function void add(real increment = 1);
+      automatic
+      int
+      result = cspect_dpi_derived_counter_evt(
+          this.ctx_chandle, this.counter_uuid, $realtime, increment
+      );
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_derived_counter_evt failed with error code %0d.", result);
+      end
+    endfunction
   endclass
 
   class process extends track;
@@ -331,11 +361,26 @@ package cspect_pkg;
   endclass
 
   class ctx extends scope;
-    function new(string trace_path, int unsigned time_mult = 1);
+    // If `resume_state` is given, the trace is resumed from a state file
+    // written by `save_state` instead of being created from scratch.
+    // If `exit_hooks` is set, the trace is flushed at exit and when the
+    // simulator is terminated by a signal, even if `finish` is never called.
+    // `metadata` holds newline-separated `key=value` pairs written into the
+    // trace next to the automatically collected run information.
+    function new(string trace_path, int unsigned time_mult = 1, string resume_state = "",
+                 bit exit_hooks = 0, string metadata = "");
       super.new(0, 0);
-      this.ctx_chandle = cspect_dpi_new(trace_path, 0.000000001, time_mult);
-      if (this.ctx_chandle == null) begin
-        $error("cspect:  cspect_dpi_new failed.");
+      if (resume_state != "") begin
+        this.ctx_chandle = cspect_dpi_resume(trace_path, resume_state, exit_hooks);
+        if (this.ctx_chandle == null) begin
+          $error("cspect:  cspect_dpi_resume failed.");
+        end
+      end else begin
+        this.ctx_chandle = cspect_dpi_new(trace_path, 0.000000001, time_mult, exit_hooks,
+                                          metadata);
+        if (this.ctx_chandle == null) begin
+          $error("cspect:  cspect_dpi_new failed.");
+        end
       end
     endfunction
 
@@ -348,6 +393,14 @@ package cspect_pkg;
       this.ctx_chandle = null;
     endfunction
 
+    // Record run information (e.g. test name, seed, git revision) in the trace.
+    function void add_metadata(string key, string value);
+      automatic int result = cspect_dpi_add_metadata(this.ctx_chandle, key, value);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_add_metadata failed with error code %0d.", result);
+      end
+    endfunction
+
     function void flush();
       automatic int result = cspect_dpi_flush(this.ctx_chandle);
       if (result != 0) begin
@@ -355,6 +408,55 @@ package cspect_pkg;
       end
     endfunction
 
+    // Flush the trace every `every_events` events, `every_bytes` bytes, or
+    // `every_time` time units (whichever comes first). Zero disables a criterion.
+    function void set_flush_policy(longint unsigned every_events = 0,
+                                   longint unsigned every_bytes = 0, realtime every_time = 0);
+      automatic int result = cspect_dpi_set_flush_policy(
+          this.ctx_chandle, every_events, every_bytes, every_time
+      );
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_set_flush_policy failed with error code %0d.", result);
+      end
+    endfunction
+
+    function void save_state(string state_path);
+      automatic int result = cspect_dpi_save_state(this.ctx_chandle, state_path);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_save_state failed with error code %0d.", result);
+      end
+    endfunction
+
+    // Don't record the creation time, hostname and command line in the trace,
+    // so that traces of identical runs are identical. Must be called before the
+    // trace is flushed for the first time.
+    function void omit_volatile_metadata();
+      automatic int result = cspect_dpi_omit_volatile_metadata(this.ctx_chandle);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_omit_volatile_metadata failed with error code %0d.", result);
+      end
+    endfunction
+
+    // Derive the UUIDs of all tracks created afterwards from `salt` and their
+    // path (e.g. the test name), so that they are stable across runs and seeds.
+    // Implies `omit_volatile_metadata`.
+    function void set_uuid_salt(string salt);
+      automatic int result = cspect_dpi_set_uuid_salt(this.ctx_chandle, salt);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_set_uuid_salt failed with error code %0d.", result);
+      end
+    endfunction
+
+    // Use `sequence_id` as trusted packet sequence ID for all further events
+    // of the calling thread, instead of the automatically allocated one. Only
+    // required if other tools write into the same trace.
+    function void set_sequence_id(int unsigned sequence_id);
+      automatic int result = cspect_dpi_set_sequence_id(this.ctx_chandle, sequence_id);
+      if (result != 0) begin
+        $error("cspect: cspect_dpi_set_sequence_id failed with error code %0d.", result);
+      end
+    endfunction
+
     function process new_process(int pid, string process_name, string cmdline = "", int prio = 0,
                                  string description = "", child_ordering_e child_ordering = Unknown,
                                  int child_order_rank = 0);
@@ -0,0 +1,101 @@
+//! Compare prost-based and hand-rolled (`synthetto::raw`) event encoding on a
+//! workload resembling the `cpu` example: Per cycle, a few register tracks
+//! change their slice (end + begin with a short hex name), memory accesses are
+//! logged as instant events, and one counter is updated.
+//!
+//! Run with `cargo run --release -p synthetto --example encode_bench`.
+
+use std::{hint::black_box, time::Instant};
+
+const CYCLES: u64 = 1_000_000;
+const SEQUENCE_ID: u32 = 42;
+
+// Names are formatted up-front in both cases, as the simulator hands them over
+// as strings:
+fn names() -> (Vec<String>, Vec<String>) {
+    let regs = (0..256).map(|i| format!("0x{i:02x}")).collect();
+    let ops = (0..256)
+        .map(|i| format!("Read @ 0x{i:02x}: 0x{:02x}", 255 - i))
+        .collect();
+    (regs, ops)
+}
+
+fn bench(label: &str, mut cycle: impl FnMut(u64, &mut Vec<u8>)) {
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let mut bytes = 0;
+    let start = Instant::now();
+    for i in 0..CYCLES {
+        cycle(i, &mut buf);
+        // Hand over to the writer in chunks, as cspect does:
+        if buf.len() > 60 * 1024 {
+            bytes += buf.len();
+            black_box(&buf);
+            buf.clear();
+        }
+    }
+    bytes += buf.len();
+    let elapsed = start.elapsed().as_secs_f64();
+    let events = CYCLES * 8;
+    println!(
+        "{label:>6}: {:>7.2} Mevents/s ({:.1} MB)",
+        events as f64 / elapsed / 1e6,
+        bytes as f64 / 1e6
+    );
+}
+
+fn main() {
+    let (regs, ops) = names();
+
+    bench("prost", |i, buf| {
+        let ts = i * 10;
+        for track in 0..3u64 {
+            let name = &regs[((i + track) % 256) as usize];
+            synthetto::slice_end_evt(track, ts, vec![], vec![], None, SEQUENCE_ID, buf).unwrap();
+            synthetto::slice_begin_evt(
+                track,
+                ts,
+                Some(name.clone()),
+                vec![],
+                vec![],
+                None,
+                SEQUENCE_ID,
+                buf,
+            )
+            .unwrap();
+        }
+        let op = &ops[(i % 256) as usize];
+        synthetto::instant_evt(
+            10,
+            ts,
+            Some(op.clone()),
+            vec![],
+            vec![],
+            None,
+            SEQUENCE_ID,
+            buf,
+        )
+        .unwrap();
+        synthetto::int_counter_evt(11, ts, i as i64, SEQUENCE_ID, buf).unwrap();
+    });
+
+    bench("raw", |i, buf| {
+        let ts = i * 10;
+        for track in 0..3u64 {
+            let name = &regs[((i + track) % 256) as usize];
+            synthetto::raw::slice_end_evt(track, ts, &[], &[], None, SEQUENCE_ID, buf);
+            synthetto::raw::slice_begin_evt(
+                track,
+                ts,
+                Some(name),
+                &[],
+                &[],
+                None,
+                SEQUENCE_ID,
+                buf,
+            );
+        }
+        let op = &ops[(i % 256) as usize];
+        synthetto::raw::instant_evt(10, ts, Some(op), &[], &[], None, SEQUENCE_ID, buf);
+        synthetto::raw::int_counter_evt(11, ts, i as i64, SEQUENCE_ID, buf);
+    });
+}
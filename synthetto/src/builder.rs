@@ -0,0 +1,592 @@
+//! Builders for track descriptors and track events.
+//!
+//! Every field of `TrackDescriptor` and `TrackEvent` in the minimal proto has
+//! a setter; unset fields are left out of the packet. Descriptors get their
+//! UUID from a [`Synthetto`] when written, and both descriptors and events are
+//! written with its [`Synthetto::sequence_id`] unless set explicitly:
+//!
+//! ```
+//! use synthetto::{CounterBuilder, CounterTrackUnit, SliceEvent, Synthetto, TrackBuilder};
+//!
+//! let mut synthetto = Synthetto::new();
+//! let mut buf = vec![];
+//! let cpu = TrackBuilder::new("CPU")
+//!     .description("Instructions retired")
+//!     .write(&mut synthetto, &mut buf)?;
+//! let ipc = CounterBuilder::new("IPC")
+//!     .parent(cpu)
+//!     .unit(CounterTrackUnit::Count)
+//!     .write(&mut synthetto, &mut buf)?;
+//! SliceEvent::begin(cpu, 100)
+//!     .name("fetch")
+//!     .flows(vec![1])
+//!     .write(&synthetto, &mut buf)?;
+//! # Ok::<(), synthetto::EncodeError>(())
+//! ```
+
+use prost::{bytes::BufMut, EncodeError, Message};
+
+use crate::{
+    protos::{self, debug_annotation, track_descriptor, track_event, DebugAnnotation, TracePacket},
+    sequence_id_field, ChildOrder, CounterTrackUnit, Synthetto, UUID_KIND_COUNTER,
+    UUID_KIND_PROCESS, UUID_KIND_THREAD, UUID_KIND_TRACK,
+};
+
+/// How the UI merges a track with siblings of the same name or key.
+pub enum SiblingMerge {
+    /// Merge with siblings of the same name.
+    ByTrackName,
+    /// Never merge.
+    None,
+    /// Merge with siblings with the same key.
+    ByKey(String),
+}
+
+fn write_packet<B: BufMut>(packet: TracePacket, buf: &mut B) -> Result<(), EncodeError> {
+    buf.put_u8(0x0A);
+    packet.encode_length_delimited(buf)
+}
+
+/// State shared by all descriptor builders.
+struct Descriptor {
+    uuid: Option<u64>,
+    desc: protos::TrackDescriptor,
+    sequence_id: Option<u32>,
+}
+
+impl Descriptor {
+    fn new(name: Option<String>) -> Self {
+        Self {
+            uuid: None,
+            desc: protos::TrackDescriptor {
+                static_or_dynamic_name: name.map(track_descriptor::StaticOrDynamicName::Name),
+                ..protos::TrackDescriptor::default()
+            },
+            sequence_id: None,
+        }
+    }
+
+    fn write<B: BufMut>(
+        mut self,
+        kind: u8,
+        key: &[u8],
+        synthetto: &mut Synthetto,
+        buf: &mut B,
+    ) -> Result<u64, EncodeError> {
+        let uuid = match self.uuid {
+            Some(uuid) => uuid,
+            None => synthetto.descriptor_uuid(kind, self.desc.parent_uuid, key),
+        };
+        self.desc.uuid = Some(uuid);
+        let sequence_id = self.sequence_id.unwrap_or(synthetto.sequence_id());
+
+        let packet = TracePacket {
+            data: Some(protos::trace_packet::Data::TrackDescriptor(self.desc)),
+            optional_trusted_packet_sequence_id: sequence_id_field(sequence_id),
+            ..TracePacket::default()
+        };
+        write_packet(packet, buf)?;
+        Ok(uuid)
+    }
+}
+
+/// Setters for the fields common to all kinds of tracks.
+macro_rules! descriptor_setters {
+    () => {
+        /// Use this UUID instead of allocating one from the [`Synthetto`].
+        pub fn uuid(mut self, uuid: u64) -> Self {
+            self.inner.uuid = Some(uuid);
+            self
+        }
+
+        pub fn parent(mut self, parent_uuid: u64) -> Self {
+            self.inner.desc.parent_uuid = Some(parent_uuid);
+            self
+        }
+
+        pub fn description(mut self, description: impl Into<String>) -> Self {
+            self.inner.desc.description = Some(description.into());
+            self
+        }
+
+        pub fn child_ordering(mut self, child_ordering: ChildOrder) -> Self {
+            self.inner.desc.child_ordering = Some(child_ordering.to_proto_enum());
+            self
+        }
+
+        pub fn sibling_order_rank(mut self, rank: i32) -> Self {
+            self.inner.desc.sibling_order_rank = Some(rank);
+            self
+        }
+
+        pub fn sibling_merge(mut self, merge: SiblingMerge) -> Self {
+            let (behavior, key) = match merge {
+                SiblingMerge::ByTrackName => {
+                    (track_descriptor::SiblingMergeBehavior::ByTrackName, None)
+                }
+                SiblingMerge::None => (track_descriptor::SiblingMergeBehavior::None, None),
+                SiblingMerge::ByKey(key) => (
+                    track_descriptor::SiblingMergeBehavior::BySiblingMergeKey,
+                    Some(key),
+                ),
+            };
+            self.inner.desc.sibling_merge_behavior = Some(behavior as i32);
+            self.inner.desc.sibling_merge_key = key;
+            self
+        }
+
+        /// Trusted packet sequence ID of the descriptor packet, instead of
+        /// [`Synthetto::sequence_id`].
+        pub fn sequence_id(mut self, sequence_id: u32) -> Self {
+            self.inner.sequence_id = Some(sequence_id);
+            self
+        }
+
+        /// Apply `f` with the value of `opt`, if any.
+        pub fn maybe<T>(self, opt: Option<T>, f: impl FnOnce(Self, T) -> Self) -> Self {
+            match opt {
+                Some(value) => f(self, value),
+                None => self,
+            }
+        }
+    };
+}
+
+/// Builder of a plain named track, e.g. for slices.
+pub struct TrackBuilder {
+    inner: Descriptor,
+    name: String,
+}
+
+impl TrackBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            inner: Descriptor::new(Some(name.clone())),
+            name,
+        }
+    }
+
+    descriptor_setters!();
+
+    /// Write the descriptor, returning the track's UUID.
+    pub fn write<B: BufMut>(
+        self,
+        synthetto: &mut Synthetto,
+        buf: &mut B,
+    ) -> Result<u64, EncodeError> {
+        self.inner
+            .write(UUID_KIND_TRACK, self.name.as_bytes(), synthetto, buf)
+    }
+}
+
+/// Builder of a process track.
+pub struct ProcessBuilder {
+    inner: Descriptor,
+}
+
+impl ProcessBuilder {
+    pub fn new(pid: i32) -> Self {
+        let mut inner = Descriptor::new(None);
+        inner.desc.process = Some(protos::ProcessDescriptor {
+            pid: Some(pid),
+            ..protos::ProcessDescriptor::default()
+        });
+        Self { inner }
+    }
+
+    fn process(&mut self) -> &mut protos::ProcessDescriptor {
+        self.inner.desc.process.get_or_insert_with(Default::default)
+    }
+
+    /// Track name, overriding the process name in the UI.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.inner.desc.static_or_dynamic_name =
+            Some(track_descriptor::StaticOrDynamicName::Name(name.into()));
+        self
+    }
+
+    pub fn process_name(mut self, process_name: impl Into<String>) -> Self {
+        self.process().process_name = Some(process_name.into());
+        self
+    }
+
+    pub fn cmdline(mut self, cmdline: Vec<String>) -> Self {
+        self.process().cmdline = cmdline;
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.process().process_priority = Some(priority);
+        self
+    }
+
+    pub fn start_timestamp_ns(mut self, start_timestamp_ns: i64) -> Self {
+        self.process().start_timestamp_ns = Some(start_timestamp_ns);
+        self
+    }
+
+    descriptor_setters!();
+
+    /// Write the descriptor, returning the track's UUID.
+    pub fn write<B: BufMut>(
+        mut self,
+        synthetto: &mut Synthetto,
+        buf: &mut B,
+    ) -> Result<u64, EncodeError> {
+        let pid = self.process().pid.unwrap_or_default();
+        self.inner
+            .write(UUID_KIND_PROCESS, &pid.to_le_bytes(), synthetto, buf)
+    }
+}
+
+/// Builder of a thread track.
+pub struct ThreadBuilder {
+    inner: Descriptor,
+}
+
+impl ThreadBuilder {
+    pub fn new(pid: i32, tid: i32) -> Self {
+        let mut inner = Descriptor::new(None);
+        inner.desc.thread = Some(protos::ThreadDescriptor {
+            pid: Some(pid),
+            tid: Some(tid),
+            thread_name: None,
+        });
+        Self { inner }
+    }
+
+    fn thread(&mut self) -> &mut protos::ThreadDescriptor {
+        self.inner.desc.thread.get_or_insert_with(Default::default)
+    }
+
+    /// Track name, overriding the thread name in the UI.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.inner.desc.static_or_dynamic_name =
+            Some(track_descriptor::StaticOrDynamicName::Name(name.into()));
+        self
+    }
+
+    pub fn thread_name(mut self, thread_name: impl Into<String>) -> Self {
+        self.thread().thread_name = Some(thread_name.into());
+        self
+    }
+
+    descriptor_setters!();
+
+    /// Write the descriptor, returning the track's UUID.
+    pub fn write<B: BufMut>(
+        mut self,
+        synthetto: &mut Synthetto,
+        buf: &mut B,
+    ) -> Result<u64, EncodeError> {
+        let thread = self.thread();
+        let key = [
+            thread.pid.unwrap_or_default().to_le_bytes(),
+            thread.tid.unwrap_or_default().to_le_bytes(),
+        ]
+        .concat();
+        self.inner.write(UUID_KIND_THREAD, &key, synthetto, buf)
+    }
+}
+
+/// Builder of a counter track.
+pub struct CounterBuilder {
+    inner: Descriptor,
+    name: String,
+}
+
+impl CounterBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        let mut inner = Descriptor::new(Some(name.clone()));
+        inner.desc.counter = Some(protos::CounterDescriptor::default());
+        Self { inner, name }
+    }
+
+    fn counter(&mut self) -> &mut protos::CounterDescriptor {
+        self.inner.desc.counter.get_or_insert_with(Default::default)
+    }
+
+    pub fn categories(mut self, categories: Vec<String>) -> Self {
+        self.counter().categories = categories;
+        self
+    }
+
+    pub fn unit(mut self, unit: CounterTrackUnit) -> Self {
+        let counter = self.counter();
+        counter.unit = unit.to_proto_unit();
+        counter.unit_name = unit.to_proto_unit_name();
+        self
+    }
+
+    /// Factor all values are multiplied with, e.g. 1000 for values in µs with
+    /// [`CounterTrackUnit::TimeNs`].
+    pub fn unit_multiplier(mut self, unit_multiplier: i64) -> Self {
+        self.counter().unit_multiplier = Some(unit_multiplier);
+        self
+    }
+
+    /// Whether values are deltas to be summed up, rather than absolute.
+    pub fn is_incremental(mut self, is_incremental: bool) -> Self {
+        self.counter().is_incremental = Some(is_incremental);
+        self
+    }
+
+    /// Counters with the same key share the range of their y axis.
+    pub fn y_axis_share_key(mut self, key: impl Into<String>) -> Self {
+        self.counter().y_axis_share_key = Some(key.into());
+        self
+    }
+
+    descriptor_setters!();
+
+    /// Write the descriptor, returning the track's UUID.
+    pub fn write<B: BufMut>(
+        self,
+        synthetto: &mut Synthetto,
+        buf: &mut B,
+    ) -> Result<u64, EncodeError> {
+        self.inner
+            .write(UUID_KIND_COUNTER, self.name.as_bytes(), synthetto, buf)
+    }
+}
+
+/// State shared by all event builders.
+struct Event {
+    ts: u64,
+    evt: protos::TrackEvent,
+    sequence_id: Option<u32>,
+}
+
+impl Event {
+    fn new(ty: track_event::Type, track_uuid: u64, ts: u64) -> Self {
+        Self {
+            ts,
+            evt: protos::TrackEvent {
+                r#type: Some(ty as i32),
+                track_uuid: Some(track_uuid),
+                ..protos::TrackEvent::default()
+            },
+            sequence_id: None,
+        }
+    }
+
+    fn write<B: BufMut>(self, sequence_id: u32, buf: &mut B) -> Result<(), EncodeError> {
+        let packet = TracePacket {
+            timestamp: Some(self.ts),
+            data: Some(protos::trace_packet::Data::TrackEvent(self.evt)),
+            optional_trusted_packet_sequence_id: sequence_id_field(sequence_id),
+            ..TracePacket::default()
+        };
+        write_packet(packet, buf)
+    }
+}
+
+/// Setters for the fields common to all kinds of events.
+macro_rules! event_setters {
+    () => {
+        pub fn categories(mut self, categories: Vec<String>) -> Self {
+            self.inner.evt.categories = categories;
+            self
+        }
+
+        /// Add a debug annotation, shown as argument of the event.
+        pub fn arg(mut self, name: impl Into<String>, value: debug_annotation::Value) -> Self {
+            self.inner
+                .evt
+                .debug_annotations
+                .push(crate::debug_arg(name.into(), value));
+            self
+        }
+
+        pub fn args(mut self, args: Vec<DebugAnnotation>) -> Self {
+            self.inner.evt.debug_annotations.extend(args);
+            self
+        }
+
+        /// Trusted packet sequence ID of the event packet, instead of
+        /// [`Synthetto::sequence_id`].
+        pub fn sequence_id(mut self, sequence_id: u32) -> Self {
+            self.inner.sequence_id = Some(sequence_id);
+            self
+        }
+
+        /// Apply `f` with the value of `opt`, if any.
+        pub fn maybe<T>(self, opt: Option<T>, f: impl FnOnce(Self, T) -> Self) -> Self {
+            match opt {
+                Some(value) => f(self, value),
+                None => self,
+            }
+        }
+
+        /// Write the event, on the sequence of descriptor packets unless set
+        /// with `sequence_id`.
+        pub fn write<B: BufMut>(
+            self,
+            synthetto: &Synthetto,
+            buf: &mut B,
+        ) -> Result<(), EncodeError> {
+            let sequence_id = self.inner.sequence_id.unwrap_or(synthetto.sequence_id());
+            self.inner.write(sequence_id, buf)
+        }
+
+        /// Write the event with `sequence_id`, for writers that allocated
+        /// their own sequence.
+        pub fn write_on_sequence<B: BufMut>(
+            self,
+            sequence_id: u32,
+            buf: &mut B,
+        ) -> Result<(), EncodeError> {
+            self.inner.write(sequence_id, buf)
+        }
+    };
+}
+
+/// Builder of slice begin/end and instant events.
+pub struct SliceEvent {
+    inner: Event,
+}
+
+impl SliceEvent {
+    pub fn begin(track_uuid: u64, ts: u64) -> Self {
+        Self {
+            inner: Event::new(track_event::Type::SliceBegin, track_uuid, ts),
+        }
+    }
+
+    pub fn end(track_uuid: u64, ts: u64) -> Self {
+        Self {
+            inner: Event::new(track_event::Type::SliceEnd, track_uuid, ts),
+        }
+    }
+
+    pub fn instant(track_uuid: u64, ts: u64) -> Self {
+        Self {
+            inner: Event::new(track_event::Type::Instant, track_uuid, ts),
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.inner.evt.name_field = Some(track_event::NameField::Name(name.into()));
+        self
+    }
+
+    /// Flows this event starts or continues.
+    pub fn flows(mut self, flows: Vec<u64>) -> Self {
+        self.inner.evt.flow_ids = flows;
+        self
+    }
+
+    /// Flows this event terminates.
+    pub fn flows_end(mut self, flows_end: Vec<u64>) -> Self {
+        self.inner.evt.terminating_flow_ids = flows_end;
+        self
+    }
+
+    pub fn correlation_id(mut self, id: u64) -> Self {
+        self.inner.evt.correlation_id_field =
+            Some(track_event::CorrelationIdField::CorrelationId(id));
+        self
+    }
+
+    pub fn correlation_id_str(mut self, id: impl Into<String>) -> Self {
+        self.inner.evt.correlation_id_field =
+            Some(track_event::CorrelationIdField::CorrelationIdStr(id.into()));
+        self
+    }
+
+    /// Correlation ID given as interned string ID.
+    pub fn correlation_id_str_iid(mut self, iid: u64) -> Self {
+        self.inner.evt.correlation_id_field =
+            Some(track_event::CorrelationIdField::CorrelationIdStrIid(iid));
+        self
+    }
+
+    event_setters!();
+}
+
+/// Builder of counter value events.
+pub struct CounterEvent {
+    inner: Event,
+}
+
+impl CounterEvent {
+    pub fn int(track_uuid: u64, ts: u64, val: i64) -> Self {
+        let mut inner = Event::new(track_event::Type::Counter, track_uuid, ts);
+        inner.evt.counter_value_field = Some(track_event::CounterValueField::CounterValue(val));
+        Self { inner }
+    }
+
+    pub fn float(track_uuid: u64, ts: u64, val: f64) -> Self {
+        let mut inner = Event::new(track_event::Type::Counter, track_uuid, ts);
+        inner.evt.counter_value_field =
+            Some(track_event::CounterValueField::DoubleCounterValue(val));
+        Self { inner }
+    }
+
+    event_setters!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_builder() {
+        let mut synthetto = Synthetto::new();
+        let mut buf = vec![];
+        let uuid = CounterBuilder::new("Latency")
+            .parent(7)
+            .unit(CounterTrackUnit::TimeNs)
+            .unit_multiplier(1000)
+            .y_axis_share_key("latency")
+            .sibling_merge(SiblingMerge::ByKey("lat".into()))
+            .write(&mut synthetto, &mut buf)
+            .unwrap();
+
+        let trace = protos::Trace::decode(buf.as_slice()).unwrap();
+        let Some(protos::trace_packet::Data::TrackDescriptor(desc)) = &trace.packet[0].data else {
+            panic!("expected track descriptor");
+        };
+        assert_eq!(desc.uuid, Some(uuid));
+        assert_eq!(desc.parent_uuid, Some(7));
+        assert_eq!(desc.sibling_merge_key.as_deref(), Some("lat"));
+        let counter = desc.counter.as_ref().unwrap();
+        assert_eq!(counter.unit_multiplier, Some(1000));
+        assert_eq!(counter.y_axis_share_key.as_deref(), Some("latency"));
+        assert_eq!(counter.is_incremental, None);
+    }
+
+    #[test]
+    fn test_event_sequence_id() {
+        let mut synthetto = Synthetto::new();
+        let mut buf = vec![];
+        let uuid = TrackBuilder::new("track")
+            .write(&mut synthetto, &mut buf)
+            .unwrap();
+        SliceEvent::instant(uuid, 10)
+            .write(&synthetto, &mut buf)
+            .unwrap();
+        SliceEvent::instant(uuid, 20)
+            .sequence_id(7)
+            .write(&synthetto, &mut buf)
+            .unwrap();
+
+        // Descriptors and events default to the same sequence:
+        let trace = protos::Trace::decode(buf.as_slice()).unwrap();
+        let sequence_ids: Vec<_> = trace
+            .packet
+            .iter()
+            .map(|packet| packet.optional_trusted_packet_sequence_id)
+            .collect();
+        assert_eq!(
+            sequence_ids,
+            vec![
+                sequence_id_field(synthetto.sequence_id()),
+                sequence_id_field(synthetto.sequence_id()),
+                sequence_id_field(7),
+            ]
+        );
+    }
+}
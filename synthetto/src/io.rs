@@ -0,0 +1,386 @@
+//! Streaming reading and writing of trace files.
+//!
+//! A trace file is a serialized `Trace` message: a sequence of top-level
+//! fields, each `TracePacket` framed as field 1 (tag byte `0x0A`) followed by
+//! its varint length. [`TraceReader`] yields the packets one by one without
+//! holding the whole trace in memory, [`TraceWriter`] writes them.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use flate2::bufread::MultiGzDecoder;
+use prost::{DecodeError, Message};
+
+use crate::protos::TracePacket;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// Wire types:
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// Field number of `Trace.packet`.
+const TRACE_PACKET_FIELD: u64 = 1;
+
+/// Error while reading a trace. All offsets are in bytes from the start of
+/// the (decompressed) trace.
+#[derive(Debug)]
+pub enum ReadError {
+    Io {
+        offset: u64,
+        source: io::Error,
+    },
+    /// The trace ended within the top-level field starting at `offset`, which
+    /// was `len` bytes long but only had `available` bytes left.
+    Truncated {
+        offset: u64,
+        len: u64,
+        available: u64,
+    },
+    /// Invalid or unterminated varint at `offset`.
+    InvalidVarint {
+        offset: u64,
+    },
+    /// Top-level field with a wire type that can't be skipped (groups, or
+    /// invalid wire types).
+    UnsupportedWireType {
+        offset: u64,
+        field: u64,
+        wire_type: u64,
+    },
+    /// The packet at `offset` is framed correctly but can't be decoded.
+    Decode {
+        offset: u64,
+        source: DecodeError,
+    },
+}
+
+impl ReadError {
+    /// Offset of the top-level field the error occurred in.
+    pub fn offset(&self) -> u64 {
+        match self {
+            ReadError::Io { offset, .. }
+            | ReadError::Truncated { offset, .. }
+            | ReadError::InvalidVarint { offset }
+            | ReadError::UnsupportedWireType { offset, .. }
+            | ReadError::Decode { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io { offset, source } => {
+                write!(f, "Failed to read trace @ {offset} - {source}")
+            }
+            ReadError::Truncated {
+                offset,
+                len,
+                available,
+            } => write!(
+                f,
+                "Trace truncated: field @ {offset} is {len} bytes long, but only {available} remain"
+            ),
+            ReadError::InvalidVarint { offset } => {
+                write!(f, "Invalid trace: bad varint @ {offset}")
+            }
+            ReadError::UnsupportedWireType {
+                offset,
+                field,
+                wire_type,
+            } => write!(
+                f,
+                "Invalid trace: field {field} @ {offset} has unsupported wire type {wire_type}"
+            ),
+            ReadError::Decode { offset, source } => {
+                write!(f, "Failed to decode packet @ {offset} - {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io { source, .. } => Some(source),
+            ReadError::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Encoded packet as read from a trace, without its framing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawPacket {
+    /// Offset of the packet's tag byte in the trace.
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl RawPacket {
+    pub fn decode(&self) -> Result<TracePacket, ReadError> {
+        TracePacket::decode(self.data.as_slice()).map_err(|source| ReadError::Decode {
+            offset: self.offset,
+            source,
+        })
+    }
+}
+
+/// Iterator over the packets of a trace, together with their offsets.
+///
+/// Top-level fields other than `Trace.packet` are skipped. Framing errors,
+/// including a truncated final packet (e.g. of a simulation that was killed),
+/// are yielded once, after which the iteration ends. Packets that fail to
+/// decode don't affect the framing, so iteration can continue past them.
+pub struct TraceReader<R: Read> {
+    reader: BufReader<R>,
+    offset: u64,
+    done: bool,
+}
+
+impl TraceReader<Box<dyn Read>> {
+    /// Open a trace file, decompressing it if gzipped.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::detect(File::open(path)?)
+    }
+
+    /// Read a trace, decompressing it if gzipped.
+    pub fn detect(reader: impl Read + 'static) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let reader: Box<dyn Read> = if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            Box::new(MultiGzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+        Ok(TraceReader::new(reader))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Read an uncompressed trace.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read the next packet without decoding it.
+    pub fn next_raw(&mut self) -> Option<Result<RawPacket, ReadError>> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_raw().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+
+    fn read_raw(&mut self) -> Result<Option<RawPacket>, ReadError> {
+        loop {
+            let offset = self.offset;
+            let Some(key) = self.read_varint(offset, true)? else {
+                return Ok(None);
+            };
+            let (field, wire_type) = (key >> 3, key & 0x7);
+
+            let len = match wire_type {
+                VARINT => {
+                    self.read_varint(offset, false)?;
+                    continue;
+                }
+                FIXED64 => 8,
+                FIXED32 => 4,
+                LEN => self
+                    .read_varint(offset, false)?
+                    .ok_or(ReadError::InvalidVarint { offset })?,
+                _ => {
+                    return Err(ReadError::UnsupportedWireType {
+                        offset,
+                        field,
+                        wire_type,
+                    })
+                }
+            };
+
+            let mut data = Vec::new();
+            let available = (&mut self.reader)
+                .take(len)
+                .read_to_end(&mut data)
+                .map_err(|source| ReadError::Io { offset, source })?
+                as u64;
+            self.offset += available;
+            if available < len {
+                return Err(ReadError::Truncated {
+                    offset,
+                    len,
+                    available,
+                });
+            }
+
+            if field == TRACE_PACKET_FIELD && wire_type == LEN {
+                return Ok(Some(RawPacket { offset, data }));
+            }
+        }
+    }
+
+    /// Read a varint of the field at `offset`. Returns `None` if the trace
+    /// ends before its first byte and `allow_eof` is set.
+    fn read_varint(&mut self, offset: u64, allow_eof: bool) -> Result<Option<u64>, ReadError> {
+        let mut value: u64 = 0;
+        for idx in 0..10 {
+            let mut byte = [0u8];
+            let cnt = loop {
+                match self.reader.read(&mut byte) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result.map_err(|source| ReadError::Io { offset, source })?,
+                }
+            };
+            if cnt == 0 {
+                if idx == 0 && allow_eof {
+                    return Ok(None);
+                }
+                return Err(ReadError::Truncated {
+                    offset,
+                    len: self.offset - offset + 1,
+                    available: self.offset - offset,
+                });
+            }
+            self.offset += 1;
+            value |= ((byte[0] & 0x7F) as u64) << (7 * idx);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(ReadError::InvalidVarint { offset })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    /// A packet and the offset of its tag byte.
+    type Item = Result<(u64, TracePacket), ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw = match self.next_raw()? {
+            Ok(raw) => raw,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(raw.decode().map(|packet| (raw.offset, packet)))
+    }
+}
+
+/// Writes packets as a trace.
+pub struct TraceWriter<W: Write> {
+    writer: BufWriter<W>,
+    encode_buffer: Vec<u8>,
+    offset: u64,
+}
+
+impl TraceWriter<File> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            encode_buffer: Vec::with_capacity(128),
+            offset: 0,
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn write_packet(&mut self, packet: &TracePacket) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.encode_buffer);
+        buf.clear();
+        buf.push(0x0A);
+        packet
+            .encode_length_delimited(&mut buf)
+            .map_err(io::Error::other)?;
+        let result = self.write_framed(&buf);
+        self.encode_buffer = buf;
+        result
+    }
+
+    /// Write an encoded packet, such as [`RawPacket::data`].
+    pub fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(11);
+        header.push(0x0A);
+        prost::encoding::encode_varint(data.len() as u64, &mut header);
+        self.write_framed(&header)?;
+        self.write_framed(data)
+    }
+
+    /// Write already framed packets, as produced by the event and descriptor
+    /// functions of this crate.
+    pub fn write_framed(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let mut writer = TraceWriter::new(vec![]);
+        let packet = TracePacket {
+            timestamp: Some(42),
+            ..TracePacket::default()
+        };
+        writer.write_packet(&packet).unwrap();
+        // Unknown top-level fields (varint and length-delimited):
+        writer
+            .write_framed(&[0x10, 0x96, 0x01, 0x1A, 0x01, 0xFF])
+            .unwrap();
+        writer.write_packet(&packet).unwrap();
+        let mut trace = writer.into_inner().unwrap();
+        // Truncated packet:
+        trace.extend([0x0A, 0x05, 0x40]);
+
+        let mut reader = TraceReader::new(trace.as_slice());
+        assert_eq!(reader.next().unwrap().unwrap(), (0, packet.clone()));
+        assert_eq!(reader.next().unwrap().unwrap(), (10, packet));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ReadError::Truncated {
+                offset: 14,
+                len: 5,
+                available: 1
+            }))
+        ));
+        assert!(reader.next().is_none());
+    }
+}
@@ -1,17 +1,39 @@
 #![allow(clippy::too_many_arguments)]
 
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 pub use prost::{bytes::BufMut, decode_length_delimiter, EncodeError, Message};
 
+mod builder;
+pub mod io;
+pub mod raw;
+
+pub use builder::{
+    CounterBuilder, CounterEvent, ProcessBuilder, SiblingMerge, SliceEvent, ThreadBuilder,
+    TrackBuilder,
+};
+pub use io::{RawPacket, ReadError, TraceReader, TraceWriter};
+
 pub mod protos {
     #![allow(clippy::large_enum_variant)]
     #![allow(clippy::enum_variant_names)]
     include!(concat!(env!("OUT_DIR"), "/perfetto.protos.rs"));
 }
 
-const TRUSTED_PACKET_SEQUENCE_ID: Option<protos::trace_packet::OptionalTrustedPacketSequenceId> =
+/// Name of the track holding the metadata of a trace (see [`metadata_evt`]).
+pub const METADATA_TRACK_NAME: &str = "Trace Metadata";
+
+fn sequence_id_field(
+    sequence_id: u32,
+) -> Option<protos::trace_packet::OptionalTrustedPacketSequenceId> {
     Some(
-        protos::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(0xDEADBEEF),
-    );
+        protos::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(sequence_id),
+    )
+}
 
 pub use protos::*;
 
@@ -35,9 +57,44 @@ impl ChildOrder {
     }
 }
 
+// Discriminators mixed into derived UUIDs, so that e.g. a track and a counter
+// with the same name and parent don't collide:
+const UUID_KIND_PROCESS: u8 = 1;
+const UUID_KIND_THREAD: u8 = 2;
+const UUID_KIND_TRACK: u8 = 3;
+const UUID_KIND_COUNTER: u8 = 4;
+
+/// Allocator of track UUIDs and packet sequence IDs for one trace writer.
+///
+/// # Multiple writers
+///
+/// Trace processor keeps state (e.g. for incremental counters or interning)
+/// per trusted packet sequence ID, so every independent stream of packets in a
+/// trace must use its own ID. Each [`Synthetto`] hands out sequence IDs from a
+/// random permutation of all 32-bit IDs, seeded differently for every instance
+/// (or from the UUID salt, see [`Synthetto::set_uuid_salt`]). IDs allocated by
+/// one instance never repeat, and IDs of different instances (e.g. of several
+/// simulations whose traces are concatenated into one file) are unlikely to
+/// collide. Writers that need a guarantee across instances can assign IDs
+/// explicitly with [`Synthetto::set_sequence_id`] instead.
+///
+/// Packets of different sequences can be interleaved freely, but each sequence
+/// must be written in order, so writers sharing a file should hand over
+/// complete packets (as produced by the functions of this crate) only.
 #[derive(Debug)]
 pub struct Synthetto {
     uuid_cnt: u64,
+    /// Salt of the deterministic UUID scheme, if enabled.
+    uuid_salt: Option<u64>,
+    /// All UUIDs derived so far, to resolve collisions.
+    derived_uuids: HashSet<u64>,
+    /// Sequence ID of descriptor packets.
+    sequence_id: u32,
+    sequence_salt: u32,
+    sequence_cnt: u32,
+    /// All sequence IDs allocated or set so far, so that re-seeding the
+    /// allocator never hands out an ID twice.
+    used_sequence_ids: HashSet<u32>,
 }
 
 impl Default for Synthetto {
@@ -48,19 +105,136 @@ impl Default for Synthetto {
 
 impl Synthetto {
     pub fn new() -> Self {
-        Synthetto {
-            uuid_cnt: 0x023DEAD000,
+        Self::from_uuid_cnt(0x023DEAD000)
+    }
+
+    pub fn from_uuid_cnt(uuid_cnt: u64) -> Self {
+        let mut synthetto = Synthetto {
+            uuid_cnt,
+            uuid_salt: None,
+            derived_uuids: HashSet::new(),
+            sequence_id: 0,
+            sequence_salt: unique_sequence_salt(),
+            sequence_cnt: 0,
+            used_sequence_ids: HashSet::new(),
+        };
+        synthetto.sequence_id = synthetto.new_sequence_id();
+        synthetto
+    }
+
+    /// Trusted packet sequence ID of descriptor packets.
+    pub fn sequence_id(&self) -> u32 {
+        self.sequence_id
+    }
+
+    /// Use `sequence_id` for descriptor packets from now on.
+    pub fn set_sequence_id(&mut self, sequence_id: u32) {
+        self.used_sequence_ids.insert(sequence_id);
+        self.sequence_id = sequence_id;
+    }
+
+    /// Allocate a trusted packet sequence ID, unique among all IDs allocated
+    /// by this instance (see [multiple writers](Synthetto#multiple-writers)).
+    pub fn new_sequence_id(&mut self) -> u32 {
+        loop {
+            let cnt = self.sequence_cnt;
+            self.sequence_cnt = self.sequence_cnt.wrapping_add(1);
+            // Zero is not a valid sequence ID:
+            let id = permute32(self.sequence_salt.wrapping_add(cnt));
+            if id != 0 && self.used_sequence_ids.insert(id) {
+                return id;
+            }
         }
     }
 
+    /// Seed and number of sequence IDs allocated so far, to continue the
+    /// allocation in another instance with [`Synthetto::restore_sequence_ids`].
+    pub fn sequence_state(&self) -> (u32, u32) {
+        (self.sequence_salt, self.sequence_cnt)
+    }
+
+    /// Continue allocating sequence IDs where another instance left off, given
+    /// its [`Synthetto::sequence_state`]. IDs already allocated by this
+    /// instance are skipped.
+    pub fn restore_sequence_ids(&mut self, salt: u32, cnt: u32) {
+        self.sequence_salt = salt;
+        self.sequence_cnt = cnt;
+    }
+
+    /// Enable deterministic UUIDs: Descriptors created from now on get a UUID
+    /// derived from `salt` and their path (names of the track and all its
+    /// parents, or pid/tid) instead of one from the global counter.
+    ///
+    /// This makes track UUIDs independent of the order in which tracks are
+    /// created, so that traces of the same test are byte-diffable across runs.
+    /// Traces with different salts can be merged safely. The counter used by
+    /// [`Synthetto::new_uuid`] and the sequence ID allocator (including
+    /// [`Synthetto::sequence_id`]) are re-seeded from the salt as well.
+    ///
+    /// Tracks with the same path (e.g. two tracks of the same name below the
+    /// same parent) are disambiguated by their creation order.
+    pub fn set_uuid_salt(&mut self, salt: &str) {
+        let salt = mix64(fnv1a(FNV_OFFSET, salt.as_bytes()));
+        self.uuid_salt = Some(salt);
+        // Leave plenty of headroom before the counter wraps around:
+        self.uuid_cnt = (salt >> 8) | 1;
+        self.restore_sequence_ids((salt >> 32) as u32, 0);
+        self.sequence_id = self.new_sequence_id();
+    }
+
+    /// Salt of the deterministic UUID scheme, if enabled.
+    pub fn uuid_salt(&self) -> Option<u64> {
+        self.uuid_salt
+    }
+
+    /// UUIDs handed out by the deterministic UUID scheme so far.
+    pub fn derived_uuids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.derived_uuids.iter().copied()
+    }
+
+    /// Restore the deterministic UUID scheme from a previous [`Synthetto`],
+    /// given its [`Synthetto::uuid_salt`] and [`Synthetto::derived_uuids`].
+    pub fn restore_uuid_salt(&mut self, salt: u64, derived_uuids: impl IntoIterator<Item = u64>) {
+        self.uuid_salt = Some(salt);
+        self.derived_uuids = derived_uuids.into_iter().collect();
+    }
+
+    pub fn uuid_cnt(&self) -> u64 {
+        self.uuid_cnt
+    }
+
     pub fn new_uuid(&mut self) -> u64 {
         let uuid = self.uuid_cnt;
-        self.uuid_cnt += 1;
+        self.uuid_cnt = self.uuid_cnt.wrapping_add(1);
         // Grab LSBs and move them to the MSBs:
         let lsbs = uuid & 0xFFF;
         (uuid >> 12) | (lsbs << 52)
     }
 
+    /// UUID of a new descriptor: Derived from the parent's UUID (or the salt)
+    /// and `key` if deterministic UUIDs are enabled, else from the counter.
+    fn descriptor_uuid(&mut self, kind: u8, parent_uuid: Option<u64>, key: &[u8]) -> u64 {
+        let Some(salt) = self.uuid_salt else {
+            return self.new_uuid();
+        };
+
+        // Since the parent's UUID is itself derived from its path, this
+        // chains into a hash of the full path.
+        let mut hash = fnv1a(FNV_OFFSET, &parent_uuid.unwrap_or(salt).to_le_bytes());
+        hash = fnv1a(hash, &[kind]);
+        hash = fnv1a(hash, key);
+
+        let mut occurrence: u64 = 0;
+        loop {
+            let uuid = mix64(fnv1a(hash, &occurrence.to_le_bytes()));
+            if uuid != 0 && self.derived_uuids.insert(uuid) {
+                return uuid;
+            }
+            occurrence += 1;
+        }
+    }
+
+    /// Process track, see [`ProcessBuilder`].
     pub fn new_process<B: BufMut>(
         &mut self,
         pid: i32,
@@ -72,40 +246,17 @@ impl Synthetto {
         sibling_order_rank: Option<i32>,
         buf: &mut B,
     ) -> Result<u64, EncodeError> {
-        let uuid = self.new_uuid();
-
-        let evt = TracePacket {
-            timestamp: None,
-            data: Some(protos::trace_packet::Data::TrackDescriptor(
-                protos::TrackDescriptor {
-                    uuid: Some(uuid),
-                    parent_uuid: None,
-                    process: Some(protos::ProcessDescriptor {
-                        pid: Some(pid),
-                        cmdline,
-                        process_name,
-                        process_priority: priority,
-                        start_timestamp_ns: None,
-                    }),
-                    thread: None,
-                    counter: None,
-                    static_or_dynamic_name: None,
-                    description,
-                    child_ordering: child_ordering.map(|x| x.to_proto_enum()),
-                    sibling_order_rank,
-                    sibling_merge_behavior: None,
-                    sibling_merge_key: None,
-                },
-            )),
-            optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-            ..protos::TracePacket::default()
-        };
-
-        buf.put_u8(0x0A);
-        evt.encode_length_delimited(buf)?;
-        Ok(uuid)
+        ProcessBuilder::new(pid)
+            .maybe(process_name, ProcessBuilder::process_name)
+            .cmdline(cmdline)
+            .maybe(priority, ProcessBuilder::priority)
+            .maybe(description, ProcessBuilder::description)
+            .maybe(child_ordering, ProcessBuilder::child_ordering)
+            .maybe(sibling_order_rank, ProcessBuilder::sibling_order_rank)
+            .write(self, buf)
     }
 
+    /// Thread track, see [`ThreadBuilder`].
     pub fn new_thread<B: BufMut>(
         &mut self,
         pid: i32,
@@ -116,38 +267,15 @@ impl Synthetto {
         sibling_order_rank: Option<i32>,
         buf: &mut B,
     ) -> Result<u64, EncodeError> {
-        let uuid = self.new_uuid();
-
-        let evt = TracePacket {
-            timestamp: None,
-            data: Some(protos::trace_packet::Data::TrackDescriptor(
-                protos::TrackDescriptor {
-                    uuid: Some(uuid),
-                    parent_uuid: None,
-                    process: None,
-                    thread: Some(protos::ThreadDescriptor {
-                        pid: Some(pid),
-                        tid: Some(tid),
-                        thread_name: Some(thread_name),
-                    }),
-                    counter: None,
-                    static_or_dynamic_name: None,
-                    description,
-                    child_ordering: child_ordering.map(|x| x.to_proto_enum()),
-                    sibling_order_rank,
-                    sibling_merge_behavior: None,
-                    sibling_merge_key: None,
-                },
-            )),
-            optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-            ..protos::TracePacket::default()
-        };
-
-        buf.put_u8(0x0A);
-        evt.encode_length_delimited(buf)?;
-        Ok(uuid)
+        ThreadBuilder::new(pid, tid)
+            .thread_name(thread_name)
+            .maybe(description, ThreadBuilder::description)
+            .maybe(child_ordering, ThreadBuilder::child_ordering)
+            .maybe(sibling_order_rank, ThreadBuilder::sibling_order_rank)
+            .write(self, buf)
     }
 
+    /// Named track, see [`TrackBuilder`].
     pub fn new_track<B: BufMut>(
         &mut self,
         name: String,
@@ -157,36 +285,15 @@ impl Synthetto {
         sibling_order_rank: Option<i32>,
         buf: &mut B,
     ) -> Result<u64, EncodeError> {
-        let uuid = self.new_uuid();
-
-        let evt = TracePacket {
-            timestamp: None,
-            data: Some(protos::trace_packet::Data::TrackDescriptor(
-                protos::TrackDescriptor {
-                    uuid: Some(uuid),
-                    parent_uuid,
-                    process: None,
-                    thread: None,
-                    counter: None,
-                    static_or_dynamic_name: Some(
-                        protos::track_descriptor::StaticOrDynamicName::Name(name),
-                    ),
-                    description,
-                    child_ordering: child_ordering.map(|x| x.to_proto_enum()),
-                    sibling_order_rank,
-                    sibling_merge_behavior: None,
-                    sibling_merge_key: None,
-                },
-            )),
-            optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-            ..protos::TracePacket::default()
-        };
-
-        buf.put_u8(0x0A);
-        evt.encode_length_delimited(buf)?;
-        Ok(uuid)
+        TrackBuilder::new(name)
+            .maybe(parent_uuid, TrackBuilder::parent)
+            .maybe(description, TrackBuilder::description)
+            .maybe(child_ordering, TrackBuilder::child_ordering)
+            .maybe(sibling_order_rank, TrackBuilder::sibling_order_rank)
+            .write(self, buf)
     }
 
+    /// Counter track, see [`CounterBuilder`].
     pub fn new_counter<B: BufMut>(
         &mut self,
         name: String,
@@ -198,42 +305,59 @@ impl Synthetto {
         sibling_order_rank: Option<i32>,
         buf: &mut B,
     ) -> Result<u64, EncodeError> {
-        let uuid = self.new_uuid();
-
-        let evt = TracePacket {
-            timestamp: None,
-            data: Some(protos::trace_packet::Data::TrackDescriptor(
-                protos::TrackDescriptor {
-                    uuid: Some(uuid),
-                    parent_uuid,
-                    process: None,
-                    thread: None,
-                    counter: Some(protos::CounterDescriptor {
-                        categories: vec![],
-                        unit: unit.to_proto_unit(),
-                        unit_name: unit.to_proto_unit_name(),
-                        unit_multiplier: None,
-                        is_incremental: Some(is_incremental),
-                        y_axis_share_key: None,
-                    }),
-                    static_or_dynamic_name: Some(
-                        protos::track_descriptor::StaticOrDynamicName::Name(name),
-                    ),
-                    description,
-                    child_ordering: child_ordering.map(|x| x.to_proto_enum()),
-                    sibling_order_rank,
-                    sibling_merge_behavior: None,
-                    sibling_merge_key: None,
-                },
-            )),
-            optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-            ..protos::TracePacket::default()
-        };
+        CounterBuilder::new(name)
+            .unit(unit)
+            .is_incremental(is_incremental)
+            .maybe(parent_uuid, CounterBuilder::parent)
+            .maybe(description, CounterBuilder::description)
+            .maybe(child_ordering, CounterBuilder::child_ordering)
+            .maybe(sibling_order_rank, CounterBuilder::sibling_order_rank)
+            .write(self, buf)
+    }
+}
 
-        buf.put_u8(0x0A);
-        evt.encode_length_delimited(buf)?;
-        Ok(uuid)
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
     }
+    hash
+}
+
+/// Sequence ID seed that differs between instances and processes.
+fn unique_sequence_salt() -> u32 {
+    static INSTANCE_CNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let mut hash = fnv1a(FNV_OFFSET, &nanos.to_le_bytes());
+    hash = fnv1a(hash, &std::process::id().to_le_bytes());
+    hash = fnv1a(
+        hash,
+        &INSTANCE_CNT.fetch_add(1, Ordering::Relaxed).to_le_bytes(),
+    );
+    mix64(hash) as u32
+}
+
+// Bijective 32-bit mix (lowbias32), so that distinct inputs give distinct IDs:
+fn permute32(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^ (x >> 16)
+}
+
+// SplitMix64 finalizer, spreading the FNV hash over all bits:
+fn mix64(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
 }
 
 pub fn slice_begin_evt<B: BufMut>(
@@ -243,29 +367,15 @@ pub fn slice_begin_evt<B: BufMut>(
     flows: Vec<u64>,
     flows_end: Vec<u64>,
     correlation_id: Option<u64>,
+    sequence_id: u32,
     buf: &mut B,
 ) -> Result<(), EncodeError> {
-    let correlation_id_field =
-        correlation_id.map(protos::track_event::CorrelationIdField::CorrelationId);
-
-    let evt = TracePacket {
-        timestamp: Some(ts),
-        data: Some(protos::trace_packet::Data::TrackEvent(protos::TrackEvent {
-            name_field: name.map(protos::track_event::NameField::Name),
-            track_uuid: Some(track_uuid),
-            r#type: Some(protos::track_event::Type::SliceBegin as i32),
-            flow_ids: flows,
-            terminating_flow_ids: flows_end,
-            correlation_id_field,
-            ..protos::TrackEvent::default()
-        })),
-        optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-        ..protos::TracePacket::default()
-    };
-
-    buf.put_u8(0x0A);
-    evt.encode_length_delimited(buf)?;
-    Ok(())
+    SliceEvent::begin(track_uuid, ts)
+        .maybe(name, SliceEvent::name)
+        .flows(flows)
+        .flows_end(flows_end)
+        .maybe(correlation_id, SliceEvent::correlation_id)
+        .write_on_sequence(sequence_id, buf)
 }
 
 pub fn slice_end_evt<B: BufMut>(
@@ -274,29 +384,14 @@ pub fn slice_end_evt<B: BufMut>(
     flows: Vec<u64>,
     flows_end: Vec<u64>,
     correlation_id: Option<u64>,
+    sequence_id: u32,
     buf: &mut B,
 ) -> Result<(), EncodeError> {
-    let correlation_id_field =
-        correlation_id.map(protos::track_event::CorrelationIdField::CorrelationId);
-
-    let evt = protos::TracePacket {
-        timestamp: Some(ts),
-        data: Some(protos::trace_packet::Data::TrackEvent(protos::TrackEvent {
-            name_field: None,
-            track_uuid: Some(track_uuid),
-            r#type: Some(protos::track_event::Type::SliceEnd as i32),
-            flow_ids: flows,
-            terminating_flow_ids: flows_end,
-            correlation_id_field,
-            ..protos::TrackEvent::default()
-        })),
-        optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-        ..protos::TracePacket::default()
-    };
-
-    buf.put_u8(0x0A);
-    evt.encode_length_delimited(buf)?;
-    Ok(())
+    SliceEvent::end(track_uuid, ts)
+        .flows(flows)
+        .flows_end(flows_end)
+        .maybe(correlation_id, SliceEvent::correlation_id)
+        .write_on_sequence(sequence_id, buf)
 }
 
 pub fn instant_evt<B: BufMut>(
@@ -306,85 +401,87 @@ pub fn instant_evt<B: BufMut>(
     flows: Vec<u64>,
     flows_end: Vec<u64>,
     correlation_id: Option<u64>,
+    sequence_id: u32,
+    buf: &mut B,
+) -> Result<(), EncodeError> {
+    SliceEvent::instant(track_uuid, ts)
+        .maybe(name, SliceEvent::name)
+        .flows(flows)
+        .flows_end(flows_end)
+        .maybe(correlation_id, SliceEvent::correlation_id)
+        .write_on_sequence(sequence_id, buf)
+}
+
+/// Instant event carrying `args` as debug annotations.
+pub fn annotated_instant_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    name: Option<String>,
+    args: Vec<protos::DebugAnnotation>,
+    sequence_id: u32,
     buf: &mut B,
 ) -> Result<(), EncodeError> {
-    let name_field = name.map(protos::track_event::NameField::Name);
-    let correlation_id_field =
-        correlation_id.map(protos::track_event::CorrelationIdField::CorrelationId);
-
-    let evt = protos::TracePacket {
-        timestamp: Some(ts),
-        data: Some(protos::trace_packet::Data::TrackEvent(protos::TrackEvent {
-            name_field,
-            track_uuid: Some(track_uuid),
-            r#type: Some(protos::track_event::Type::Instant as i32),
-            flow_ids: flows,
-            terminating_flow_ids: flows_end,
-            correlation_id_field,
-            ..protos::TrackEvent::default()
-        })),
-        optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-        ..protos::TracePacket::default()
-    };
-
-    buf.put_u8(0x0A);
-    evt.encode_length_delimited(buf)?;
-    Ok(())
+    SliceEvent::instant(track_uuid, ts)
+        .maybe(name, SliceEvent::name)
+        .args(args)
+        .write_on_sequence(sequence_id, buf)
+}
+
+/// Instant event at timestamp 0 carrying metadata key/value pairs as string
+/// arguments. Should be placed on a track named [`METADATA_TRACK_NAME`].
+pub fn metadata_evt<B: BufMut>(
+    track_uuid: u64,
+    entries: Vec<(String, String)>,
+    sequence_id: u32,
+    buf: &mut B,
+) -> Result<(), EncodeError> {
+    let args = entries
+        .into_iter()
+        .map(|(key, value)| debug_arg(key, protos::debug_annotation::Value::StringValue(value)))
+        .collect();
+    annotated_instant_evt(
+        track_uuid,
+        0,
+        Some("metadata".to_string()),
+        args,
+        sequence_id,
+        buf,
+    )
+}
+
+/// Named debug annotation (i.e. event argument) with the given value.
+pub fn debug_arg(name: String, value: protos::debug_annotation::Value) -> DebugAnnotation {
+    DebugAnnotation {
+        name_field: Some(protos::debug_annotation::NameField::Name(name)),
+        value: Some(value),
+        ..DebugAnnotation::default()
+    }
 }
 
 pub fn int_counter_evt<V, B: BufMut>(
     track_uuid: u64,
     ts: u64,
     val: V,
+    sequence_id: u32,
     buf: &mut B,
 ) -> Result<(), EncodeError>
 where
     V: Into<i64>,
 {
-    let evt = protos::TracePacket {
-        timestamp: Some(ts),
-        data: Some(protos::trace_packet::Data::TrackEvent(protos::TrackEvent {
-            track_uuid: Some(track_uuid),
-            r#type: Some(protos::track_event::Type::Counter as i32),
-            counter_value_field: Some(protos::track_event::CounterValueField::CounterValue(
-                val.into(),
-            )),
-            ..protos::TrackEvent::default()
-        })),
-        optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-        ..protos::TracePacket::default()
-    };
-    buf.put_u8(0x0A);
-    evt.encode_length_delimited(buf)?;
-    Ok(())
+    CounterEvent::int(track_uuid, ts, val.into()).write_on_sequence(sequence_id, buf)
 }
 
 pub fn float_counter_evt<V, B: BufMut>(
     track_uuid: u64,
     ts: u64,
     val: V,
+    sequence_id: u32,
     buf: &mut B,
 ) -> Result<(), EncodeError>
 where
     V: Into<f64>,
 {
-    let evt = protos::TracePacket {
-        timestamp: Some(ts),
-        data: Some(protos::trace_packet::Data::TrackEvent(protos::TrackEvent {
-            track_uuid: Some(track_uuid),
-            r#type: Some(protos::track_event::Type::Counter as i32),
-            counter_value_field: Some(protos::track_event::CounterValueField::DoubleCounterValue(
-                val.into(),
-            )),
-            ..protos::TrackEvent::default()
-        })),
-        optional_trusted_packet_sequence_id: TRUSTED_PACKET_SEQUENCE_ID,
-        ..protos::TracePacket::default()
-    };
-
-    buf.put_u8(0x0A);
-    evt.encode_length_delimited(buf)?;
-    Ok(())
+    CounterEvent::float(track_uuid, ts, val.into()).write_on_sequence(sequence_id, buf)
 }
 
 pub enum CounterTrackUnit {
@@ -425,3 +522,57 @@ impl CounterTrackUnit {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_ids_unique() {
+        let mut synthetto = Synthetto::new();
+        let mut ids = HashSet::from([synthetto.sequence_id()]);
+        for _ in 0..100 {
+            assert!(ids.insert(synthetto.new_sequence_id()));
+        }
+
+        // Re-seeding (even twice with the same salt) never hands out an ID
+        // again:
+        synthetto.set_uuid_salt("a");
+        assert!(ids.insert(synthetto.sequence_id()));
+        for _ in 0..100 {
+            assert!(ids.insert(synthetto.new_sequence_id()));
+        }
+        synthetto.set_uuid_salt("a");
+        assert!(ids.insert(synthetto.sequence_id()));
+        for _ in 0..100 {
+            assert!(ids.insert(synthetto.new_sequence_id()));
+        }
+
+        let (salt, cnt) = synthetto.sequence_state();
+        synthetto.restore_sequence_ids(salt, cnt - 10);
+        assert!(ids.insert(synthetto.new_sequence_id()));
+        assert!(!ids.contains(&0));
+    }
+
+    #[test]
+    fn test_salt_deterministic() {
+        let ids = |salt: &str| {
+            let mut synthetto = Synthetto::new();
+            synthetto.new_sequence_id();
+            synthetto.set_uuid_salt(salt);
+            let sequence_ids = [synthetto.sequence_id(), synthetto.new_sequence_id()];
+            let mut buf = vec![];
+            let parent = TrackBuilder::new("parent")
+                .write(&mut synthetto, &mut buf)
+                .unwrap();
+            let child = TrackBuilder::new("child")
+                .parent(parent)
+                .write(&mut synthetto, &mut buf)
+                .unwrap();
+            (sequence_ids, [parent, child, synthetto.new_uuid()])
+        };
+        assert_eq!(ids("a"), ids("a"));
+        assert_ne!(ids("a").0, ids("b").0);
+        assert_ne!(ids("a").1, ids("b").1);
+    }
+}
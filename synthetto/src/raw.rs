@@ -0,0 +1,298 @@
+//! Hand-rolled, allocation-free encoding of the most common trace events.
+//!
+//! The functions in this module produce the same packets as their
+//! counterparts at the crate root, but borrow all arguments and write straight
+//! into the buffer instead of building prost messages first.
+
+use prost::bytes::BufMut;
+
+use crate::protos::track_event::Type;
+
+// Wire types:
+const VARINT: u32 = 0;
+const FIXED64: u32 = 1;
+const LEN: u32 = 2;
+
+// TracePacket fields:
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+
+// TrackEvent fields:
+const EVT_TYPE: u32 = 9;
+const EVT_TRACK_UUID: u32 = 11;
+const EVT_NAME: u32 = 23;
+const EVT_COUNTER_VALUE: u32 = 30;
+const EVT_DOUBLE_COUNTER_VALUE: u32 = 44;
+const EVT_FLOW_IDS: u32 = 47;
+const EVT_TERMINATING_FLOW_IDS: u32 = 48;
+const EVT_CORRELATION_ID: u32 = 52;
+
+#[derive(Clone, Copy)]
+enum CounterValue {
+    Int(i64),
+    Float(f64),
+}
+
+/// All fields of a track event this module can encode.
+#[derive(Clone, Copy)]
+struct Event<'a> {
+    ty: Type,
+    track_uuid: u64,
+    name: Option<&'a str>,
+    counter_value: Option<CounterValue>,
+    flows: &'a [u64],
+    flows_end: &'a [u64],
+    correlation_id: Option<u64>,
+}
+
+impl Event<'_> {
+    fn encoded_len(&self) -> usize {
+        let mut len = key_len(EVT_TYPE) + varint_len(self.ty as u64);
+        len += key_len(EVT_TRACK_UUID) + varint_len(self.track_uuid);
+        if let Some(name) = self.name {
+            len += key_len(EVT_NAME) + varint_len(name.len() as u64) + name.len();
+        }
+        match self.counter_value {
+            Some(CounterValue::Int(v)) => len += key_len(EVT_COUNTER_VALUE) + varint_len(v as u64),
+            Some(CounterValue::Float(_)) => len += key_len(EVT_DOUBLE_COUNTER_VALUE) + 8,
+            None => (),
+        }
+        len += self.flows.len() * (key_len(EVT_FLOW_IDS) + 8);
+        len += self.flows_end.len() * (key_len(EVT_TERMINATING_FLOW_IDS) + 8);
+        if let Some(id) = self.correlation_id {
+            len += key_len(EVT_CORRELATION_ID) + varint_len(id);
+        }
+        len
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        put_key(buf, EVT_TYPE, VARINT);
+        put_varint(buf, self.ty as u64);
+        put_key(buf, EVT_TRACK_UUID, VARINT);
+        put_varint(buf, self.track_uuid);
+        if let Some(name) = self.name {
+            put_key(buf, EVT_NAME, LEN);
+            put_varint(buf, name.len() as u64);
+            buf.put_slice(name.as_bytes());
+        }
+        match self.counter_value {
+            Some(CounterValue::Int(v)) => {
+                put_key(buf, EVT_COUNTER_VALUE, VARINT);
+                put_varint(buf, v as u64);
+            }
+            Some(CounterValue::Float(v)) => {
+                put_key(buf, EVT_DOUBLE_COUNTER_VALUE, FIXED64);
+                buf.put_f64_le(v);
+            }
+            None => (),
+        }
+        for flow in self.flows {
+            put_key(buf, EVT_FLOW_IDS, FIXED64);
+            buf.put_u64_le(*flow);
+        }
+        for flow in self.flows_end {
+            put_key(buf, EVT_TERMINATING_FLOW_IDS, FIXED64);
+            buf.put_u64_le(*flow);
+        }
+        if let Some(id) = self.correlation_id {
+            put_key(buf, EVT_CORRELATION_ID, VARINT);
+            put_varint(buf, id);
+        }
+    }
+
+    /// Write a complete `Trace.packet` entry holding this event.
+    fn write_packet<B: BufMut>(&self, ts: u64, sequence_id: u32, buf: &mut B) {
+        let evt_len = self.encoded_len();
+        let packet_len = key_len(PACKET_TIMESTAMP)
+            + varint_len(ts)
+            + key_len(PACKET_SEQUENCE_ID)
+            + varint_len(sequence_id as u64)
+            + key_len(PACKET_TRACK_EVENT)
+            + varint_len(evt_len as u64)
+            + evt_len;
+
+        buf.put_u8(0x0A);
+        put_varint(buf, packet_len as u64);
+        // Fields in tag order, as prost writes them:
+        put_key(buf, PACKET_TIMESTAMP, VARINT);
+        put_varint(buf, ts);
+        put_key(buf, PACKET_SEQUENCE_ID, VARINT);
+        put_varint(buf, sequence_id as u64);
+        put_key(buf, PACKET_TRACK_EVENT, LEN);
+        put_varint(buf, evt_len as u64);
+        self.encode(buf);
+    }
+}
+
+pub fn slice_begin_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    name: Option<&str>,
+    flows: &[u64],
+    flows_end: &[u64],
+    correlation_id: Option<u64>,
+    sequence_id: u32,
+    buf: &mut B,
+) {
+    let evt = Event {
+        ty: Type::SliceBegin,
+        track_uuid,
+        name,
+        counter_value: None,
+        flows,
+        flows_end,
+        correlation_id,
+    };
+    evt.write_packet(ts, sequence_id, buf);
+}
+
+pub fn slice_end_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    flows: &[u64],
+    flows_end: &[u64],
+    correlation_id: Option<u64>,
+    sequence_id: u32,
+    buf: &mut B,
+) {
+    let evt = Event {
+        ty: Type::SliceEnd,
+        track_uuid,
+        name: None,
+        counter_value: None,
+        flows,
+        flows_end,
+        correlation_id,
+    };
+    evt.write_packet(ts, sequence_id, buf);
+}
+
+pub fn instant_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    name: Option<&str>,
+    flows: &[u64],
+    flows_end: &[u64],
+    correlation_id: Option<u64>,
+    sequence_id: u32,
+    buf: &mut B,
+) {
+    let evt = Event {
+        ty: Type::Instant,
+        track_uuid,
+        name,
+        counter_value: None,
+        flows,
+        flows_end,
+        correlation_id,
+    };
+    evt.write_packet(ts, sequence_id, buf);
+}
+
+pub fn int_counter_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    val: i64,
+    sequence_id: u32,
+    buf: &mut B,
+) {
+    let evt = Event {
+        ty: Type::Counter,
+        track_uuid,
+        name: None,
+        counter_value: Some(CounterValue::Int(val)),
+        flows: &[],
+        flows_end: &[],
+        correlation_id: None,
+    };
+    evt.write_packet(ts, sequence_id, buf);
+}
+
+pub fn float_counter_evt<B: BufMut>(
+    track_uuid: u64,
+    ts: u64,
+    val: f64,
+    sequence_id: u32,
+    buf: &mut B,
+) {
+    let evt = Event {
+        ty: Type::Counter,
+        track_uuid,
+        name: None,
+        counter_value: Some(CounterValue::Float(val)),
+        flows: &[],
+        flows_end: &[],
+        correlation_id: None,
+    };
+    evt.write_packet(ts, sequence_id, buf);
+}
+
+fn key_len(field: u32) -> usize {
+    varint_len((field << 3) as u64)
+}
+
+fn put_key<B: BufMut>(buf: &mut B, field: u32, wire_type: u32) {
+    put_varint(buf, ((field << 3) | wire_type) as u64);
+}
+
+fn varint_len(v: u64) -> usize {
+    // Each byte holds 7 bits of payload:
+    (64 - (v | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+fn put_varint<B: BufMut>(buf: &mut B, mut v: u64) {
+    while v >= 0x80 {
+        buf.put_u8((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.put_u8(v as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_prost() {
+        let mut raw = vec![];
+        let mut prost = vec![];
+
+        slice_begin_evt(42, 1000, Some("slice"), &[1, 2], &[3], Some(7), 5, &mut raw);
+        crate::slice_begin_evt(
+            42,
+            1000,
+            Some("slice".into()),
+            vec![1, 2],
+            vec![3],
+            Some(7),
+            5,
+            &mut prost,
+        )
+        .unwrap();
+
+        slice_end_evt(42, 2000, &[], &[1, 2], None, 5, &mut raw);
+        crate::slice_end_evt(42, 2000, vec![], vec![1, 2], None, 5, &mut prost).unwrap();
+
+        instant_evt(u64::MAX, 0, None, &[], &[], None, 0xDEADBEEF, &mut raw);
+        crate::instant_evt(
+            u64::MAX,
+            0,
+            None,
+            vec![],
+            vec![],
+            None,
+            0xDEADBEEF,
+            &mut prost,
+        )
+        .unwrap();
+
+        int_counter_evt(3, 3000, -1, 5, &mut raw);
+        crate::int_counter_evt(3, 3000, -1, 5, &mut prost).unwrap();
+
+        float_counter_evt(3, 4000, 0.5, 5, &mut raw);
+        crate::float_counter_evt(3, 4000, 0.5, 5, &mut prost).unwrap();
+
+        assert_eq!(raw, prost);
+    }
+}
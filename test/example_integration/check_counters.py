@@ -25,8 +25,9 @@ with TraceProcessor(trace=trace_file) as tp:
     """))
 
     print(f"Counter tracks: {len(counter_tracks)}")
-    # 11 counter tracks defined (excluding parent track)
-    assert len(counter_tracks) == 11
+    # 11 counter tracks, 3 derived counters and 4 histogram buckets defined
+    # (excluding parent tracks)
+    assert len(counter_tracks) == 18
 
     # Check basic counters
     basic_counter_track = next(
@@ -109,4 +110,53 @@ with TraceProcessor(trace=trace_file) as tp:
     assert exec_time_values[0].value == 1500
     assert exec_time_values[1].value == 2300
 
+    def values(name):
+        track = next(t for t in counter_tracks if t.name == name)
+        return [(c.ts, c.value) for c in tp.query(f"""
+            SELECT c.*
+            FROM counter c
+            WHERE c.track_id = {track.id}
+            ORDER BY c.ts;
+        """)]
+
+    # Check derived counters: 95 increments of 0, 16, 32, 48, .. bytes every
+    # 10ns starting at 390ns, sampled every 100ns. Samples are only emitted on
+    # change, the last (partial) interval when the context is finished.
+    assert values("BytesPerUs") == [(400, 0), (500, 2400), (1400, 960)]
+    assert values("AvgPackets") == [
+        (400, 0), (500, 4), (600, 5), (700, 5.75), (800, 7.5), (1400, 6.5)
+    ]
+    assert values("TotalBytes") == [
+        (400, 0), (500, 240), (600, 480), (700, 720), (800, 960),
+        (900, 1200), (1000, 1440), (1100, 1680), (1200, 1920), (1300, 2160),
+        (1400, 2256)
+    ]
+
+    # Check histogram buckets (final count of each bucket):
+    buckets = {
+        "< 10": 8,
+        "[10, 20)": 14,
+        "[20, 50)": 39,
+        ">= 50": 39,
+    }
+    for name, count in buckets.items():
+        assert values(name)[-1][1] == count
+
+    # Check histogram summary:
+    summary = list(tp.query("""
+        SELECT a.key, a.display_value
+        FROM slice s
+        JOIN track t ON s.track_id = t.id
+        JOIN args a ON s.arg_set_id = a.arg_set_id
+        WHERE t.name = "Latency" AND s.name = "summary";
+    """))
+    summary = {a.key: float(a.display_value) for a in summary}
+    print(f"Latency summary: {summary}")
+    assert summary["debug.count"] == 100
+    assert summary["debug.min"] == 5
+    assert summary["debug.max"] == 80
+    assert abs(summary["debug.mean"] - 41.42) < 0.00001
+    assert abs(summary["debug.p50"] - 41.53846) < 0.00001
+    assert abs(summary["debug.p99"] - 79.23077) < 0.00001
+
 print("OK!")